@@ -0,0 +1,22 @@
+//! `mkfs-dumbfs ...` runs `dumbfs mkfs ...`. Cargo cannot name a binary `mkfs.dumbfs`, link it to
+//! that name for `mkfs -t dumbfs`.
+
+use std::env;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{exit, Command};
+
+fn main() {
+    // the dumbfs installed next to this binary, the one on the `PATH` otherwise
+    let dumbfs = env::current_exe()
+        .map(|it| it.with_file_name("dumbfs"))
+        .ok()
+        .filter(|it| it.exists())
+        .unwrap_or_else(|| PathBuf::from("dumbfs"));
+    let e = Command::new(&dumbfs)
+        .arg("mkfs")
+        .args(env::args_os().skip(1))
+        .exec();
+    eprintln!("cannot run {:?}: {}", dumbfs, e);
+    exit(1)
+}
@@ -0,0 +1,362 @@
+use crate::disk::device::{write_all_at, BlockDevice};
+use crate::disk::direct::AlignedBuffer;
+use std::cmp::{max, min};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+use std::sync::{Mutex, MutexGuard};
+
+/// Granularity of the cache, a page holds eight nodes.
+pub const CACHE_BLOCK_SIZE: u64 = 4096;
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Dirty blocks written to the backing store, on eviction or flush.
+    pub write_backs: u64,
+}
+
+struct CachedBlock {
+    /// Aligned, so whole blocks can go to a store opened with `O_DIRECT` as they are.
+    data: AlignedBuffer,
+    dirty: bool,
+    used: u64,
+}
+
+struct CacheState {
+    blocks: HashMap<u64, CachedBlock>,
+    /// Blocks by the tick they were last used at, the first one is evicted next.
+    lru: BTreeMap<u64, u64>,
+    tick: u64,
+    /// Size of the store including writes that were not written back yet.
+    size: u64,
+    /// Bumped whenever the store is written, so a miss read without the lock can tell whether it
+    /// may have missed a write-back of its block.
+    written: u64,
+    /// Blocks a flush is writing back with the lock let go, they are not evicted meanwhile.
+    flushing: HashSet<u64>,
+    stats: CacheStats,
+}
+
+impl CacheState {
+    fn touch(&mut self, index: u64) {
+        self.tick += 1;
+        let block = self.blocks.get_mut(&index).unwrap();
+        self.lru.remove(&block.used);
+        block.used = self.tick;
+        self.lru.insert(self.tick, index);
+    }
+    fn forget(&mut self, index: u64) {
+        if let Some(block) = self.blocks.remove(&index) {
+            self.lru.remove(&block.used);
+        }
+    }
+}
+
+/// Caches the backing store in `CACHE_BLOCK_SIZE` blocks. Writes stay in memory until the block is
+/// evicted, least recently used first, or the cache is flushed.
+pub struct BlockCache<D: BlockDevice> {
+    device: D,
+    capacity: usize,
+    state: Mutex<CacheState>,
+    /// Held by a flush throughout and by whatever drops blocks, so no older copy of a block is
+    /// written over what happened to it meanwhile.
+    flush_lock: Mutex<()>,
+}
+
+impl<D: BlockDevice> BlockCache<D> {
+    /// Caches up to `capacity` bytes of `device`, at least one block.
+    pub fn new(device: D, capacity: u64) -> io::Result<Self> {
+        let size = device.size()?;
+        Ok(BlockCache {
+            device,
+            capacity: max(capacity / CACHE_BLOCK_SIZE, 1) as usize,
+            state: Mutex::new(CacheState {
+                blocks: HashMap::new(),
+                lru: BTreeMap::new(),
+                tick: 0,
+                size,
+                written: 0,
+                flushing: HashSet::new(),
+                stats: CacheStats::default(),
+            }),
+            flush_lock: Mutex::new(()),
+        })
+    }
+    fn write_back(&self, state: &CacheState, index: u64, data: &[u8]) -> io::Result<()> {
+        let start = index * CACHE_BLOCK_SIZE;
+        let length = min(CACHE_BLOCK_SIZE, state.size.saturating_sub(start)) as usize;
+        write_all_at(&self.device, &data[..length], start)
+    }
+    /// Evicts blocks until `room` more fit in.
+    fn evict(&self, state: &mut CacheState, room: usize) -> io::Result<()> {
+        while state.blocks.len() + room > self.capacity {
+            let index = match state.lru.values().find(|it| !state.flushing.contains(it)) {
+                Some(&index) => index,
+                None => break,
+            };
+            let block = &state.blocks[&index];
+            if block.dirty {
+                self.write_back(state, index, &block.data)?;
+                state.written += 1;
+                state.stats.write_backs += 1;
+            }
+            state.forget(index);
+        }
+        Ok(())
+    }
+    /// Reads block `index` from the store, `length` bytes of it are stored.
+    fn read_block(&self, index: u64, length: usize) -> io::Result<AlignedBuffer> {
+        let mut data = AlignedBuffer::new(CACHE_BLOCK_SIZE as usize);
+        let start = index * CACHE_BLOCK_SIZE;
+        let mut read = 0;
+        while read < length {
+            match self
+                .device
+                .read_at(&mut data[read..length], start + read as u64)?
+            {
+                0 => break,
+                it => read += it,
+            }
+        }
+        Ok(data)
+    }
+    /// Caches block `index`, read from the store first unless it is about to be overwritten.
+    /// The lock is let go while reading, other blocks stay available meanwhile.
+    fn load<'a>(
+        &'a self,
+        mut state: MutexGuard<'a, CacheState>,
+        index: u64,
+        overwrite: bool,
+    ) -> io::Result<MutexGuard<'a, CacheState>> {
+        if state.blocks.contains_key(&index) {
+            state.stats.hits += 1;
+        } else {
+            state.stats.misses += 1;
+            let data = loop {
+                let start = index * CACHE_BLOCK_SIZE;
+                let length = min(CACHE_BLOCK_SIZE, state.size.saturating_sub(start)) as usize;
+                if overwrite || length == 0 {
+                    break AlignedBuffer::new(CACHE_BLOCK_SIZE as usize);
+                }
+                let written = state.written;
+                drop(state);
+                let data = self.read_block(index, length);
+                state = self.state.lock().unwrap();
+                // a write-back meanwhile may have been missed by the read, read again
+                if state.written == written || state.blocks.contains_key(&index) {
+                    break data?;
+                }
+            };
+            if !state.blocks.contains_key(&index) {
+                self.evict(&mut state, 1)?;
+                let block = CachedBlock {
+                    data,
+                    dirty: false,
+                    used: 0,
+                };
+                state.blocks.insert(index, block);
+            }
+        }
+        state.touch(index);
+        Ok(state)
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for BlockCache<D> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let end = min(offset + buf.len() as u64, state.size);
+        let mut position = offset;
+        while position < end {
+            let within = position % CACHE_BLOCK_SIZE;
+            let length = min(CACHE_BLOCK_SIZE - within, end - position) as usize;
+            let index = position / CACHE_BLOCK_SIZE;
+            state = self.load(state, index, false)?;
+            let block = &state.blocks[&index];
+            let at = (position - offset) as usize;
+            let within = within as usize;
+            buf[at..at + length].copy_from_slice(&block.data[within..within + length]);
+            position += length as u64;
+        }
+        Ok(end.saturating_sub(offset) as usize)
+    }
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let end = offset + buf.len() as u64;
+        let mut position = offset;
+        while position < end {
+            let within = position % CACHE_BLOCK_SIZE;
+            let length = min(CACHE_BLOCK_SIZE - within, end - position) as usize;
+            let overwrite = length as u64 == CACHE_BLOCK_SIZE;
+            let index = position / CACHE_BLOCK_SIZE;
+            state = self.load(state, index, overwrite)?;
+            let block = state.blocks.get_mut(&index).unwrap();
+            let at = (position - offset) as usize;
+            let within = within as usize;
+            block.data[within..within + length].copy_from_slice(&buf[at..at + length]);
+            block.dirty = true;
+            position += length as u64;
+        }
+        state.size = max(state.size, end);
+        Ok(buf.len())
+    }
+    fn flush(&self) -> io::Result<()> {
+        let _flush_lock = self.flush_lock.lock().unwrap();
+        let mut state = self.state.lock().unwrap();
+        let mut dirty: Vec<u64> = state
+            .blocks
+            .iter()
+            .filter(|(_, block)| block.dirty)
+            .map(|(index, _)| *index)
+            .collect();
+        dirty.sort();
+        // written from copies, so reads and writes go on while the store is busy
+        let size = state.size;
+        let mut copies = Vec::with_capacity(dirty.len());
+        for &index in &dirty {
+            let block = state.blocks.get_mut(&index).unwrap();
+            let mut copy = AlignedBuffer::new(CACHE_BLOCK_SIZE as usize);
+            copy.copy_from_slice(&block.data);
+            block.dirty = false;
+            copies.push(copy);
+        }
+        state.flushing = dirty.iter().cloned().collect();
+        drop(state);
+        let writes: Vec<(u64, &[u8])> = dirty
+            .iter()
+            .zip(&copies)
+            .map(|(index, data)| {
+                let start = index * CACHE_BLOCK_SIZE;
+                let length = min(CACHE_BLOCK_SIZE, size.saturating_sub(start)) as usize;
+                (start, &data[..length])
+            })
+            .collect();
+        let written = self.device.write_batch(&writes);
+        let mut state = self.state.lock().unwrap();
+        state.flushing.clear();
+        state.written += 1;
+        if let Err(e) = written {
+            for index in dirty {
+                state.blocks.get_mut(&index).unwrap().dirty = true;
+            }
+            return Err(e);
+        }
+        state.stats.write_backs += dirty.len() as u64;
+        drop(state);
+        if size > self.device.size()? {
+            self.device.set_size(size)?;
+        }
+        self.device.flush()
+    }
+    fn discard(&self, offset: u64, length: u64) -> io::Result<()> {
+        let _flush_lock = self.flush_lock.lock().unwrap();
+        let mut state = self.state.lock().unwrap();
+        let end = offset + length;
+        let overlapping: Vec<u64> = state
+            .blocks
+            .keys()
+            .filter(|&&index| {
+                index * CACHE_BLOCK_SIZE < end && offset < (index + 1) * CACHE_BLOCK_SIZE
+            })
+            .cloned()
+            .collect();
+        for index in overlapping {
+            let start = index * CACHE_BLOCK_SIZE;
+            if offset <= start && start + CACHE_BLOCK_SIZE <= end {
+                state.forget(index);
+            } else {
+                let from = (max(offset, start) - start) as usize;
+                let to = (min(end, start + CACHE_BLOCK_SIZE) - start) as usize;
+                let block = state.blocks.get_mut(&index).unwrap();
+                block.data[from..to].iter_mut().for_each(|it| *it = 0);
+            }
+        }
+        state.written += 1;
+        self.device.discard(offset, length)
+    }
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.state.lock().unwrap().size)
+    }
+    fn set_size(&self, size: u64) -> io::Result<()> {
+        let _flush_lock = self.flush_lock.lock().unwrap();
+        let mut state = self.state.lock().unwrap();
+        let beyond: Vec<u64> = state
+            .blocks
+            .keys()
+            .filter(|&&index| index * CACHE_BLOCK_SIZE >= size)
+            .cloned()
+            .collect();
+        for index in beyond {
+            state.forget(index);
+        }
+        let within = (size % CACHE_BLOCK_SIZE) as usize;
+        if let Some(block) = state.blocks.get_mut(&(size / CACHE_BLOCK_SIZE)) {
+            block.data[within..].iter_mut().for_each(|it| *it = 0);
+        }
+        self.device.set_size(size)?;
+        state.written += 1;
+        state.size = size;
+        Ok(())
+    }
+    fn cache_stats(&self) -> Option<CacheStats> {
+        Some(self.state.lock().unwrap().stats)
+    }
+}
+
+impl<D: BlockDevice> Drop for BlockCache<D> {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            error!("cannot write back the block cache: {}", e);
+        }
+    }
+}
+
+#[test]
+fn test_block_cache() -> io::Result<()> {
+    use crate::disk::device::Memory;
+    use crate::disk::fault::{Fault, FaultRule, FaultSchedule, Faulty, Op};
+    let cache = BlockCache::new(Memory::default(), 2 * CACHE_BLOCK_SIZE)?;
+    cache.write_at(b"hello world", 4090)?;
+    assert_eq!(cache.size()?, 4101);
+    assert_eq!(cache.device.size()?, 0);
+    let mut buffer = [0u8; 11];
+    assert_eq!(cache.read_at(&mut buffer, 4090)?, 11);
+    assert_eq!(&buffer, b"hello world");
+    assert_eq!(
+        cache.cache_stats(),
+        Some(CacheStats {
+            hits: 2,
+            misses: 2,
+            write_backs: 0
+        })
+    );
+
+    // a third block evicts the least recently used one and writes it back
+    cache.write_at(&[7u8; 4096], 8192)?;
+    assert_eq!(cache.cache_stats().unwrap().write_backs, 1);
+    assert_eq!(cache.device.read_at(&mut buffer[..6], 4090)?, 6);
+    assert_eq!(&buffer[..6], b"hello ");
+    cache.flush()?;
+    assert_eq!(cache.device.size()?, 12288);
+    assert_eq!(cache.read_at(&mut buffer, 4090)?, 11);
+    assert_eq!(&buffer, b"hello world");
+
+    cache.discard(4096, 4096)?;
+    assert_eq!(cache.read_at(&mut buffer, 4090)?, 11);
+    assert_eq!(&buffer, b"hello \0\0\0\0\0");
+    cache.set_size(4092)?;
+    assert_eq!(cache.read_at(&mut buffer, 4090)?, 2);
+    assert_eq!(cache.device.size()?, 4092);
+
+    // a failed flush keeps the blocks dirty for the next one
+    let schedule = FaultSchedule::new(0);
+    let cache = BlockCache::new(Faulty::new(Memory::default(), schedule.clone()), 8192)?;
+    cache.write_at(b"hello", 0)?;
+    schedule.inject(FaultRule::new(Op::Write, Fault::Error).times(1));
+    assert!(cache.flush().is_err());
+    cache.flush()?;
+    assert_eq!(cache.device.read_at(&mut buffer[..5], 0)?, 5);
+    assert_eq!(&buffer[..5], b"hello");
+    Ok(())
+}
@@ -0,0 +1,327 @@
+//! An image kept as a directory of fixed-size chunk files, so that backups copy what changed.
+//!
+//! Chunk `i` holds the `CHUNK_SIZE` bytes at `i * CHUNK_SIZE` in a file named by `i` in 8 hex
+//! digits. A chunk that is missing or shorter reads as zeroes, zeroes written to a missing chunk
+//! do not create it and a discard over a whole chunk deletes it. The size of the image is kept in
+//! the file `size` as decimal text, stored on flush. An image that grew since then reaches at least
+//! as far as its last chunk.
+
+use crate::disk::device::{BlockDevice, HostFile};
+use crate::util::align;
+use libc::EROFS;
+use std::cmp::{max, min};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::ErrorKind;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+pub const CHUNK_SIZE: u64 = 4 << 20;
+/// Chunk files kept open, the clean ones are closed beyond it.
+const MAX_OPEN_CHUNKS: usize = 64;
+const SIZE_FILE: &str = "size";
+
+struct State {
+    open: HashMap<u64, Arc<File>>,
+    /// Chunks written since the last flush.
+    dirty: HashSet<u64>,
+    size: u64,
+    /// What the size file holds.
+    stored_size: u64,
+}
+
+pub struct Chunks {
+    directory: PathBuf,
+    read_only: bool,
+    state: Mutex<State>,
+}
+
+/// Reads the chunk names of `directory` as their indices, skipping every other file.
+fn chunk_indices(directory: &Path) -> io::Result<Vec<u64>> {
+    let mut indices = vec![];
+    for entry in fs::read_dir(directory)? {
+        let name = entry?.file_name();
+        let name = match name.to_str() {
+            Some(name) if name.len() == 8 => name,
+            _ => continue,
+        };
+        if let Ok(index) = u64::from_str_radix(name, 16) {
+            indices.push(index);
+        }
+    }
+    Ok(indices)
+}
+
+impl Chunks {
+    pub fn open<P: AsRef<Path>>(directory: P, read_only: bool) -> io::Result<Self> {
+        let directory = directory.as_ref().to_path_buf();
+        let stored_size = match fs::read_to_string(directory.join(SIZE_FILE)) {
+            Ok(text) => text.trim().parse().map_err(|_| {
+                io::Error::new(ErrorKind::InvalidData, "bad size file in a chunk directory")
+            })?,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+        // writes since the last flush, or a size file lost on the way, reach up to the last chunk
+        let mut size = stored_size;
+        for index in chunk_indices(&directory)? {
+            let length = fs::metadata(directory.join(chunk_name(index)))?.len();
+            size = max(size, index * CHUNK_SIZE + length);
+        }
+        Ok(Chunks {
+            directory,
+            read_only,
+            state: Mutex::new(State {
+                open: HashMap::new(),
+                dirty: HashSet::new(),
+                size,
+                stored_size,
+            }),
+        })
+    }
+    fn path(&self, index: u64) -> PathBuf {
+        self.directory.join(chunk_name(index))
+    }
+    /// Chunk `index`, `None` when it does not exist and `create` is not set. A chunk handed out
+    /// for a `write` is dirty from then on, so it stays open until the next flush syncs it.
+    fn chunk(&self, index: u64, create: bool, write: bool) -> io::Result<Option<Arc<File>>> {
+        {
+            let mut state = self.state.lock().unwrap();
+            if let Some(file) = state.open.get(&index).cloned() {
+                if write {
+                    state.dirty.insert(index);
+                }
+                return Ok(Some(file));
+            }
+        }
+        let opened = OpenOptions::new()
+            .read(true)
+            .write(!self.read_only)
+            .create(create)
+            .open(self.path(index));
+        let file = match opened {
+            Ok(file) => Arc::new(file),
+            Err(ref e) if e.kind() == ErrorKind::NotFound && !create => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut state = self.state.lock().unwrap();
+        let State { open, dirty, .. } = &mut *state;
+        if open.len() >= MAX_OPEN_CHUNKS {
+            open.retain(|it, _| dirty.contains(it));
+        }
+        if write {
+            dirty.insert(index);
+        }
+        Ok(Some(open.entry(index).or_insert(file).clone()))
+    }
+    fn check_writable(&self) -> io::Result<()> {
+        if self.read_only {
+            Err(io::Error::from_raw_os_error(EROFS))
+        } else {
+            Ok(())
+        }
+    }
+    /// Deletes chunk `index`.
+    fn remove(&self, index: u64) -> io::Result<()> {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.open.remove(&index);
+            state.dirty.remove(&index);
+        }
+        match fs::remove_file(self.path(index)) {
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+    /// Replaces the size file, so that it is never seen half written.
+    fn store_size(&self, size: u64) -> io::Result<()> {
+        let temporary = self.directory.join(format!("{}.new", SIZE_FILE));
+        let file = File::create(&temporary)?;
+        file.write_all_at(size.to_string().as_bytes(), 0)?;
+        file.sync_all()?;
+        fs::rename(&temporary, self.directory.join(SIZE_FILE))
+    }
+}
+
+fn chunk_name(index: u64) -> String {
+    format!("{:08x}", index)
+}
+
+/// The chunk `offset` falls into, the offset within it and how much of `length` it takes.
+fn locate(offset: u64, length: usize) -> (u64, u64, usize) {
+    let index = offset / CHUNK_SIZE;
+    let start = offset % CHUNK_SIZE;
+    (
+        index,
+        start,
+        min(length as u64, CHUNK_SIZE - start) as usize,
+    )
+}
+
+impl BlockDevice for Chunks {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let size = self.state.lock().unwrap().size;
+        let length = min(buf.len() as u64, size.saturating_sub(offset)) as usize;
+        let (index, start, length) = locate(offset, length);
+        let buf = &mut buf[..length];
+        let mut read = 0;
+        if let Some(file) = self.chunk(index, false, false)? {
+            while read < length {
+                match file.read_at(&mut buf[read..], start + read as u64)? {
+                    0 => break,
+                    it => read += it,
+                }
+            }
+        }
+        buf[read..].iter_mut().for_each(|it| *it = 0);
+        Ok(length)
+    }
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        self.check_writable()?;
+        let (index, start, length) = locate(offset, buf.len());
+        let buf = &buf[..length];
+        let zeroes = buf.iter().all(|&it| it == 0);
+        // a missing chunk already reads as zeroes
+        if let Some(file) = self.chunk(index, !zeroes, true)? {
+            file.write_all_at(buf, start)?;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.size = max(state.size, offset + length as u64);
+        Ok(length)
+    }
+    fn flush(&self) -> io::Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        let files = {
+            let mut state = self.state.lock().unwrap();
+            let dirty = state.dirty.drain().collect::<Vec<_>>();
+            dirty
+                .iter()
+                .filter_map(|it| state.open.get(it).cloned())
+                .collect::<Vec<_>>()
+        };
+        for file in files {
+            file.sync_data()?;
+        }
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.stored_size != state.size {
+                self.store_size(state.size)?;
+                state.stored_size = state.size;
+            }
+        }
+        // new and deleted chunks
+        File::open(&self.directory)?.sync_all()
+    }
+    fn discard(&self, offset: u64, length: u64) -> io::Result<()> {
+        self.check_writable()?;
+        let size = self.state.lock().unwrap().size;
+        let end = min(offset.saturating_add(length), size);
+        let mut position = offset;
+        while position < end {
+            let (index, start, length) = locate(position, (end - position) as usize);
+            if length as u64 == CHUNK_SIZE || start == 0 && position + length as u64 == size {
+                self.remove(index)?;
+            } else if let Some(file) = self.chunk(index, false, true)? {
+                let hole = HostFile(file.try_clone()?).discard(start, length as u64);
+                if hole.is_err() {
+                    file.write_all_at(&vec![0u8; length], start)?;
+                }
+            }
+            position += length as u64;
+        }
+        Ok(())
+    }
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.state.lock().unwrap().size)
+    }
+    fn set_size(&self, size: u64) -> io::Result<()> {
+        self.check_writable()?;
+        let old_size = self.state.lock().unwrap().size;
+        let chunks = align(size, CHUNK_SIZE) / CHUNK_SIZE;
+        if size < old_size {
+            for index in chunk_indices(&self.directory)? {
+                if index >= chunks {
+                    self.remove(index)?;
+                }
+            }
+            // what is cut off the last chunk has to read back as zeroes when the image grows again
+            if let Some(file) = self.chunk(size / CHUNK_SIZE, false, true)? {
+                let length = size % CHUNK_SIZE;
+                if file.metadata()?.len() > length {
+                    file.set_len(length)?;
+                }
+            }
+        }
+        let mut state = self.state.lock().unwrap();
+        state.size = size;
+        self.store_size(size)?;
+        state.stored_size = size;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_chunks() -> io::Result<()> {
+    use crate::disk::device::{read_all_at, write_all_at};
+    use tempfile::tempdir;
+
+    let tempdir = tempdir()?;
+    let chunks = Chunks::open(tempdir.path(), false)?;
+    assert_eq!(chunks.size()?, 0);
+
+    // a write over a chunk boundary touches both chunks only
+    write_all_at(&chunks, b"hello world", CHUNK_SIZE * 3 - 5)?;
+    assert_eq!(chunks.size()?, CHUNK_SIZE * 3 + 6);
+    assert_eq!(chunk_indices(tempdir.path())?.len(), 2);
+    assert!(chunks.state.lock().unwrap().dirty.contains(&3));
+    // only a flush stores the size, a crash before it still finds the data past the stored size
+    assert!(!tempdir.path().join(SIZE_FILE).exists());
+    assert_eq!(
+        Chunks::open(tempdir.path(), true)?.size()?,
+        CHUNK_SIZE * 3 + 6
+    );
+    let mut buffer = [1u8; 16];
+    assert_eq!(read_all_at(&chunks, &mut buffer, CHUNK_SIZE * 3 - 10)?, 16);
+    assert_eq!(&buffer, b"\0\0\0\0\0hello world");
+    assert_eq!(read_all_at(&chunks, &mut buffer, 4096)?, 16);
+    assert_eq!(buffer, [0u8; 16]);
+    assert_eq!(read_all_at(&chunks, &mut buffer, CHUNK_SIZE * 3)?, 6);
+
+    // zeroes never create a chunk
+    write_all_at(&chunks, &[0u8; 4096], 0)?;
+    assert!(!tempdir.path().join("00000000").exists());
+    chunks.flush()?;
+    let reopened = Chunks::open(tempdir.path(), true)?;
+    assert_eq!(reopened.size()?, CHUNK_SIZE * 3 + 6);
+    assert_eq!(
+        reopened.write_at(b"x", 0).unwrap_err().raw_os_error(),
+        Some(EROFS)
+    );
+    fs::remove_file(tempdir.path().join(SIZE_FILE))?;
+    assert_eq!(
+        Chunks::open(tempdir.path(), true)?.size()?,
+        CHUNK_SIZE * 3 + 6
+    );
+
+    // a discard deletes the chunks it covers and zeroes the edges of the others
+    write_all_at(&chunks, b"first", CHUNK_SIZE - 5)?;
+    chunks.discard(CHUNK_SIZE - 2, CHUNK_SIZE * 2 + 4)?;
+    assert!(!tempdir.path().join("00000001").exists());
+    assert_eq!(read_all_at(&chunks, &mut buffer[..5], CHUNK_SIZE - 5)?, 5);
+    assert_eq!(&buffer[..5], b"fir\0\0");
+    assert_eq!(read_all_at(&chunks, &mut buffer, CHUNK_SIZE * 3 - 5)?, 11);
+    assert_eq!(&buffer[..11], b"\0\0\0\0\0\0\0orld");
+
+    chunks.set_size(CHUNK_SIZE + 2)?;
+    assert_eq!(chunk_indices(tempdir.path())?, vec![0]);
+    chunks.set_size(CHUNK_SIZE * 2)?;
+    assert_eq!(read_all_at(&chunks, &mut buffer, CHUNK_SIZE - 5)?, 16);
+    assert_eq!(&buffer, b"fir\0\0\0\0\0\0\0\0\0\0\0\0\0");
+    assert_eq!(Chunks::open(tempdir.path(), true)?.size()?, CHUNK_SIZE * 2);
+    Ok(())
+}
@@ -0,0 +1,176 @@
+//! Spans one address space over several devices.
+//!
+//! An address holds the device ID above `DEVICE_SHIFT` and the offset into that device below it,
+//! so every device gets a window of its own and the first one alone is addressed like a plain
+//! image. Devices after the first are added to make room and keep their size, the filesystem
+//! labels each with its UUID and position.
+
+use crate::disk::cache::CacheStats;
+use crate::disk::device::BlockDevice;
+use libc::ENXIO;
+use std::cmp::min;
+use std::io;
+use std::io::ErrorKind;
+use std::sync::RwLock;
+
+pub const DEVICE_SHIFT: u32 = 48;
+/// Size of the window of a device, no device may be larger.
+pub const MAX_DEVICE_SIZE: u64 = 1 << DEVICE_SHIFT;
+
+/// The address of `offset` on `device`.
+pub fn address(device: usize, offset: u64) -> u64 {
+    (device as u64) << DEVICE_SHIFT | offset
+}
+
+/// The device and offset `address` points to.
+pub fn split(address: u64) -> (usize, u64) {
+    (
+        (address >> DEVICE_SHIFT) as usize,
+        address & (MAX_DEVICE_SIZE - 1),
+    )
+}
+
+pub struct Concat {
+    devices: RwLock<Vec<Box<dyn BlockDevice>>>,
+}
+
+impl Concat {
+    pub fn new<D: BlockDevice + 'static>(first: D) -> Self {
+        Concat {
+            devices: RwLock::new(vec![Box::new(first)]),
+        }
+    }
+    /// Appends `device` and returns its ID.
+    pub fn push<D: BlockDevice + 'static>(&self, device: D) -> usize {
+        let mut devices = self.devices.write().unwrap();
+        devices.push(Box::new(device));
+        devices.len() - 1
+    }
+    pub fn count(&self) -> usize {
+        self.devices.read().unwrap().len()
+    }
+    /// The size of every device, by ID.
+    pub fn sizes(&self) -> io::Result<Vec<u64>> {
+        let devices = self.devices.read().unwrap();
+        devices.iter().map(|it| it.size()).collect()
+    }
+}
+
+/// The device `address` points to and how much of `length` stays within its window.
+fn locate(address: u64, length: usize) -> (usize, u64, usize) {
+    let (device, offset) = split(address);
+    let length = min(length as u64, MAX_DEVICE_SIZE - offset) as usize;
+    (device, offset, length)
+}
+
+impl BlockDevice for Concat {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let (device, offset, length) = locate(offset, buf.len());
+        match self.devices.read().unwrap().get(device) {
+            Some(device) => device.read_at(&mut buf[..length], offset),
+            None => Err(io::Error::from_raw_os_error(ENXIO)),
+        }
+    }
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        let (device, offset, length) = locate(offset, buf.len());
+        match self.devices.read().unwrap().get(device) {
+            Some(device) => device.write_at(&buf[..length], offset),
+            None => Err(io::Error::from_raw_os_error(ENXIO)),
+        }
+    }
+    fn flush(&self) -> io::Result<()> {
+        for device in self.devices.read().unwrap().iter() {
+            device.flush()?;
+        }
+        Ok(())
+    }
+    fn discard(&self, offset: u64, length: u64) -> io::Result<()> {
+        let devices = self.devices.read().unwrap();
+        let end = offset.saturating_add(length);
+        let mut position = offset;
+        while position < end {
+            let (device, start) = split(position);
+            let window_end = address(device + 1, 0);
+            let device = match devices.get(device) {
+                Some(device) => device,
+                None => break,
+            };
+            // the rest of a window past the end of its device holds nothing
+            let size = device.size()?;
+            let stop = min(min(end, window_end) - position + start, size);
+            if start < stop {
+                device.discard(start, stop - start)?;
+            }
+            position = window_end;
+        }
+        Ok(())
+    }
+    /// The end of the address space, the window of the last device up to its size.
+    fn size(&self) -> io::Result<u64> {
+        let devices = self.devices.read().unwrap();
+        let last = devices.len() - 1;
+        Ok(address(last, devices[last].size()?))
+    }
+    fn set_size(&self, size: u64) -> io::Result<()> {
+        let devices = self.devices.read().unwrap();
+        let (device, size) = split(size);
+        if device != devices.len() - 1 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "only the last device of a filesystem can be resized",
+            ));
+        }
+        devices[device].set_size(size)
+    }
+    fn cache_stats(&self) -> Option<CacheStats> {
+        let devices = self.devices.read().unwrap();
+        let mut stats = devices.iter().filter_map(|it| it.cache_stats()).peekable();
+        stats.peek()?;
+        Some(stats.fold(CacheStats::default(), |total, it| CacheStats {
+            hits: total.hits + it.hits,
+            misses: total.misses + it.misses,
+            write_backs: total.write_backs + it.write_backs,
+        }))
+    }
+}
+
+#[test]
+fn test_concat() -> io::Result<()> {
+    use crate::disk::device::{read_all_at, write_all_at, Memory};
+    use std::sync::Arc;
+
+    let first = Arc::new(Memory::default());
+    let concat = Concat::new(first.clone());
+    write_all_at(&concat, b"hello world", 512)?;
+    assert_eq!(concat.size()?, 523);
+    let second = Arc::new(Memory::default());
+    second.set_size(8192)?;
+    assert_eq!(concat.push(second.clone()), 1);
+    assert_eq!(concat.sizes()?, vec![523, 8192]);
+    assert_eq!(concat.size()?, address(1, 8192));
+    assert_eq!(split(address(1, 4096)), (1, 4096));
+
+    // every device is addressed within its own window
+    write_all_at(&concat, b"second", address(1, 4096))?;
+    let mut buffer = [0u8; 6];
+    second.read_at(&mut buffer, 4096)?;
+    assert_eq!(&buffer, b"second");
+    let mut buffer = [0u8; 11];
+    assert_eq!(read_all_at(&concat, &mut buffer, 512)?, 11);
+    assert_eq!(&buffer, b"hello world");
+    assert_eq!(read_all_at(&concat, &mut buffer, MAX_DEVICE_SIZE - 4)?, 0);
+    assert!(concat.read_at(&mut buffer, address(2, 0)).is_err());
+
+    // a discard over both devices stops at the end of each
+    concat.discard(518, address(1, 4099) - 518)?;
+    assert_eq!(first.size()?, 523);
+    assert_eq!(read_all_at(&concat, &mut buffer, 512)?, 11);
+    assert_eq!(&buffer, b"hello \0\0\0\0\0");
+    second.read_at(&mut buffer[..6], 4096)?;
+    assert_eq!(&buffer[..6], b"\0\0\0ond");
+
+    assert!(concat.set_size(1024).is_err());
+    concat.set_size(address(1, 16384))?;
+    assert_eq!(second.size()?, 16384);
+    Ok(())
+}
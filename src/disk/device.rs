@@ -0,0 +1,274 @@
+//! Backing stores a `Disk` can sit on.
+
+use crate::disk::cache::CacheStats;
+use std::cmp::min;
+use std::fs::File;
+use std::io;
+use std::io::ErrorKind;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+
+/// `_IO(0x12, 119)` from `linux/fs.h`.
+#[cfg(target_os = "linux")]
+const BLKDISCARD: u64 = 0x1277;
+/// `_IOR(0x12, 114, size_t)` from `linux/fs.h`.
+#[cfg(target_os = "linux")]
+const BLKGETSIZE64: u64 = 0x8008_1272;
+
+/// Byte-addressed storage. Reads past the end return fewer bytes, writes past it extend the store
+/// where it can grow. All I/O is positional, so a device can be used from several threads at once.
+pub trait BlockDevice: Send + Sync {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize>;
+    /// Writes every buffer at its offset. Stores that keep several requests in flight submit them
+    /// together.
+    fn write_batch(&self, writes: &[(u64, &[u8])]) -> io::Result<()> {
+        for &(offset, buf) in writes {
+            write_all_at(self, buf, offset)?;
+        }
+        Ok(())
+    }
+    /// Makes everything written so far durable.
+    fn flush(&self) -> io::Result<()>;
+    /// Tells the store that `length` bytes at `offset` are unused, they read back as zeroes.
+    fn discard(&self, offset: u64, length: u64) -> io::Result<()>;
+    fn size(&self) -> io::Result<u64>;
+    fn set_size(&self, size: u64) -> io::Result<()>;
+    /// Hit and miss counters of stores that cache.
+    fn cache_stats(&self) -> Option<CacheStats> {
+        None
+    }
+}
+
+pub fn write_all_at<D: BlockDevice + ?Sized>(
+    device: &D,
+    mut buf: &[u8],
+    mut offset: u64,
+) -> io::Result<()> {
+    while !buf.is_empty() {
+        match device.write_at(buf, offset)? {
+            0 => return Err(io::Error::new(ErrorKind::WriteZero, "short write")),
+            written => {
+                buf = &buf[written..];
+                offset += written as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads until `buf` is full or the store ends, returns the number of bytes read.
+pub fn read_all_at<D: BlockDevice + ?Sized>(
+    device: &D,
+    buf: &mut [u8],
+    offset: u64,
+) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match device.read_at(&mut buf[read..], offset + read as u64)? {
+            0 => break,
+            it => read += it,
+        }
+    }
+    Ok(read)
+}
+
+impl<D: BlockDevice + ?Sized> BlockDevice for Box<D> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        (**self).read_at(buf, offset)
+    }
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        (**self).write_at(buf, offset)
+    }
+    fn write_batch(&self, writes: &[(u64, &[u8])]) -> io::Result<()> {
+        (**self).write_batch(writes)
+    }
+    fn flush(&self) -> io::Result<()> {
+        (**self).flush()
+    }
+    fn discard(&self, offset: u64, length: u64) -> io::Result<()> {
+        (**self).discard(offset, length)
+    }
+    fn size(&self) -> io::Result<u64> {
+        (**self).size()
+    }
+    fn set_size(&self, size: u64) -> io::Result<()> {
+        (**self).set_size(size)
+    }
+    fn cache_stats(&self) -> Option<CacheStats> {
+        (**self).cache_stats()
+    }
+}
+
+impl<D: BlockDevice + ?Sized> BlockDevice for Arc<D> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        (**self).read_at(buf, offset)
+    }
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        (**self).write_at(buf, offset)
+    }
+    fn write_batch(&self, writes: &[(u64, &[u8])]) -> io::Result<()> {
+        (**self).write_batch(writes)
+    }
+    fn flush(&self) -> io::Result<()> {
+        (**self).flush()
+    }
+    fn discard(&self, offset: u64, length: u64) -> io::Result<()> {
+        (**self).discard(offset, length)
+    }
+    fn size(&self) -> io::Result<u64> {
+        (**self).size()
+    }
+    fn set_size(&self, size: u64) -> io::Result<()> {
+        (**self).set_size(size)
+    }
+    fn cache_stats(&self) -> Option<CacheStats> {
+        (**self).cache_stats()
+    }
+}
+
+/// A regular file on the host holding the image.
+pub struct HostFile(pub File);
+
+impl BlockDevice for HostFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.0.read_at(buf, offset)
+    }
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        self.0.write_at(buf, offset)
+    }
+    fn flush(&self) -> io::Result<()> {
+        self.0.sync_data()
+    }
+    #[cfg(target_os = "linux")]
+    fn discard(&self, offset: u64, length: u64) -> io::Result<()> {
+        let result = unsafe {
+            libc::fallocate(
+                self.0.as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                offset as _,
+                length as _,
+            )
+        };
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    fn discard(&self, _offset: u64, _length: u64) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+    }
+    fn size(&self) -> io::Result<u64> {
+        self.0.metadata().map(|it| it.len())
+    }
+    fn set_size(&self, size: u64) -> io::Result<()> {
+        self.0.set_len(size)
+    }
+}
+
+impl AsRawFd for HostFile {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+/// A raw block device, its size is fixed by the device.
+pub struct RawDevice(pub File);
+
+impl BlockDevice for RawDevice {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.0.read_at(buf, offset)
+    }
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        self.0.write_at(buf, offset)
+    }
+    fn flush(&self) -> io::Result<()> {
+        self.0.sync_data()
+    }
+    #[cfg(target_os = "linux")]
+    fn discard(&self, offset: u64, length: u64) -> io::Result<()> {
+        let range = [offset, length];
+        if unsafe { libc::ioctl(self.0.as_raw_fd(), BLKDISCARD as _, &range) } == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    fn discard(&self, _offset: u64, _length: u64) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+    }
+    #[cfg(target_os = "linux")]
+    fn size(&self) -> io::Result<u64> {
+        let mut size = 0u64;
+        if unsafe { libc::ioctl(self.0.as_raw_fd(), BLKGETSIZE64 as _, &mut size) } == 0 {
+            Ok(size)
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    fn size(&self) -> io::Result<u64> {
+        use std::io::{Seek, SeekFrom};
+        (&self.0).seek(SeekFrom::End(0))
+    }
+    fn set_size(&self, size: u64) -> io::Result<()> {
+        if size == self.size()? {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "the size of a block device cannot be changed",
+            ))
+        }
+    }
+}
+
+impl AsRawFd for RawDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+/// An image kept in memory, gone when dropped.
+#[derive(Default)]
+pub struct Memory(Mutex<Vec<u8>>);
+
+impl BlockDevice for Memory {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let data = self.0.lock().unwrap();
+        let start = min(offset, data.len() as u64) as usize;
+        let length = min(buf.len(), data.len() - start);
+        buf[..length].copy_from_slice(&data[start..start + length]);
+        Ok(length)
+    }
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        let mut data = self.0.lock().unwrap();
+        let end = offset as usize + buf.len();
+        if end > data.len() {
+            data.resize(end, 0);
+        }
+        data[offset as usize..end].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+    fn discard(&self, offset: u64, length: u64) -> io::Result<()> {
+        let mut data = self.0.lock().unwrap();
+        let end = min(offset + length, data.len() as u64) as usize;
+        let start = min(offset as usize, end);
+        data[start..end].iter_mut().for_each(|it| *it = 0);
+        Ok(())
+    }
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.0.lock().unwrap().len() as u64)
+    }
+    fn set_size(&self, size: u64) -> io::Result<()> {
+        self.0.lock().unwrap().resize(size as usize, 0);
+        Ok(())
+    }
+}
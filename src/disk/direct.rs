@@ -0,0 +1,221 @@
+//! Block-aligned I/O for backing stores opened with `O_DIRECT`.
+
+use crate::disk::device::{write_all_at, BlockDevice};
+use crate::util::align;
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::cmp::{max, min};
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::slice;
+use std::sync::Mutex;
+
+/// Alignment of offsets, lengths and buffers, enough for devices with 4K logical blocks.
+pub const DIRECT_ALIGNMENT: u64 = 4096;
+
+/// A zeroed heap buffer starting at a `DIRECT_ALIGNMENT` boundary.
+pub struct AlignedBuffer {
+    data: *mut u8,
+    layout: Layout,
+}
+
+unsafe impl Send for AlignedBuffer {}
+unsafe impl Sync for AlignedBuffer {}
+
+impl AlignedBuffer {
+    pub fn new(length: usize) -> Self {
+        let layout = Layout::from_size_align(max(length, 1), DIRECT_ALIGNMENT as usize).unwrap();
+        let data = unsafe { alloc_zeroed(layout) };
+        assert!(!data.is_null(), "out of memory");
+        AlignedBuffer { data, layout }
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.data, self.layout.size()) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.data, self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.data, self.layout) }
+    }
+}
+
+fn is_aligned(buf: &[u8], offset: u64) -> bool {
+    let mask = DIRECT_ALIGNMENT as usize - 1;
+    (buf.as_ptr() as usize | buf.len() | offset as usize) & mask == 0
+}
+
+fn read_fully<D: BlockDevice + ?Sized>(
+    device: &D,
+    buf: &mut [u8],
+    offset: u64,
+) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match device.read_at(&mut buf[read..], offset + read as u64)? {
+            0 => break,
+            // only the end of the store cuts a read off in the middle of a block
+            it if it & (DIRECT_ALIGNMENT as usize - 1) != 0 => return Ok(read + it),
+            it => read += it,
+        }
+    }
+    Ok(read)
+}
+
+/// Turns any request into whole aligned blocks. Unaligned writes read the blocks they touch first,
+/// so they are serialized against every other write.
+pub struct Direct<D: BlockDevice> {
+    device: D,
+    writing: Mutex<()>,
+}
+
+impl<D: BlockDevice> Direct<D> {
+    pub fn new(device: D) -> Self {
+        Direct {
+            device,
+            writing: Mutex::new(()),
+        }
+    }
+    /// Fills `block` with the aligned block at `offset`, zeroes past the end of the store.
+    fn read_block(&self, block: &mut [u8], offset: u64) -> io::Result<()> {
+        let read = read_fully(&self.device, block, offset)?;
+        block[read..].iter_mut().for_each(|it| *it = 0);
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for Direct<D> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        if is_aligned(buf, offset) {
+            return self.device.read_at(buf, offset);
+        }
+        let start = offset / DIRECT_ALIGNMENT * DIRECT_ALIGNMENT;
+        let end = align(offset + buf.len() as u64, DIRECT_ALIGNMENT);
+        let mut bounce = AlignedBuffer::new((end - start) as usize);
+        let read = read_fully(&self.device, &mut bounce, start)?;
+        let skip = (offset - start) as usize;
+        let length = min(read.saturating_sub(skip), buf.len());
+        buf[..length].copy_from_slice(&bounce[skip..skip + length]);
+        Ok(length)
+    }
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        let _writing = self.writing.lock().unwrap();
+        if is_aligned(buf, offset) {
+            return self.device.write_at(buf, offset);
+        }
+        let size = self.device.size()?;
+        let start = offset / DIRECT_ALIGNMENT * DIRECT_ALIGNMENT;
+        let end = align(offset + buf.len() as u64, DIRECT_ALIGNMENT);
+        let mut bounce = AlignedBuffer::new((end - start) as usize);
+        let block = DIRECT_ALIGNMENT as usize;
+        self.read_block(&mut bounce[..block], start)?;
+        if end - start > DIRECT_ALIGNMENT {
+            let last = bounce.len() - block;
+            self.read_block(&mut bounce[last..], end - DIRECT_ALIGNMENT)?;
+        }
+        let skip = (offset - start) as usize;
+        bounce[skip..skip + buf.len()].copy_from_slice(buf);
+        write_all_at(&self.device, &bounce, start)?;
+        // the last block is written whole, cut the store back to where the data ends
+        if end > size {
+            self.device.set_size(max(size, offset + buf.len() as u64))?;
+        }
+        Ok(buf.len())
+    }
+    fn write_batch(&self, writes: &[(u64, &[u8])]) -> io::Result<()> {
+        if writes.iter().all(|&(offset, buf)| is_aligned(buf, offset)) {
+            let _writing = self.writing.lock().unwrap();
+            return self.device.write_batch(writes);
+        }
+        for &(offset, buf) in writes {
+            write_all_at(self, buf, offset)?;
+        }
+        Ok(())
+    }
+    fn flush(&self) -> io::Result<()> {
+        self.device.flush()
+    }
+    fn discard(&self, offset: u64, length: u64) -> io::Result<()> {
+        self.device.discard(offset, length)
+    }
+    fn size(&self) -> io::Result<u64> {
+        self.device.size()
+    }
+    fn set_size(&self, size: u64) -> io::Result<()> {
+        self.device.set_size(size)
+    }
+}
+
+#[test]
+fn test_direct() -> io::Result<()> {
+    use crate::disk::device::Memory;
+    use std::io::ErrorKind;
+
+    /// Refuses unaligned requests like a file opened with `O_DIRECT`.
+    struct Strict(Memory);
+
+    impl Strict {
+        fn check(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+            if is_aligned(buf, offset) {
+                Ok(())
+            } else {
+                Err(io::Error::new(ErrorKind::InvalidInput, "unaligned"))
+            }
+        }
+    }
+
+    impl BlockDevice for Strict {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+            self.check(buf, offset)?;
+            self.0.read_at(buf, offset)
+        }
+        fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+            self.check(buf, offset)?;
+            self.0.write_at(buf, offset)
+        }
+        fn flush(&self) -> io::Result<()> {
+            self.0.flush()
+        }
+        fn discard(&self, offset: u64, length: u64) -> io::Result<()> {
+            self.0.discard(offset, length)
+        }
+        fn size(&self) -> io::Result<u64> {
+            self.0.size()
+        }
+        fn set_size(&self, size: u64) -> io::Result<()> {
+            self.0.set_size(size)
+        }
+    }
+
+    let direct = Direct::new(Strict(Memory::default()));
+    assert!(direct.device.write_at(b"unaligned", 1).is_err());
+    direct.write_at(&[1u8; 8192], 0)?;
+    direct.write_at(b"hello world", 4090)?;
+    assert_eq!(direct.size()?, 8192);
+    direct.write_at(b"tail", 8192)?;
+    assert_eq!(direct.size()?, 8196);
+
+    let mut buffer = [0u8; 13];
+    assert_eq!(direct.read_at(&mut buffer, 4089)?, 13);
+    assert_eq!(&buffer, b"\x01hello world\x01");
+    assert_eq!(direct.read_at(&mut buffer, 8190)?, 6);
+    assert_eq!(&buffer[..6], b"\x01\x01tail");
+
+    let mut block = AlignedBuffer::new(4096);
+    block.iter_mut().for_each(|it| *it = 2);
+    direct.write_batch(&[(4096, &block[..]), (8192, &block[..])])?;
+    assert_eq!(direct.size()?, 12288);
+    assert_eq!(direct.read_at(&mut block, 0)?, 4096);
+    assert_eq!(block[0], 1);
+    assert_eq!(block[4095], b' ');
+    Ok(())
+}
@@ -1,20 +1,18 @@
+use crate::disk::encode::{Decode, Encode};
 use crate::disk::Disk;
-use bincode::{serialized_size, Error};
-use serde::de::DeserializeOwned;
-use serde::Serialize;
+use std::io;
 
-pub trait DumpToFixedLocation<DumpPart: Serialize + DeserializeOwned>: Sized {
+pub trait DumpToFixedLocation<DumpPart: Encode + Decode>: Sized {
     fn dump_part(&self) -> DumpPart;
     fn location(&self) -> u64;
-    fn load(disk: &Disk, address: u64) -> Result<Self, Error>;
+    fn load(disk: &Disk, address: u64) -> io::Result<Self>;
     fn dump_size(&self) -> u64 {
-        let dump_part = self.dump_part();
-        serialized_size(&dump_part).unwrap()
+        self.dump_part().encoded_size()
     }
     fn address_after_dump(&self) -> u64 {
         self.location() + self.dump_size()
     }
-    fn sync(&self, disk: &Disk) {
+    fn sync(&self, disk: &Disk) -> io::Result<()> {
         disk.dump_fixed_location(self)
     }
 }
@@ -0,0 +1,179 @@
+//! Explicit little-endian encoding for everything dumbfs writes to disk.
+//! `doc/format.md` describes the resulting layouts.
+
+use std::io;
+use std::io::{ErrorKind, Read};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub trait Encode {
+    fn encode(&self, encoder: &mut Encoder);
+    fn encoded_size(&self) -> u64 {
+        let mut encoder = Encoder::default();
+        self.encode(&mut encoder);
+        encoder.size() as u64
+    }
+}
+
+pub trait Decode: Sized {
+    fn decode<R: Read>(decoder: &mut Decoder<R>) -> io::Result<Self>;
+}
+
+pub fn invalid_data<T>(message: &str) -> io::Result<T> {
+    Err(io::Error::new(ErrorKind::InvalidData, message.to_string()))
+}
+
+#[derive(Default)]
+pub struct Encoder {
+    buffer: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn size(&self) -> usize {
+        self.buffer.len()
+    }
+    pub fn u8(&mut self, value: u8) {
+        self.buffer.push(value);
+    }
+    pub fn u16(&mut self, value: u16) {
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn u32(&mut self, value: u32) {
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn u64(&mut self, value: u64) {
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn i64(&mut self, value: i64) {
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.buffer.extend_from_slice(value);
+    }
+    /// Seconds relative to the unix epoch as an `i64`, then the nanoseconds as an `u32`.
+    pub fn time(&mut self, value: SystemTime) {
+        let (seconds, nanoseconds) = match value.duration_since(UNIX_EPOCH) {
+            Ok(after) => (after.as_secs() as i64, after.subsec_nanos()),
+            Err(before) => {
+                let before = before.duration();
+                if before.subsec_nanos() == 0 {
+                    (-(before.as_secs() as i64), 0)
+                } else {
+                    (
+                        -(before.as_secs() as i64) - 1,
+                        1_000_000_000 - before.subsec_nanos(),
+                    )
+                }
+            }
+        };
+        self.i64(seconds);
+        self.u32(nanoseconds);
+    }
+    /// Zero-fills the record up to `size` bytes.
+    pub fn pad_to(&mut self, size: usize) {
+        assert!(self.buffer.len() <= size, "record exceeds {} bytes", size);
+        self.buffer.resize(size, 0);
+    }
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buffer
+    }
+}
+
+pub struct Decoder<R: Read> {
+    reader: R,
+}
+
+impl<R: Read> Decoder<R> {
+    pub fn new(reader: R) -> Self {
+        Decoder { reader }
+    }
+    fn array<A: Default + AsMut<[u8]>>(&mut self) -> io::Result<A> {
+        let mut buffer = A::default();
+        self.reader.read_exact(buffer.as_mut())?;
+        Ok(buffer)
+    }
+    pub fn u8(&mut self) -> io::Result<u8> {
+        self.array::<[u8; 1]>().map(|it| it[0])
+    }
+    pub fn u16(&mut self) -> io::Result<u16> {
+        self.array().map(u16::from_le_bytes)
+    }
+    pub fn u32(&mut self) -> io::Result<u32> {
+        self.array().map(u32::from_le_bytes)
+    }
+    pub fn u64(&mut self) -> io::Result<u64> {
+        self.array().map(u64::from_le_bytes)
+    }
+    pub fn i64(&mut self) -> io::Result<i64> {
+        self.array().map(i64::from_le_bytes)
+    }
+    pub fn bool(&mut self) -> io::Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => invalid_data("invalid boolean"),
+        }
+    }
+    pub fn bytes(&mut self, length: usize) -> io::Result<Vec<u8>> {
+        let mut buffer = vec![0u8; length];
+        self.reader.read_exact(&mut buffer)?;
+        Ok(buffer)
+    }
+    pub fn time(&mut self) -> io::Result<SystemTime> {
+        let seconds = self.i64()?;
+        let nanoseconds = self.u32()?;
+        if nanoseconds >= 1_000_000_000 {
+            return invalid_data("invalid timestamp");
+        }
+        let since_epoch = Duration::from_secs(if seconds >= 0 {
+            seconds as u64
+        } else {
+            seconds.wrapping_neg() as u64
+        });
+        let time = if seconds >= 0 {
+            UNIX_EPOCH.checked_add(since_epoch)
+        } else {
+            UNIX_EPOCH.checked_sub(since_epoch)
+        };
+        time.and_then(|it| it.checked_add(Duration::from_nanos(nanoseconds.into())))
+            .map_or_else(|| invalid_data("timestamp out of range"), Ok)
+    }
+}
+
+#[test]
+fn test_encode_decode() -> io::Result<()> {
+    let before_epoch = UNIX_EPOCH - Duration::new(10, 250);
+    let after_epoch = UNIX_EPOCH + Duration::new(1_584_000_000, 999_999_999);
+    let mut encoder = Encoder::default();
+    encoder.u8(7);
+    encoder.u16(0x0102);
+    encoder.u32(0x0304_0506);
+    encoder.u64(0x0708_090A_0B0C_0D0E);
+    encoder.i64(-2);
+    encoder.bool(true);
+    encoder.time(before_epoch);
+    encoder.time(after_epoch);
+    encoder.bytes(b"dumb");
+    encoder.pad_to(64);
+    let encoded = encoder.into_inner();
+    assert_eq!(encoded.len(), 64);
+    assert_eq!(&encoded[1..3], &[0x02, 0x01]);
+    assert_eq!(
+        &encoded[15..23],
+        &[0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]
+    );
+    let mut decoder = Decoder::new(&encoded[..]);
+    assert_eq!(decoder.u8()?, 7);
+    assert_eq!(decoder.u16()?, 0x0102);
+    assert_eq!(decoder.u32()?, 0x0304_0506);
+    assert_eq!(decoder.u64()?, 0x0708_090A_0B0C_0D0E);
+    assert_eq!(decoder.i64()?, -2);
+    assert!(decoder.bool()?);
+    assert_eq!(decoder.time()?, before_epoch);
+    assert_eq!(decoder.time()?, after_epoch);
+    assert_eq!(decoder.bytes(4)?, b"dumb");
+    assert!(decoder.bool().is_ok());
+    Ok(())
+}
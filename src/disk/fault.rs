@@ -0,0 +1,331 @@
+//! A backing store that misbehaves on purpose, to exercise the error paths above it.
+
+use crate::disk::cache::CacheStats;
+use crate::disk::device::BlockDevice;
+use crate::util::align;
+use libc::EIO;
+use std::cmp::min;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// The operations a fault can be injected into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Read,
+    Write,
+    Flush,
+}
+
+/// What goes wrong. Only `Error` and `Delay` apply to flushes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Fails with `EIO` without touching the store.
+    Error,
+    /// Transfers only the first half of the buffer.
+    Short,
+    /// Writes a prefix that ends inside a sector, then fails with `EIO` like a write cut off by a
+    /// power loss.
+    Torn,
+    /// Flips one bit of the data read or written.
+    BitFlip,
+    /// Completes normally after sleeping.
+    Delay(Duration),
+}
+
+/// When a fault is injected: into every matching operation by default.
+#[derive(Debug, Clone)]
+pub struct FaultRule {
+    op: Op,
+    fault: Fault,
+    range: Option<(u64, u64)>,
+    skip: u64,
+    one_in: u64,
+    remaining: Option<u64>,
+}
+
+impl FaultRule {
+    pub fn new(op: Op, fault: Fault) -> Self {
+        FaultRule {
+            op,
+            fault,
+            range: None,
+            skip: 0,
+            one_in: 1,
+            remaining: None,
+        }
+    }
+    /// Only operations overlapping `length` bytes at `offset` match.
+    pub fn within(mut self, offset: u64, length: u64) -> Self {
+        self.range = Some((offset, offset + length));
+        self
+    }
+    /// Lets the first `count` matching operations through.
+    pub fn after(mut self, count: u64) -> Self {
+        self.skip = count;
+        self
+    }
+    /// Injects into a matching operation with a chance of 1 in `n`.
+    pub fn one_in(mut self, n: u64) -> Self {
+        self.one_in = n;
+        self
+    }
+    /// Stops after injecting `count` faults.
+    pub fn times(mut self, count: u64) -> Self {
+        self.remaining = Some(count);
+        self
+    }
+    fn matches(&self, op: Op, offset: u64, length: u64) -> bool {
+        let overlaps = match self.range {
+            Some((start, end)) => offset < end && start < offset + length,
+            None => true,
+        };
+        self.op == op && overlaps && self.remaining != Some(0)
+    }
+}
+
+struct ScheduleState {
+    random: u64,
+    rules: Vec<FaultRule>,
+    injected: u64,
+}
+
+impl ScheduleState {
+    /// xorshift64*, good enough to pick faults and reproducible from the seed.
+    fn next_random(&mut self) -> u64 {
+        self.random ^= self.random >> 12;
+        self.random ^= self.random << 25;
+        self.random ^= self.random >> 27;
+        self.random.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+    fn pick(&mut self, op: Op, offset: u64, length: u64) -> Option<Fault> {
+        for i in 0..self.rules.len() {
+            if !self.rules[i].matches(op, offset, length) {
+                continue;
+            }
+            if self.rules[i].skip > 0 {
+                self.rules[i].skip -= 1;
+                continue;
+            }
+            let one_in = self.rules[i].one_in;
+            if one_in > 1 {
+                let roll = self.next_random() % one_in;
+                if roll != 0 {
+                    continue;
+                }
+            }
+            let rule = &mut self.rules[i];
+            if let Some(remaining) = rule.remaining.as_mut() {
+                *remaining -= 1;
+            }
+            self.injected += 1;
+            return Some(rule.fault);
+        }
+        None
+    }
+}
+
+/// The faults a `Faulty` store injects. Clones share the schedule, so a test keeps one to arm
+/// and inspect it while the store sits under a `Disk`.
+#[derive(Clone)]
+pub struct FaultSchedule(Arc<Mutex<ScheduleState>>);
+
+impl FaultSchedule {
+    /// An empty schedule, `seed` fixes every random choice made later.
+    pub fn new(seed: u64) -> Self {
+        FaultSchedule(Arc::new(Mutex::new(ScheduleState {
+            // xorshift gets stuck at zero
+            random: seed | 1,
+            rules: vec![],
+            injected: 0,
+        })))
+    }
+    /// Adds a rule, rules added earlier take precedence.
+    pub fn inject(&self, rule: FaultRule) {
+        self.0.lock().unwrap().rules.push(rule);
+    }
+    /// Removes every rule, the store behaves from now on.
+    pub fn clear(&self) {
+        self.0.lock().unwrap().rules.clear();
+    }
+    /// Number of faults injected so far.
+    pub fn injected(&self) -> u64 {
+        self.0.lock().unwrap().injected
+    }
+    fn pick(&self, op: Op, offset: u64, length: u64) -> Option<Fault> {
+        self.0.lock().unwrap().pick(op, offset, length)
+    }
+    fn random(&self, below: u64) -> u64 {
+        self.0.lock().unwrap().next_random() % below
+    }
+}
+
+fn injected_error() -> io::Error {
+    io::Error::from_raw_os_error(EIO)
+}
+
+/// Wraps a store and injects faults into its I/O as `schedule` says.
+pub struct Faulty<D: BlockDevice> {
+    device: D,
+    schedule: FaultSchedule,
+}
+
+impl<D: BlockDevice> Faulty<D> {
+    pub fn new(device: D, schedule: FaultSchedule) -> Self {
+        Faulty { device, schedule }
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for Faulty<D> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let fault = self.schedule.pick(Op::Read, offset, buf.len() as u64);
+        match fault {
+            None => self.device.read_at(buf, offset),
+            Some(Fault::Error) | Some(Fault::Torn) => Err(injected_error()),
+            Some(Fault::Short) => {
+                let length = buf.len() - buf.len() / 2;
+                self.device.read_at(&mut buf[..length], offset)
+            }
+            Some(Fault::BitFlip) => {
+                let read = self.device.read_at(buf, offset)?;
+                if read != 0 {
+                    let bit = self.schedule.random(read as u64 * 8);
+                    buf[(bit / 8) as usize] ^= 1 << (bit % 8);
+                }
+                Ok(read)
+            }
+            Some(Fault::Delay(delay)) => {
+                thread::sleep(delay);
+                self.device.read_at(buf, offset)
+            }
+        }
+    }
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        let fault = self.schedule.pick(Op::Write, offset, buf.len() as u64);
+        match fault {
+            None => self.device.write_at(buf, offset),
+            Some(Fault::Error) => Err(injected_error()),
+            Some(Fault::Short) => {
+                let length = buf.len() - buf.len() / 2;
+                self.device.write_at(&buf[..length], offset)
+            }
+            Some(Fault::Torn) => {
+                if !buf.is_empty() {
+                    let sector = self.schedule.random(align(buf.len() as u64, 512) / 512) * 512;
+                    let end = min(sector + 1 + self.schedule.random(511), buf.len() as u64);
+                    self.device.write_at(&buf[..end as usize], offset)?;
+                }
+                Err(injected_error())
+            }
+            Some(Fault::BitFlip) => {
+                let mut flipped = buf.to_vec();
+                if !flipped.is_empty() {
+                    let bit = self.schedule.random(flipped.len() as u64 * 8);
+                    flipped[(bit / 8) as usize] ^= 1 << (bit % 8);
+                }
+                self.device.write_at(&flipped, offset)
+            }
+            Some(Fault::Delay(delay)) => {
+                thread::sleep(delay);
+                self.device.write_at(buf, offset)
+            }
+        }
+    }
+    fn flush(&self) -> io::Result<()> {
+        match self.schedule.pick(Op::Flush, 0, u64::MAX) {
+            None | Some(Fault::Short) | Some(Fault::BitFlip) => self.device.flush(),
+            Some(Fault::Error) | Some(Fault::Torn) => Err(injected_error()),
+            Some(Fault::Delay(delay)) => {
+                thread::sleep(delay);
+                self.device.flush()
+            }
+        }
+    }
+    fn discard(&self, offset: u64, length: u64) -> io::Result<()> {
+        self.device.discard(offset, length)
+    }
+    fn size(&self) -> io::Result<u64> {
+        self.device.size()
+    }
+    fn set_size(&self, size: u64) -> io::Result<()> {
+        self.device.set_size(size)
+    }
+    fn cache_stats(&self) -> Option<CacheStats> {
+        self.device.cache_stats()
+    }
+}
+
+#[test]
+fn test_fault() -> io::Result<()> {
+    use crate::disk::device::Memory;
+    use crate::disk::dump::DumpToFixedLocation;
+    use crate::disk::Disk;
+    use crate::file::{File, FileBuilder};
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    let schedule = FaultSchedule::new(42);
+    let disk = Disk::with_device(Faulty::new(Memory::default(), schedule.clone()));
+    let mut file = FileBuilder::new(&disk, 512)
+        .ino(2)
+        .filename("file")
+        .data(1024, 1024)
+        .build();
+    file.sync(&disk)?;
+
+    // short writes and reads are retried
+    schedule.inject(FaultRule::new(Op::Write, Fault::Short).times(3));
+    schedule.inject(FaultRule::new(Op::Read, Fault::Short).times(3));
+    file.write_all(b"hello world")?;
+    let mut content = String::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_string(&mut content)?;
+    assert_eq!(content, "hello world");
+    assert_eq!(schedule.injected(), 6);
+
+    // the second write to the data extent fails
+    schedule.inject(
+        FaultRule::new(Op::Write, Fault::Error)
+            .within(1024, 1024)
+            .after(1),
+    );
+    file.write_all(b"!")?;
+    let e = file.write_all(b"?").unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(EIO));
+    schedule.clear();
+
+    // a node load fails with the read under it, slow reads only take longer
+    schedule.inject(FaultRule::new(Op::Read, Fault::Error).times(1));
+    schedule.inject(FaultRule::new(Op::Read, Fault::Delay(Duration::from_millis(1))).times(1));
+    assert!(File::load(&disk, 512).is_err());
+    assert_eq!(File::load(&disk, 512)?.meta.file_attr.ino, 2);
+
+    // a torn write lands a prefix of the buffer and fails
+    schedule.inject(FaultRule::new(Op::Write, Fault::Torn).times(1));
+    assert!(file.write_data(0, &[b'x'; 1024]).is_err());
+    let mut content = vec![];
+    let mut raw = disk.clone();
+    raw.seek(SeekFrom::Start(1024))?;
+    raw.read_to_end(&mut content)?;
+    let torn = content.iter().take_while(|&&it| it == b'x').count();
+    assert!(torn > 0 && torn < 1024, "{} bytes landed", torn);
+
+    // the same seed flips the same bits
+    let flipped = |seed| -> io::Result<Vec<u8>> {
+        let schedule = FaultSchedule::new(seed);
+        schedule.inject(FaultRule::new(Op::Read, Fault::BitFlip).one_in(2));
+        let device = Faulty::new(Memory::default(), schedule);
+        device.write_at(&[0u8; 64], 0)?;
+        let mut buffer = vec![0u8; 64];
+        for i in 0..8 {
+            device.read_at(&mut buffer[i * 8..(i + 1) * 8], (i * 8) as u64)?;
+        }
+        Ok(buffer)
+    };
+    assert_eq!(flipped(7)?, flipped(7)?);
+    assert!(flipped(7)?.iter().any(|&it| it != 0));
+
+    schedule.inject(FaultRule::new(Op::Flush, Fault::Error));
+    assert!(disk.clone().flush().is_err());
+    Ok(())
+}
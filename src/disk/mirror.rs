@@ -0,0 +1,496 @@
+//! Keeps every block on two or more members and reads whichever copy is intact.
+//!
+//! Members stay plain images that can be mounted on their own. Each one comes with a checksum
+//! store holding the CRC-32 of every `MIRROR_BLOCK_SIZE` block as 4 little-endian bytes, `0` for a
+//! block never written through the mirror. A copy that cannot be read or does not match its
+//! checksum is rewritten from an intact one.
+//!
+//! The checksums follow a header with the generation of the member. It is raised on the members in
+//! sync before the first write of a mount and whenever a member drops out, so only the members at
+//! the newest generation are trusted on the next mount.
+
+use crate::disk::device::{read_all_at, write_all_at, BlockDevice};
+use crate::util::{align, crc32};
+use libc::EIO;
+use std::cmp::{max, min};
+use std::io;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+
+pub const MIRROR_BLOCK_SIZE: u64 = 4096;
+
+const SUMS_MAGIC: &[u8; 8] = b"DUMBSUMS";
+/// Magic and generation in front of the checksums.
+const SUMS_HEADER: u64 = 16;
+
+/// Checksum of a block, never `0` so that a stored `0` keeps meaning unknown.
+fn checksum(block: &[u8]) -> u32 {
+    max(crc32(block), 1)
+}
+
+/// Bytes of block `index` that lie within an image of `size` bytes.
+fn block_length(index: u64, size: u64) -> usize {
+    min(
+        MIRROR_BLOCK_SIZE,
+        size.saturating_sub(index * MIRROR_BLOCK_SIZE),
+    ) as usize
+}
+
+/// One copy of the image with its checksums.
+pub struct Member {
+    data: Box<dyn BlockDevice>,
+    sums: Box<dyn BlockDevice>,
+}
+
+impl Member {
+    pub fn new<D: BlockDevice + 'static, S: BlockDevice + 'static>(data: D, sums: S) -> Self {
+        Member {
+            data: Box::new(data),
+            sums: Box::new(sums),
+        }
+    }
+    /// Reads block `index`, zero past the end, and tells whether it matches its checksum.
+    fn read_block(&self, index: u64, block: &mut [u8]) -> io::Result<bool> {
+        let read = read_all_at(&*self.data, block, index * MIRROR_BLOCK_SIZE)?;
+        block[read..].iter_mut().for_each(|it| *it = 0);
+        let mut sum = [0u8; 4];
+        read_all_at(&*self.sums, &mut sum, SUMS_HEADER + index * 4)?;
+        let sum = u32::from_le_bytes(sum);
+        Ok(sum == 0 || sum == checksum(block))
+    }
+    /// Writes the first `length` bytes of block `index` and the checksum of the whole block.
+    fn write_block(&self, index: u64, block: &[u8], length: usize) -> io::Result<()> {
+        write_all_at(&*self.data, &block[..length], index * MIRROR_BLOCK_SIZE)?;
+        write_all_at(
+            &*self.sums,
+            &checksum(block).to_le_bytes(),
+            SUMS_HEADER + index * 4,
+        )
+    }
+    /// The generation stored with the checksums, `0` for a member that has none yet.
+    fn generation(&self) -> io::Result<u64> {
+        let mut header = [0u8; SUMS_HEADER as usize];
+        read_all_at(&*self.sums, &mut header, 0)?;
+        if &header[..8] != SUMS_MAGIC {
+            return Ok(0);
+        }
+        let mut generation = [0u8; 8];
+        generation.copy_from_slice(&header[8..]);
+        Ok(u64::from_le_bytes(generation))
+    }
+    /// Stores `generation` durably, before anything written under it.
+    fn set_generation(&self, generation: u64) -> io::Result<()> {
+        let mut header = [0u8; SUMS_HEADER as usize];
+        header[..8].copy_from_slice(SUMS_MAGIC);
+        header[8..].copy_from_slice(&generation.to_le_bytes());
+        write_all_at(&*self.sums, &header, 0)?;
+        self.sums.flush()
+    }
+}
+
+pub struct Mirror {
+    members: Vec<Member>,
+    /// Whether each member is in sync. Members that failed or wait for a resync get no I/O.
+    in_sync: Mutex<Vec<bool>>,
+    /// Generation of the members in sync, only changed under the lock above.
+    generation: AtomicU64,
+    /// Whether this mount raised the generation before writing yet.
+    written: AtomicBool,
+}
+
+impl Mirror {
+    /// Mirrors `members`. The ones behind the newest generation, or shorter than the longest
+    /// member at it, missed writes and are left out until they are resynced.
+    pub fn new(members: Vec<Member>) -> io::Result<Self> {
+        let generations: Vec<Option<u64>> = members
+            .iter()
+            .enumerate()
+            .map(|(i, it)| match it.generation() {
+                Ok(generation) => Some(generation),
+                Err(e) => {
+                    warn!("cannot read the generation of mirror member {}: {}", i, e);
+                    None
+                }
+            })
+            .collect();
+        let generation = generations.iter().flatten().copied().max().unwrap_or(0);
+        let sizes = members
+            .iter()
+            .zip(&generations)
+            .map(|(it, &member)| match member {
+                Some(member) if member == generation => it.data.size().map(Some),
+                _ => Ok(None),
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let size = sizes.iter().flatten().copied().max().unwrap_or(0);
+        let in_sync = sizes
+            .iter()
+            .enumerate()
+            .map(|(i, &it)| {
+                if it != Some(size) {
+                    warn!("mirror member {} is out of date and needs a resync", i);
+                }
+                it == Some(size)
+            })
+            .collect();
+        Ok(Mirror {
+            members,
+            in_sync: Mutex::new(in_sync),
+            generation: AtomicU64::new(generation),
+            written: AtomicBool::new(false),
+        })
+    }
+    /// Whether each member takes part in reads and writes.
+    pub fn in_sync(&self) -> Vec<bool> {
+        self.in_sync.lock().unwrap().clone()
+    }
+    /// Rebuilds `member` from the members in sync, after it was replaced or missed writes.
+    pub fn resync(&self, member: usize) -> io::Result<()> {
+        let mut in_sync = self.in_sync.lock().unwrap();
+        if member >= self.members.len() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("the mirror has no member {}", member),
+            ));
+        }
+        in_sync[member] = false;
+        let size = self.size_of(&in_sync)?;
+        let blocks = align(size, MIRROR_BLOCK_SIZE) / MIRROR_BLOCK_SIZE;
+        let target = &self.members[member];
+        target.data.set_size(size)?;
+        target.sums.set_size(0)?;
+        target.sums.set_size(SUMS_HEADER + blocks * 4)?;
+        let mut block = vec![0u8; MIRROR_BLOCK_SIZE as usize];
+        for index in 0..blocks {
+            self.load(&mut in_sync, index, size, &mut block)?;
+            target.write_block(index, &block, block_length(index, size))?;
+        }
+        target.data.flush()?;
+        target.set_generation(self.generation.load(Ordering::SeqCst))?;
+        in_sync[member] = true;
+        info!("mirror member {} is in sync", member);
+        Ok(())
+    }
+    fn size_of(&self, in_sync: &[bool]) -> io::Result<u64> {
+        match in_sync.iter().position(|&it| it) {
+            Some(i) => self.members[i].data.size(),
+            None => Err(io::Error::from_raw_os_error(EIO)),
+        }
+    }
+    fn fail(&self, in_sync: &mut [bool], member: usize, e: &io::Error) {
+        warn!("mirror member {} failed and needs a resync: {}", member, e);
+        in_sync[member] = false;
+        // the others move on, so the failed member stays out on the next mount; before the first
+        // write that happens anyway, and a read-only mount must not write
+        if self.written.load(Ordering::SeqCst) {
+            self.raise_generation(in_sync);
+        }
+    }
+    fn raise_generation(&self, in_sync: &mut [bool]) {
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        let mut failed = vec![];
+        for (i, member) in self.members.iter().enumerate() {
+            if in_sync[i] {
+                if let Err(e) = member.set_generation(generation) {
+                    failed.push((i, e));
+                }
+            }
+        }
+        for (i, e) in failed {
+            if in_sync[i] {
+                self.fail(in_sync, i, &e);
+            }
+        }
+    }
+    /// Raises the generation before the first write of the mount, a member left out meanwhile
+    /// is then told apart by its older one.
+    fn start_writing(&self, in_sync: &mut [bool]) -> io::Result<()> {
+        if !self.written.swap(true, Ordering::SeqCst) {
+            self.raise_generation(in_sync);
+        }
+        self.size_of(in_sync).map(|_| ())
+    }
+    /// Runs `f` on every member in sync and drops the ones it fails on, fails if none is left.
+    fn each<F: Fn(&Member) -> io::Result<()>>(&self, in_sync: &mut [bool], f: F) -> io::Result<()> {
+        let mut error = None;
+        for (i, member) in self.members.iter().enumerate() {
+            if in_sync[i] {
+                if let Err(e) = f(member) {
+                    self.fail(in_sync, i, &e);
+                    error = Some(e);
+                }
+            }
+        }
+        match error {
+            Some(e) if !in_sync.contains(&true) => Err(e),
+            _ if !in_sync.contains(&true) => Err(io::Error::from_raw_os_error(EIO)),
+            _ => Ok(()),
+        }
+    }
+    /// Reads block `index` from the first intact copy and repairs the damaged copies before it.
+    fn load(
+        &self,
+        in_sync: &mut [bool],
+        index: u64,
+        size: u64,
+        block: &mut [u8],
+    ) -> io::Result<()> {
+        let mut damaged: Vec<usize> = vec![];
+        let mut error = None;
+        for (i, member) in self.members.iter().enumerate() {
+            if !in_sync[i] {
+                continue;
+            }
+            match member.read_block(index, block) {
+                Ok(true) => {
+                    for j in damaged {
+                        warn!("repair block {} of mirror member {}", index, j);
+                        let length = block_length(index, size);
+                        if let Err(e) = self.members[j].write_block(index, block, length) {
+                            self.fail(in_sync, j, &e);
+                        }
+                    }
+                    return Ok(());
+                }
+                Ok(false) => {
+                    warn!("block {} of mirror member {} is corrupted", index, i);
+                    damaged.push(i);
+                }
+                Err(e) => {
+                    warn!("cannot read block {} of mirror member {}: {}", index, i, e);
+                    damaged.push(i);
+                    error = Some(e);
+                }
+            }
+        }
+        Err(error.unwrap_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("no mirror member holds an intact copy of block {}", index),
+            )
+        }))
+    }
+    fn store(&self, in_sync: &mut [bool], buf: &[u8], offset: u64) -> io::Result<()> {
+        self.start_writing(in_sync)?;
+        let size = self.size_of(in_sync)?;
+        let end = offset + buf.len() as u64;
+        let new_size = max(size, end);
+        let mut block = vec![0u8; MIRROR_BLOCK_SIZE as usize];
+        let mut index = offset / MIRROR_BLOCK_SIZE;
+        while index * MIRROR_BLOCK_SIZE < end {
+            let start = index * MIRROR_BLOCK_SIZE;
+            let from = max(start, offset);
+            let to = min(start + MIRROR_BLOCK_SIZE, end);
+            // only a block keeping some of its current bytes is read first
+            if from > start || to < min(start + MIRROR_BLOCK_SIZE, size) {
+                self.load(in_sync, index, size, &mut block)?;
+            } else {
+                block.iter_mut().for_each(|it| *it = 0);
+            }
+            block[(from - start) as usize..(to - start) as usize]
+                .copy_from_slice(&buf[(from - offset) as usize..(to - offset) as usize]);
+            let length = block_length(index, new_size);
+            self.each(in_sync, |member| member.write_block(index, &block, length))?;
+            index += 1;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for Mirror {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let mut in_sync = self.in_sync.lock().unwrap();
+        let size = self.size_of(&in_sync)?;
+        if offset >= size {
+            return Ok(0);
+        }
+        let end = min(size, offset + buf.len() as u64);
+        let mut block = vec![0u8; MIRROR_BLOCK_SIZE as usize];
+        let mut index = offset / MIRROR_BLOCK_SIZE;
+        while index * MIRROR_BLOCK_SIZE < end {
+            let start = index * MIRROR_BLOCK_SIZE;
+            let from = max(start, offset);
+            let to = min(start + MIRROR_BLOCK_SIZE, end);
+            self.load(&mut in_sync, index, size, &mut block)?;
+            buf[(from - offset) as usize..(to - offset) as usize]
+                .copy_from_slice(&block[(from - start) as usize..(to - start) as usize]);
+            index += 1;
+        }
+        Ok((end - offset) as usize)
+    }
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        let mut in_sync = self.in_sync.lock().unwrap();
+        self.store(&mut in_sync, buf, offset)?;
+        Ok(buf.len())
+    }
+    fn flush(&self) -> io::Result<()> {
+        let mut in_sync = self.in_sync.lock().unwrap();
+        self.each(&mut in_sync, |member| {
+            member.data.flush()?;
+            member.sums.flush()
+        })
+    }
+    fn discard(&self, offset: u64, length: u64) -> io::Result<()> {
+        let mut in_sync = self.in_sync.lock().unwrap();
+        let size = self.size_of(&in_sync)?;
+        let end = min(offset.saturating_add(length), size);
+        if offset >= end {
+            return Ok(());
+        }
+        let first = align(offset, MIRROR_BLOCK_SIZE) / MIRROR_BLOCK_SIZE;
+        let last = end / MIRROR_BLOCK_SIZE;
+        if first >= last {
+            return self.store(&mut in_sync, &vec![0u8; (end - offset) as usize], offset);
+        }
+        // the edges are zeroed, the whole blocks between them discarded with their checksums
+        // forgotten, so a member that cannot discard still reads back
+        let head = first * MIRROR_BLOCK_SIZE - offset;
+        self.store(&mut in_sync, &vec![0u8; head as usize], offset)?;
+        let tail = end - last * MIRROR_BLOCK_SIZE;
+        self.store(
+            &mut in_sync,
+            &vec![0u8; tail as usize],
+            last * MIRROR_BLOCK_SIZE,
+        )?;
+        let sums = vec![0u8; ((last - first) * 4) as usize];
+        self.each(&mut in_sync, |member| {
+            write_all_at(&*member.sums, &sums, SUMS_HEADER + first * 4)
+        })?;
+        let mut result = Ok(());
+        for (i, member) in self.members.iter().enumerate() {
+            if in_sync[i] {
+                let discarded = member.data.discard(
+                    first * MIRROR_BLOCK_SIZE,
+                    (last - first) * MIRROR_BLOCK_SIZE,
+                );
+                result = result.and(discarded);
+            }
+        }
+        result
+    }
+    fn size(&self) -> io::Result<u64> {
+        self.size_of(&self.in_sync.lock().unwrap())
+    }
+    fn set_size(&self, size: u64) -> io::Result<()> {
+        let mut in_sync = self.in_sync.lock().unwrap();
+        self.start_writing(&mut in_sync)?;
+        let old_size = self.size_of(&in_sync)?;
+        // cutting into a block changes its checksum
+        let index = size / MIRROR_BLOCK_SIZE;
+        let tail = (size % MIRROR_BLOCK_SIZE) as usize;
+        let mut block = vec![0u8; MIRROR_BLOCK_SIZE as usize];
+        let cut = size < old_size && tail != 0;
+        if cut {
+            self.load(&mut in_sync, index, old_size, &mut block)?;
+            block[tail..].iter_mut().for_each(|it| *it = 0);
+        }
+        let blocks = align(size, MIRROR_BLOCK_SIZE) / MIRROR_BLOCK_SIZE;
+        self.each(&mut in_sync, |member| {
+            member.data.set_size(size)?;
+            member.sums.set_size(SUMS_HEADER + blocks * 4)?;
+            if cut {
+                member.write_block(index, &block, tail)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+#[test]
+fn test_mirror() -> io::Result<()> {
+    use crate::disk::device::Memory;
+    use crate::disk::fault::{Fault, FaultRule, FaultSchedule, Faulty, Op};
+    use std::sync::Arc;
+
+    let a = Arc::new(Memory::default());
+    let b = Arc::new(Memory::default());
+    let sums_a = Arc::new(Memory::default());
+    let mirror = Mirror::new(vec![
+        Member::new(a.clone(), sums_a.clone()),
+        Member::new(b.clone(), Memory::default()),
+    ])?;
+    let content = (0..10000).map(|it| it as u8).collect::<Vec<_>>();
+    write_all_at(&mirror, &content, 0)?;
+    write_all_at(&mirror, b"hello world", 4090)?;
+    let mut expected = content.clone();
+    expected[4090..4101].copy_from_slice(b"hello world");
+    let read_back = |mirror: &Mirror| -> io::Result<Vec<u8>> {
+        let mut buffer = vec![0u8; mirror.size()? as usize];
+        assert_eq!(read_all_at(mirror, &mut buffer, 0)?, buffer.len());
+        Ok(buffer)
+    };
+    assert_eq!(read_back(&mirror)?, expected);
+
+    // a corrupted copy is read from the other member and repaired
+    a.write_at(b"X", 4095)?;
+    assert_eq!(read_back(&mirror)?, expected);
+    let mut byte = [0u8];
+    a.read_at(&mut byte, 4095)?;
+    assert_eq!(byte[0], expected[4095]);
+
+    // with both copies corrupted the block is lost
+    a.write_at(b"X", 8192)?;
+    b.write_at(b"X", 8192)?;
+    let e = read_back(&mirror).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidData);
+    write_all_at(&mirror, &expected[8192..], 8192)?;
+    assert_eq!(read_back(&mirror)?, expected);
+
+    // cutting into a block keeps it verifiable, discarded blocks read back as zeroes
+    mirror.set_size(9000)?;
+    expected.truncate(9000);
+    mirror.discard(100, 8192)?;
+    expected[100..8292].iter_mut().for_each(|it| *it = 0);
+    assert_eq!(read_back(&mirror)?, expected);
+    mirror.flush()?;
+
+    // a replaced member is left out until it is resynced
+    let c = Arc::new(Memory::default());
+    let mirror = Mirror::new(vec![
+        Member::new(a.clone(), sums_a.clone()),
+        Member::new(c.clone(), Memory::default()),
+    ])?;
+    assert_eq!(mirror.in_sync(), vec![true, false]);
+    mirror.resync(1)?;
+    assert_eq!(mirror.in_sync(), vec![true, true]);
+    assert_eq!(
+        read_back(&Mirror::new(vec![Member::new(c, Memory::default())])?)?,
+        expected
+    );
+
+    // so is a blank one of the same size, its fresh checksums are of no older generation
+    let d = Memory::default();
+    d.set_size(a.size()?)?;
+    let mirror = Mirror::new(vec![
+        Member::new(a.clone(), sums_a.clone()),
+        Member::new(d, Memory::default()),
+    ])?;
+    assert_eq!(mirror.in_sync(), vec![true, false]);
+
+    // a member that fails a write drops out, the mirror carries on with the other
+    // and stays out on the next mount
+    let schedule = FaultSchedule::new(1);
+    schedule.inject(FaultRule::new(Op::Write, Fault::Error));
+    let faulty: Arc<dyn BlockDevice> = Arc::new(Faulty::new(Memory::default(), schedule.clone()));
+    let intact: Arc<dyn BlockDevice> = Arc::new(Memory::default());
+    let members = [
+        (faulty, Arc::new(Memory::default())),
+        (intact, Arc::new(Memory::default())),
+    ];
+    let mount = || {
+        Mirror::new(
+            members
+                .iter()
+                .map(|(data, sums)| Member::new(data.clone(), sums.clone()))
+                .collect(),
+        )
+    };
+    let mirror = mount()?;
+    write_all_at(&mirror, b"hello world", 0)?;
+    assert_eq!(mirror.in_sync(), vec![false, true]);
+    assert_eq!(read_back(&mirror)?, b"hello world");
+    schedule.clear();
+    assert_eq!(mount()?.in_sync(), vec![false, true]);
+    Ok(())
+}
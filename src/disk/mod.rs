@@ -1,67 +1,483 @@
+use crate::disk::cache::{BlockCache, CacheStats};
+use crate::disk::chunks::Chunks;
+use crate::disk::concat::{address, Concat, MAX_DEVICE_SIZE};
+use crate::disk::device::{BlockDevice, HostFile, Memory, RawDevice};
+use crate::disk::direct::Direct;
 use crate::disk::dump::DumpToFixedLocation;
-use bincode::{deserialize_from, serialize_into, Error};
-use serde::de::DeserializeOwned;
-use serde::Serialize;
-use std::cell::RefCell;
+use crate::disk::encode::{Decode, Decoder, Encode, Encoder};
+use crate::disk::mirror::{Member, Mirror};
+use crate::disk::nbd::{Nbd, NbdTarget};
+use crate::disk::parity::Parity;
+use std::ffi::OsStr;
 use std::fs::{File, OpenOptions};
 use std::io;
 use std::io::{Read, Seek, SeekFrom, Write};
-use std::ops::Deref;
-use std::path::Path;
-use std::rc::Rc;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+pub mod cache;
+pub mod chunks;
+pub mod concat;
+pub mod device;
+pub mod direct;
 pub mod dump;
+pub mod encode;
+#[cfg(test)]
+pub mod fault;
+pub mod mirror;
+pub mod nbd;
+pub mod parity;
+#[cfg(target_os = "linux")]
+pub mod uring;
 
+/// How `Disk::open` layers the backing store.
+#[derive(Debug, Clone, Default)]
+pub struct DiskOptions {
+    /// Capacity of the write-back block cache in bytes, `0` disables it.
+    pub cache_size: u64,
+    /// Read and write through io_uring where the kernel supports it.
+    pub uring: bool,
+    /// Bypass the host page cache with `O_DIRECT` where the host filesystem supports it.
+    pub direct: bool,
+    /// Open the backing store without write access.
+    pub read_only: bool,
+    /// Further images holding a copy of every block, see `mirror`.
+    pub mirrors: Vec<PathBuf>,
+    /// Further images the data is striped over with parity, see `parity`.
+    pub stripes: Vec<PathBuf>,
+    /// Parity chunks per stripe, `1` or `2`, `0` picks one.
+    pub parity: usize,
+}
+
+/// A handle to a shared backing store. Every clone has its own cursor for `Read`, `Write` and `Seek`,
+/// clones can be sent to other threads.
 #[derive(Clone)]
-pub struct Disk(Rc<RefCell<File>>);
+pub struct Disk {
+    device: Arc<dyn BlockDevice>,
+    cursor: u64,
+    devices: Option<Arc<Devices>>,
+}
+
+/// The devices an image opened from a path spans, see `concat`.
+struct Devices {
+    concat: Arc<Concat>,
+    /// Lists the devices after the first one, see `devices_path`. NBD images have none.
+    list: Option<PathBuf>,
+    options: DiskOptions,
+}
 
 impl Disk {
-    pub fn new<P: AsRef<Path>>(path: P) -> Self {
-        Disk(Rc::new(RefCell::new(
-            OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(cfg!(test))
-                .open(path)
-                .unwrap(),
-        )))
-    }
-    pub fn dump_at<D: Serialize + DeserializeOwned>(&self, location: u64, value: &D) {
-        self.0.borrow_mut().seek(SeekFrom::Start(location)).unwrap();
-        serialize_into(self.0.deref().borrow().deref(), value).unwrap();
-    }
-    pub fn load_at<D: Serialize + DeserializeOwned>(&self, location: u64) -> Result<D, Error> {
-        self.0.borrow_mut().seek(SeekFrom::Start(location)).unwrap();
-        deserialize_from(self.0.deref().borrow().deref())
-    }
-    pub fn dump_fixed_location<D: Serialize + DeserializeOwned, T: DumpToFixedLocation<D>>(
+    /// Opens the image at `path`, which is either a regular file or a block device.
+    pub fn new<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::open(path, &DiskOptions::default())
+    }
+    /// Opens the image at `path` with the layers `options` asks for, spanning the devices listed
+    /// next to it.
+    pub fn open<P: AsRef<Path>>(path: P, options: &DiskOptions) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut paths = vec![path];
+        paths.extend(options.mirrors.iter().map(PathBuf::as_path));
+        paths.extend(options.stripes.iter().map(PathBuf::as_path));
+        let device: Box<dyn BlockDevice> = if paths.len() == 1 {
+            open_store(path, options)?
+        } else if options.stripes.is_empty() {
+            Box::new(open_mirror(&paths, options)?)
+        } else if options.mirrors.is_empty() {
+            Box::new(open_parity(&paths, options)?)
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "an image is either mirrored or striped",
+            ));
+        };
+        let concat = Arc::new(Concat::new(cached(device, options)?));
+        let disk = Disk {
+            device: concat.clone(),
+            cursor: 0,
+            devices: Some(Arc::new(Devices {
+                concat,
+                list: devices_path(path).ok(),
+                options: options.clone(),
+            })),
+        };
+        disk.refresh_devices()?;
+        Ok(disk)
+    }
+    /// An empty image in memory.
+    pub fn memory() -> Self {
+        Self::with_device(Memory::default())
+    }
+    pub fn with_device<D: BlockDevice + 'static>(device: D) -> Self {
+        Disk {
+            device: Arc::new(device),
+            cursor: 0,
+            devices: None,
+        }
+    }
+    /// Bytes the image holds over all of its devices.
+    pub fn len(&self) -> io::Result<u64> {
+        Ok(self.device_sizes()?.iter().sum())
+    }
+    /// Resizes the image to `size` bytes over all of its devices, only the last one changes.
+    pub fn set_len(&self, size: u64) -> io::Result<()> {
+        let sizes = self.device_sizes()?;
+        let last = sizes.len() - 1;
+        let others = sizes[..last].iter().sum::<u64>();
+        if size < others {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only the last device of a filesystem can be resized",
+            ));
+        }
+        self.device.set_size(address(last, size - others))
+    }
+    /// The size of every device the image spans, by ID.
+    pub fn device_sizes(&self) -> io::Result<Vec<u64>> {
+        match &self.devices {
+            Some(devices) => devices.concat.sizes(),
+            None => Ok(vec![self.device.size()?]),
+        }
+    }
+    /// Opens the devices listed since the image was opened.
+    pub fn refresh_devices(&self) -> io::Result<()> {
+        let devices = match &self.devices {
+            Some(devices) => devices,
+            None => return Ok(()),
+        };
+        let listed = match &devices.list {
+            Some(list) => listed_devices(list)?,
+            None => vec![],
+        };
+        for path in listed.iter().skip(devices.concat.count() - 1) {
+            let device = cached(open_store(path, &devices.options)?, &devices.options)?;
+            let id = devices.concat.push(device);
+            info!("device {} is {:?}", id, path);
+        }
+        Ok(())
+    }
+    /// Counters of the block cache, if the image is opened with one.
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.device.cache_stats()
+    }
+    /// Tells the backing store that `length` bytes at `offset` are no longer used.
+    /// Holes are punched into regular files and ranges discarded on block devices,
+    /// either way the range reads back as zeroes afterwards. Stores that cannot discard fail
+    /// with `EOPNOTSUPP`.
+    pub fn discard(&self, offset: u64, length: u64) -> io::Result<()> {
+        if length == 0 {
+            return Ok(());
+        }
+        self.device.discard(offset, length)
+    }
+    pub fn dump_at<D: Encode>(&self, location: u64, value: &D) -> io::Result<()> {
+        let mut encoder = Encoder::default();
+        value.encode(&mut encoder);
+        let mut disk = self.clone();
+        disk.seek(SeekFrom::Start(location))?;
+        disk.write_all(&encoder.into_inner())
+    }
+    pub fn load_at<D: Decode>(&self, location: u64) -> io::Result<D> {
+        D::decode(&mut Decoder::new(BlockReader::new(self, location)))
+    }
+    pub fn dump_fixed_location<D: Encode + Decode, T: DumpToFixedLocation<D>>(
         &self,
         object: &T,
-    ) {
-        let location = object.location();
-        self.dump_at(location, &object.dump_part());
+    ) -> io::Result<()> {
+        self.dump_at(object.location(), &object.dump_part())
+    }
+}
+
+/// Reads `LOAD_BLOCK_SIZE` bytes at a time from a location on, so decoding a node
+/// reads that node and nothing past it.
+struct BlockReader<'a> {
+    device: &'a dyn BlockDevice,
+    position: u64,
+    block: Vec<u8>,
+    consumed: usize,
+}
+
+/// Unit of the reads behind `Disk::load_at`, the size of a node.
+const LOAD_BLOCK_SIZE: usize = 512;
+
+impl<'a> BlockReader<'a> {
+    fn new(disk: &'a Disk, position: u64) -> Self {
+        BlockReader {
+            device: &*disk.device,
+            position,
+            block: vec![],
+            consumed: 0,
+        }
+    }
+}
+
+impl Read for BlockReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.consumed == self.block.len() {
+            if buf.len() >= LOAD_BLOCK_SIZE {
+                let length = buf.len() / LOAD_BLOCK_SIZE * LOAD_BLOCK_SIZE;
+                let read = self.device.read_at(&mut buf[..length], self.position)?;
+                self.position += read as u64;
+                return Ok(read);
+            }
+            self.block.resize(LOAD_BLOCK_SIZE, 0);
+            let read = self.device.read_at(&mut self.block, self.position)?;
+            self.block.truncate(read);
+            self.position += read as u64;
+            self.consumed = 0;
+        }
+        let length = buf.len().min(self.block.len() - self.consumed);
+        buf[..length].copy_from_slice(&self.block[self.consumed..self.consumed + length]);
+        self.consumed += length;
+        Ok(length)
+    }
+}
+
+/// Puts a block cache over `device` if `options` asks for one.
+fn cached(device: Box<dyn BlockDevice>, options: &DiskOptions) -> io::Result<Box<dyn BlockDevice>> {
+    Ok(if options.cache_size == 0 {
+        device
+    } else {
+        Box::new(BlockCache::new(device, options.cache_size)?)
+    })
+}
+
+/// `path` with `suffix` appended, for files kept next to an image. NBD URIs have no place for them.
+fn sidecar_path(path: &Path, suffix: &str, what: &str) -> io::Result<PathBuf> {
+    if path.to_str().and_then(NbdTarget::parse).is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} is a network block device, {} cannot be kept next to it",
+                path.display(),
+                what
+            ),
+        ));
+    }
+    let mut sidecar = path.as_os_str().to_os_string();
+    sidecar.push(suffix);
+    Ok(PathBuf::from(sidecar))
+}
+
+/// Where the devices an image spans after its first one are listed, one path per line.
+pub fn devices_path(path: &Path) -> io::Result<PathBuf> {
+    sidecar_path(path, ".devices", "a device list")
+}
+
+fn listed_devices(list: &Path) -> io::Result<Vec<PathBuf>> {
+    match std::fs::read(list) {
+        Ok(content) => Ok(content
+            .split(|&it| it == b'\n')
+            .filter(|it| !it.is_empty())
+            .map(|it| PathBuf::from(OsStr::from_bytes(it)))
+            .collect()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e),
+    }
+}
+
+/// `path` without symlinks or relative parts, NBD URIs as they are.
+fn canonical(path: &Path) -> io::Result<PathBuf> {
+    match path.to_str().and_then(NbdTarget::parse) {
+        Some(_) => Ok(path.to_path_buf()),
+        None => path.canonicalize(),
+    }
+}
+
+/// Lists `device` as the next device of the image at `path`. It has to keep its size, a mounted
+/// filesystem starts using it once asked to grow, see `fs::resize::grow_on_signal`.
+pub fn add_device(path: &Path, device: &Path) -> io::Result<()> {
+    let list_path = devices_path(path)?;
+    let device = canonical(device)?;
+    let listed = listed_devices(&list_path)?;
+    if device == canonical(path)? || listed.contains(&device) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{:?} is already part of the filesystem", device),
+        ));
+    }
+    let read_only = DiskOptions {
+        read_only: true,
+        ..DiskOptions::default()
+    };
+    let size = open_store(&device, &read_only)?.size()?;
+    if size == 0 || size % 512 != 0 || size > MAX_DEVICE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{:?} holds {} bytes, a device needs a multiple of 512 up to {}",
+                device, size, MAX_DEVICE_SIZE
+            ),
+        ));
     }
+    let mut list = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(list_path)?;
+    list.write_all(device.as_os_str().as_bytes())?;
+    list.write_all(b"\n")?;
+    list.sync_all()
+}
+
+fn open_options(options: &DiskOptions) -> OpenOptions {
+    let mut open_options = OpenOptions::new();
+    open_options
+        .read(true)
+        .write(!options.read_only)
+        .create(cfg!(test) && !options.read_only);
+    open_options
+}
+
+/// The image at `path` with every layer below the cache.
+fn open_store(path: &Path, options: &DiskOptions) -> io::Result<Box<dyn BlockDevice>> {
+    if let Some(target) = path.to_str().and_then(NbdTarget::parse) {
+        return Ok(Box::new(Nbd::connect(target, options.read_only)?));
+    }
+    if path.is_dir() {
+        return Ok(Box::new(Chunks::open(path, options.read_only)?));
+    }
+    let open_options = open_options(options);
+    let file = if options.direct {
+        open_direct(&open_options, path)
+    } else {
+        None
+    };
+    let direct = file.is_some();
+    let file = match file {
+        Some(file) => file,
+        None => open_options.open(path)?,
+    };
+    let device = if file.metadata()?.file_type().is_block_device() {
+        layered(RawDevice(file), options)
+    } else {
+        layered(HostFile(file), options)
+    };
+    Ok(if direct {
+        Box::new(Direct::new(device))
+    } else {
+        device
+    })
+}
+
+/// Where the checksums of the mirror member at `path` are kept.
+pub fn sums_path(path: &Path) -> io::Result<PathBuf> {
+    sidecar_path(path, ".sum", "mirror checksums")
+}
+
+/// Mirrors the images at `paths`, each with its checksums at `sums_path`.
+pub fn open_mirror(paths: &[&Path], options: &DiskOptions) -> io::Result<Mirror> {
+    let mut members = vec![];
+    for &path in paths {
+        let sums = open_options(options)
+            .create(!options.read_only)
+            .open(sums_path(path)?)?;
+        members.push(Member::new(open_store(path, options)?, HostFile(sums)));
+    }
+    Mirror::new(members)
+}
+
+/// Stripes over the images at `paths` in this order, the ones that do not exist are missing.
+pub fn open_parity(paths: &[&Path], options: &DiskOptions) -> io::Result<Parity> {
+    let mut members = vec![];
+    for &path in paths {
+        match open_store(path, options) {
+            Ok(store) => members.push(Some(store)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => members.push(None),
+            Err(e) => return Err(e),
+        }
+    }
+    Parity::new(members, options.parity)
+}
+
+#[cfg(target_os = "linux")]
+fn open_direct(options: &OpenOptions, path: &Path) -> Option<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    match options.clone().custom_flags(libc::O_DIRECT).open(path) {
+        Ok(file) => Some(file),
+        Err(e) => {
+            warn!(
+                "cannot open {:?} with O_DIRECT, using the page cache: {}",
+                path, e
+            );
+            None
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_direct(_options: &OpenOptions, _path: &Path) -> Option<File> {
+    warn!("O_DIRECT is only available on Linux, using the page cache");
+    None
+}
+
+#[cfg(target_os = "linux")]
+fn layered<D: BlockDevice + AsRawFd + 'static>(
+    device: D,
+    options: &DiskOptions,
+) -> Box<dyn BlockDevice> {
+    use crate::disk::uring::{Ring, Uring, QUEUE_DEPTH};
+    if options.uring {
+        match Ring::new(QUEUE_DEPTH) {
+            Ok(ring) => return Box::new(Uring::new(device, ring)),
+            Err(e) => warn!("io_uring is unavailable, using synchronous I/O: {}", e),
+        }
+    }
+    Box::new(device)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn layered<D: BlockDevice + AsRawFd + 'static>(
+    device: D,
+    options: &DiskOptions,
+) -> Box<dyn BlockDevice> {
+    if options.uring {
+        warn!("io_uring is only available on Linux, using synchronous I/O");
+    }
+    Box::new(device)
 }
 
 impl Seek for Disk {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        self.0.borrow_mut().seek(pos)
+        let cursor = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => checked_offset(self.cursor, offset),
+            SeekFrom::End(offset) => checked_offset(self.device.size()?, offset),
+        };
+        self.cursor = cursor.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative offset")
+        })?;
+        Ok(self.cursor)
+    }
+}
+
+/// `base` moved by `offset`, `None` if that leaves the range of `u64`.
+pub fn checked_offset(base: u64, offset: i64) -> Option<u64> {
+    if offset >= 0 {
+        base.checked_add(offset as u64)
+    } else {
+        base.checked_sub(offset.wrapping_neg() as u64)
     }
 }
 
 impl Read for Disk {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.0.borrow_mut().read(buf)
+        let read = self.device.read_at(buf, self.cursor)?;
+        self.cursor += read as u64;
+        Ok(read)
     }
 }
 
 impl Write for Disk {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.0.borrow_mut().write(buf)
+        let written = self.device.write_at(buf, self.cursor)?;
+        self.cursor += written as u64;
+        Ok(written)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.0.borrow_mut().flush()
+        self.device.flush()
     }
 }
 
@@ -70,7 +486,7 @@ fn test_disk() -> io::Result<()> {
     use tempfile::tempdir;
     let tempdir = tempdir()?;
     let file_path = tempdir.path().join("temp.img");
-    let mut disk = Disk::new(&file_path);
+    let mut disk = Disk::new(&file_path)?;
     disk.write_all(b"hello world").unwrap();
     disk.seek(SeekFrom::Start(6)).unwrap();
     let mut result = [0u8; 5];
@@ -78,3 +494,71 @@ fn test_disk() -> io::Result<()> {
     assert_eq!(&result, b"world");
     Ok(())
 }
+
+#[test]
+fn test_backends() -> io::Result<()> {
+    use tempfile::tempdir;
+    let tempdir = tempdir()?;
+    let file_path = tempdir.path().join("temp.img");
+    let direct = DiskOptions {
+        cache_size: 0,
+        uring: true,
+        direct: true,
+        read_only: false,
+        mirrors: vec![],
+        stripes: vec![],
+        parity: 1,
+    };
+    let disks = [
+        Disk::new(&file_path)?,
+        Disk::memory(),
+        Disk::open(tempdir.path().join("direct.img"), &direct)?,
+    ];
+    for mut disk in disks.iter().cloned() {
+        disk.write_all(b"hello world").unwrap();
+        disk.seek(SeekFrom::Start(6)).unwrap();
+        let mut result = [0u8; 5];
+        disk.read_exact(&mut result).unwrap();
+        assert_eq!(&result, b"world");
+        assert_eq!(disk.len()?, 11);
+        let mut other = disk.clone();
+        other.seek(SeekFrom::End(-5))?;
+        assert_eq!(disk.read(&mut result)?, 0);
+        assert_eq!(other.read(&mut result)?, 5);
+        assert_eq!(other.read(&mut result)?, 0);
+        disk.set_len(4)?;
+        assert_eq!(disk.len()?, 4);
+    }
+
+    // an NBD export has nowhere to keep mirror checksums
+    let mirrored = DiskOptions {
+        mirrors: vec![file_path],
+        ..DiskOptions::default()
+    };
+    let opened = Disk::open("nbd://localhost/disk", &mirrored);
+    assert_eq!(opened.err().unwrap().kind(), io::ErrorKind::InvalidInput);
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_discard() -> io::Result<()> {
+    use tempfile::tempdir;
+    let tempdir = tempdir()?;
+    let file_path = tempdir.path().join("temp.img");
+    let mut disk = Disk::new(&file_path)?;
+    disk.write_all(&[0xffu8; 8192])?;
+    disk.discard(4096, 4096)?;
+    assert_eq!(disk.len()?, 8192);
+    let mut result = [0xaau8; 4096];
+    disk.seek(SeekFrom::Start(4096))?;
+    disk.read_exact(&mut result)?;
+    assert!(result.iter().all(|&it| it == 0));
+    disk.seek(SeekFrom::Start(4095))?;
+    disk.read_exact(&mut result[..1])?;
+    assert_eq!(result[0], 0xff);
+    // a regular file taken for a block device cannot discard, and says so
+    let raw = Disk::with_device(RawDevice(File::open(&file_path)?));
+    assert!(raw.discard(0, 4096).is_err());
+    Ok(())
+}
@@ -0,0 +1,695 @@
+//! A backing store exported by a network block device server, `nbdkit` or `qemu-nbd` for instance.
+//!
+//! The fixed newstyle handshake picks the export with `NBD_OPT_GO`, or with `NBD_OPT_EXPORT_NAME`
+//! on servers without it. Requests from every thread share one connection and are in flight at
+//! the same time, a reader thread hands each reply to the request with its handle. A connection
+//! that fails is replaced, and the requests that were on it are sent again.
+
+use crate::disk::device::BlockDevice;
+use libc::{ENOSPC, EOPNOTSUPP, EROFS};
+use std::cmp::min;
+use std::collections::HashMap;
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// `NBDMAGIC` in ASCII.
+const NBD_MAGIC: u64 = 0x4e42_444d_4147_4943;
+/// `IHAVEOPT` in ASCII.
+const IHAVEOPT: u64 = 0x4948_4156_454f_5054;
+const OPTION_REPLY_MAGIC: u64 = 0x0003_e889_0455_65a9;
+const REQUEST_MAGIC: u32 = 0x2560_9513;
+const SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+
+const FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const FLAG_NO_ZEROES: u16 = 1 << 1;
+
+const OPT_EXPORT_NAME: u32 = 1;
+const OPT_GO: u32 = 7;
+const REP_ACK: u32 = 1;
+const REP_INFO: u32 = 3;
+const REP_FLAG_ERROR: u32 = 1 << 31;
+const REP_ERR_UNSUP: u32 = REP_FLAG_ERROR | 1;
+const INFO_EXPORT: u16 = 0;
+
+const TRANSMISSION_READ_ONLY: u16 = 1 << 1;
+const TRANSMISSION_SEND_FLUSH: u16 = 1 << 2;
+const TRANSMISSION_SEND_TRIM: u16 = 1 << 5;
+const TRANSMISSION_SEND_WRITE_ZEROES: u16 = 1 << 6;
+
+const CMD_READ: u16 = 0;
+const CMD_WRITE: u16 = 1;
+const CMD_DISC: u16 = 2;
+const CMD_FLUSH: u16 = 3;
+const CMD_TRIM: u16 = 4;
+const CMD_WRITE_ZEROES: u16 = 6;
+
+pub const NBD_DEFAULT_PORT: u16 = 10809;
+/// Longest request sent, servers refuse longer ones.
+const MAX_REQUEST: u64 = 32 << 20;
+/// Connection attempts before a request fails, with a doubling pause between them.
+const CONNECT_ATTEMPTS: u32 = 5;
+const FIRST_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NbdAddress {
+    /// `host:port`
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+/// An export on a server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NbdTarget {
+    pub address: NbdAddress,
+    pub export: String,
+}
+
+impl NbdTarget {
+    /// Parses the URIs `nbd://<host>[:<port>]/[<export>]` and `nbd+unix:///[<export>]?socket=<path>`.
+    pub fn parse(uri: &str) -> Option<Self> {
+        let (scheme, rest) = cut(uri, "://")?;
+        let (authority, path) = cut(rest, "/").unwrap_or((rest, ""));
+        match scheme {
+            "nbd" if !authority.is_empty() => {
+                let address = if authority.ends_with(']') || !authority.contains(':') {
+                    format!("{}:{}", authority, NBD_DEFAULT_PORT)
+                } else {
+                    authority.to_string()
+                };
+                Some(NbdTarget {
+                    address: NbdAddress::Tcp(address),
+                    export: path.to_string(),
+                })
+            }
+            "nbd+unix" if authority.is_empty() => {
+                let (export, query) = cut(path, "?")?;
+                let socket = query.split('&').find_map(|it| match cut(it, "=") {
+                    Some(("socket", socket)) => Some(socket),
+                    _ => None,
+                })?;
+                Some(NbdTarget {
+                    address: NbdAddress::Unix(PathBuf::from(socket)),
+                    export: export.to_string(),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// `text` before and after the first `separator`.
+fn cut<'a>(text: &'a str, separator: &str) -> Option<(&'a str, &'a str)> {
+    let at = text.find(separator)?;
+    Some((&text[..at], &text[at + separator.len()..]))
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn connect(address: &NbdAddress) -> io::Result<Self> {
+        Ok(match address {
+            NbdAddress::Tcp(address) => {
+                let stream = TcpStream::connect(address)?;
+                stream.set_nodelay(true)?;
+                Stream::Tcp(stream)
+            }
+            NbdAddress::Unix(path) => Stream::Unix(UnixStream::connect(path)?),
+        })
+    }
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(match self {
+            Stream::Tcp(stream) => Stream::Tcp(stream.try_clone()?),
+            Stream::Unix(stream) => Stream::Unix(stream.try_clone()?),
+        })
+    }
+    fn shutdown(&self) {
+        let _ = match self {
+            Stream::Tcp(stream) => stream.shutdown(Shutdown::Both),
+            Stream::Unix(stream) => stream.shutdown(Shutdown::Both),
+        };
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> io::Result<u16> {
+    let mut bytes = [0u8; 2];
+    reader.read_exact(&mut bytes)?;
+    Ok(u16::from_be_bytes(bytes))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("NBD: {}", message))
+}
+
+fn send_option(stream: &mut Stream, option: u32, data: &[u8]) -> io::Result<()> {
+    let mut message = Vec::with_capacity(16 + data.len());
+    message.extend_from_slice(&IHAVEOPT.to_be_bytes());
+    message.extend_from_slice(&option.to_be_bytes());
+    message.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    message.extend_from_slice(data);
+    stream.write_all(&message)
+}
+
+/// Negotiates `export` and returns its size and transmission flags.
+fn handshake(stream: &mut Stream, export: &str) -> io::Result<(u64, u16)> {
+    if read_u64(stream)? != NBD_MAGIC {
+        return Err(protocol_error("not a server"));
+    }
+    if read_u64(stream)? != IHAVEOPT {
+        return Err(protocol_error(
+            "the server only knows the oldstyle handshake",
+        ));
+    }
+    let server_flags = read_u16(stream)?;
+    let flags = server_flags & (FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES);
+    stream.write_all(&u32::from(flags).to_be_bytes())?;
+    if flags & FLAG_FIXED_NEWSTYLE != 0 {
+        let mut data = (export.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(export.as_bytes());
+        data.extend_from_slice(&0u16.to_be_bytes());
+        send_option(stream, OPT_GO, &data)?;
+        let mut info = None;
+        loop {
+            if read_u64(stream)? != OPTION_REPLY_MAGIC {
+                return Err(protocol_error("bad option reply"));
+            }
+            let _option = read_u32(stream)?;
+            let reply = read_u32(stream)?;
+            let mut data = vec![0u8; read_u32(stream)? as usize];
+            stream.read_exact(&mut data)?;
+            match reply {
+                REP_INFO if data.len() >= 12 && data[..2] == INFO_EXPORT.to_be_bytes() => {
+                    let mut size = [0u8; 8];
+                    size.copy_from_slice(&data[2..10]);
+                    info = Some((
+                        u64::from_be_bytes(size),
+                        u16::from_be_bytes([data[10], data[11]]),
+                    ));
+                }
+                REP_ACK => return info.ok_or_else(|| protocol_error("export size missing")),
+                REP_ERR_UNSUP => break,
+                reply if reply & REP_FLAG_ERROR != 0 => {
+                    return Err(io::Error::new(
+                        ErrorKind::NotFound,
+                        format!(
+                            "NBD server refused export {:?}: {}",
+                            export,
+                            String::from_utf8_lossy(&data)
+                        ),
+                    ))
+                }
+                _ => {}
+            }
+        }
+    }
+    // without the fixed newstyle or `NBD_OPT_GO`, and no way to learn why an export is refused
+    send_option(stream, OPT_EXPORT_NAME, export.as_bytes())?;
+    let size = read_u64(stream)?;
+    let transmission_flags = read_u16(stream)?;
+    if flags & FLAG_NO_ZEROES == 0 {
+        stream.read_exact(&mut [0u8; 124])?;
+    }
+    Ok((size, transmission_flags))
+}
+
+/// A request waiting for its reply: the bytes it reads and where the reply goes.
+type Waiting = (usize, Sender<io::Result<Vec<u8>>>);
+
+struct Request<'a> {
+    command: u16,
+    offset: u64,
+    length: u32,
+    data: &'a [u8],
+}
+
+struct Connection {
+    writer: Mutex<Stream>,
+    waiting: Arc<Mutex<HashMap<u64, Waiting>>>,
+    /// Set when the connection failed, the requests on it got an error.
+    broken: Arc<AtomicBool>,
+    next_handle: AtomicU64,
+    size: u64,
+    flags: u16,
+}
+
+impl Connection {
+    fn open(target: &NbdTarget) -> io::Result<Self> {
+        let mut stream = Stream::connect(&target.address)?;
+        let (size, flags) = handshake(&mut stream, &target.export)?;
+        let connection = Connection {
+            writer: Mutex::new(stream.try_clone()?),
+            waiting: Arc::new(Mutex::new(HashMap::new())),
+            broken: Arc::new(AtomicBool::new(false)),
+            next_handle: AtomicU64::new(0),
+            size,
+            flags,
+        };
+        let waiting = connection.waiting.clone();
+        let broken = connection.broken.clone();
+        thread::Builder::new()
+            .name("dumbfs-nbd".to_string())
+            .spawn(move || {
+                let e = receive(&mut stream, &waiting).unwrap_err();
+                // under the lock, so no request is added after the others got their error
+                let mut waiting = waiting.lock().unwrap();
+                if !broken.swap(true, Ordering::SeqCst) {
+                    warn!("NBD connection lost: {}", e);
+                }
+                for (_, (_, sender)) in waiting.drain() {
+                    let _ = sender.send(Err(io::Error::new(e.kind(), e.to_string())));
+                }
+                stream.shutdown();
+            })?;
+        Ok(connection)
+    }
+    fn broken(&self) -> bool {
+        self.broken.load(Ordering::SeqCst)
+    }
+    fn submit(&self, request: &Request) -> io::Result<Receiver<io::Result<Vec<u8>>>> {
+        let handle = self.next_handle.fetch_add(1, Ordering::SeqCst);
+        let (sender, receiver) = channel();
+        {
+            let mut waiting = self.waiting.lock().unwrap();
+            if self.broken() {
+                return Err(io::Error::new(
+                    ErrorKind::ConnectionReset,
+                    "NBD connection lost",
+                ));
+            }
+            let length = if request.command == CMD_READ {
+                request.length as usize
+            } else {
+                0
+            };
+            waiting.insert(handle, (length, sender));
+        }
+        self.send(handle, request)?;
+        Ok(receiver)
+    }
+    /// Writes `request` to the server, failing the connection if it cannot be written.
+    fn send(&self, handle: u64, request: &Request) -> io::Result<()> {
+        let mut message = Vec::with_capacity(28 + request.data.len());
+        message.extend_from_slice(&REQUEST_MAGIC.to_be_bytes());
+        message.extend_from_slice(&0u16.to_be_bytes());
+        message.extend_from_slice(&request.command.to_be_bytes());
+        message.extend_from_slice(&handle.to_be_bytes());
+        message.extend_from_slice(&request.offset.to_be_bytes());
+        message.extend_from_slice(&request.length.to_be_bytes());
+        message.extend_from_slice(request.data);
+        let mut writer = self.writer.lock().unwrap();
+        if let Err(e) = writer.write_all(&message) {
+            // the reader then fails every request on the connection
+            if !self.broken.swap(true, Ordering::SeqCst) {
+                warn!("NBD connection lost: {}", e);
+            }
+            writer.shutdown();
+            return Err(e);
+        }
+        Ok(())
+    }
+    /// Sends every request before waiting for the first reply.
+    fn run(&self, requests: &[Request]) -> io::Result<Vec<Vec<u8>>> {
+        let receivers = requests
+            .iter()
+            .map(|it| self.submit(it))
+            .collect::<io::Result<Vec<_>>>()?;
+        receivers
+            .into_iter()
+            .map(|it| {
+                it.recv().unwrap_or_else(|_| {
+                    Err(io::Error::new(
+                        ErrorKind::ConnectionReset,
+                        "NBD connection lost",
+                    ))
+                })
+            })
+            .collect()
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let request = Request {
+            command: CMD_DISC,
+            offset: 0,
+            length: 0,
+            data: &[],
+        };
+        // the reader sees the connection close, but must not take that for a lost one
+        if !self.broken.swap(true, Ordering::SeqCst) {
+            let _ = self.send(0, &request);
+        }
+        self.writer.lock().unwrap().shutdown();
+    }
+}
+
+/// Hands out replies until the connection fails.
+fn receive(stream: &mut Stream, waiting: &Mutex<HashMap<u64, Waiting>>) -> io::Result<()> {
+    loop {
+        if read_u32(stream)? != SIMPLE_REPLY_MAGIC {
+            return Err(protocol_error("bad reply"));
+        }
+        let error = read_u32(stream)?;
+        let handle = read_u64(stream)?;
+        let (length, sender) = waiting
+            .lock()
+            .unwrap()
+            .remove(&handle)
+            .ok_or_else(|| protocol_error("reply to an unknown request"))?;
+        let result = if error == 0 {
+            let mut data = vec![0u8; length];
+            stream.read_exact(&mut data)?;
+            Ok(data)
+        } else {
+            Err(io::Error::from_raw_os_error(error as i32))
+        };
+        let _ = sender.send(result);
+    }
+}
+
+pub struct Nbd {
+    target: NbdTarget,
+    connection: Mutex<Arc<Connection>>,
+    size: u64,
+    flags: u16,
+    read_only: bool,
+}
+
+impl Nbd {
+    pub fn connect(target: NbdTarget, read_only: bool) -> io::Result<Self> {
+        let connection = Connection::open(&target)?;
+        info!(
+            "NBD export {:?} at {:?}: {} bytes",
+            target.export, target.address, connection.size
+        );
+        Ok(Nbd {
+            target,
+            size: connection.size,
+            flags: connection.flags,
+            read_only: read_only || connection.flags & TRANSMISSION_READ_ONLY != 0,
+            connection: Mutex::new(Arc::new(connection)),
+        })
+    }
+    /// The current connection, a new one if it failed.
+    fn connection(&self) -> io::Result<Arc<Connection>> {
+        let mut connection = self.connection.lock().unwrap();
+        let mut delay = FIRST_RETRY_DELAY;
+        let mut attempt = 1;
+        while connection.broken() {
+            match Connection::open(&self.target) {
+                Ok(new) if new.size != self.size => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        "the NBD export changed its size",
+                    ))
+                }
+                Ok(new) => {
+                    info!("NBD connection restored");
+                    *connection = Arc::new(new);
+                }
+                Err(e) if attempt == CONNECT_ATTEMPTS => return Err(e),
+                Err(e) => {
+                    warn!("cannot reconnect to the NBD server: {}", e);
+                    thread::sleep(delay);
+                    delay *= 2;
+                    attempt += 1;
+                }
+            }
+        }
+        Ok(connection.clone())
+    }
+    /// Runs `requests` at once, again on a new connection if the current one fails under them.
+    fn run(&self, requests: &[Request]) -> io::Result<Vec<Vec<u8>>> {
+        let mut attempt = 1;
+        loop {
+            let connection = self.connection()?;
+            match connection.run(requests) {
+                Err(_) if connection.broken() && attempt < CONNECT_ATTEMPTS => attempt += 1,
+                result => return result,
+            }
+        }
+    }
+    /// `length` bytes at `offset` in requests the server accepts, cut off at the end of the export.
+    fn split(&self, command: u16, offset: u64, length: u64) -> Vec<Request<'static>> {
+        let end = min(offset.saturating_add(length), self.size);
+        let mut requests = vec![];
+        let mut position = offset;
+        while position < end {
+            let length = min(MAX_REQUEST, end - position);
+            requests.push(Request {
+                command,
+                offset: position,
+                length: length as u32,
+                data: &[],
+            });
+            position += length;
+        }
+        requests
+    }
+    fn check_write(&self, offset: u64) -> io::Result<()> {
+        if self.read_only {
+            Err(io::Error::from_raw_os_error(EROFS))
+        } else if offset >= self.size {
+            Err(io::Error::from_raw_os_error(ENOSPC))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl BlockDevice for Nbd {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let requests = self.split(CMD_READ, offset, buf.len() as u64);
+        let mut read = 0;
+        for data in self.run(&requests)? {
+            buf[read..read + data.len()].copy_from_slice(&data);
+            read += data.len();
+        }
+        Ok(read)
+    }
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        self.write_batch(&[(offset, buf)])?;
+        Ok(min(buf.len() as u64, self.size - offset) as usize)
+    }
+    fn write_batch(&self, writes: &[(u64, &[u8])]) -> io::Result<()> {
+        let mut requests = vec![];
+        for &(offset, buf) in writes {
+            if buf.is_empty() {
+                continue;
+            }
+            self.check_write(offset)?;
+            for mut request in self.split(CMD_WRITE, offset, buf.len() as u64) {
+                let at = (request.offset - offset) as usize;
+                request.data = &buf[at..at + request.length as usize];
+                requests.push(request);
+            }
+        }
+        self.run(&requests)?;
+        Ok(())
+    }
+    fn flush(&self) -> io::Result<()> {
+        if self.flags & TRANSMISSION_SEND_FLUSH != 0 && !self.read_only {
+            let request = Request {
+                command: CMD_FLUSH,
+                offset: 0,
+                length: 0,
+                data: &[],
+            };
+            self.run(&[request])?;
+        }
+        Ok(())
+    }
+    /// Zeroes the range where the server can, so it reads back as zeroes, trims it otherwise.
+    fn discard(&self, offset: u64, length: u64) -> io::Result<()> {
+        let command = if self.flags & TRANSMISSION_SEND_WRITE_ZEROES != 0 {
+            CMD_WRITE_ZEROES
+        } else if self.flags & TRANSMISSION_SEND_TRIM != 0 {
+            CMD_TRIM
+        } else {
+            return Err(io::Error::from_raw_os_error(EOPNOTSUPP));
+        };
+        self.check_write(offset)?;
+        self.run(&self.split(command, offset, length))?;
+        Ok(())
+    }
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.size)
+    }
+    fn set_size(&self, size: u64) -> io::Result<()> {
+        if size == self.size {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "the size of an NBD export cannot be changed",
+            ))
+        }
+    }
+}
+
+#[test]
+fn test_nbd() -> io::Result<()> {
+    use crate::disk::device::{read_all_at, write_all_at};
+    use std::os::unix::net::UnixListener;
+    use tempfile::tempdir;
+
+    assert_eq!(
+        NbdTarget::parse("nbd://localhost/disk"),
+        Some(NbdTarget {
+            address: NbdAddress::Tcp("localhost:10809".to_string()),
+            export: "disk".to_string(),
+        })
+    );
+    assert_eq!(
+        NbdTarget::parse("nbd://[::1]:1234/").unwrap().address,
+        NbdAddress::Tcp("[::1]:1234".to_string())
+    );
+    assert_eq!(
+        NbdTarget::parse("nbd+unix:///disk?socket=/run/nbd.sock"),
+        Some(NbdTarget {
+            address: NbdAddress::Unix(PathBuf::from("/run/nbd.sock")),
+            export: "disk".to_string(),
+        })
+    );
+    assert_eq!(NbdTarget::parse("nbd+unix:///disk"), None);
+    assert_eq!(NbdTarget::parse("/var/lib/disk.img"), None);
+
+    /// Serves one connection of a fixed newstyle server on `image`, hanging up after `limit`
+    /// requests.
+    fn serve(mut stream: UnixStream, image: &Mutex<Vec<u8>>, limit: usize) -> io::Result<()> {
+        stream.write_all(&NBD_MAGIC.to_be_bytes())?;
+        stream.write_all(&IHAVEOPT.to_be_bytes())?;
+        stream.write_all(&(FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES).to_be_bytes())?;
+        read_u32(&mut stream)?;
+        assert_eq!(read_u64(&mut stream)?, IHAVEOPT);
+        assert_eq!(read_u32(&mut stream)?, OPT_GO);
+        let length = read_u32(&mut stream)? as usize;
+        stream.read_exact(&mut vec![0u8; length])?;
+        let size = image.lock().unwrap().len() as u64;
+        let flags = TRANSMISSION_SEND_FLUSH | TRANSMISSION_SEND_TRIM;
+        let mut info = INFO_EXPORT.to_be_bytes().to_vec();
+        info.extend_from_slice(&size.to_be_bytes());
+        info.extend_from_slice(&flags.to_be_bytes());
+        for &(reply, ref data) in &[(REP_INFO, info), (REP_ACK, vec![])] {
+            stream.write_all(&OPTION_REPLY_MAGIC.to_be_bytes())?;
+            stream.write_all(&OPT_GO.to_be_bytes())?;
+            stream.write_all(&reply.to_be_bytes())?;
+            stream.write_all(&(data.len() as u32).to_be_bytes())?;
+            stream.write_all(data)?;
+        }
+        for _ in 0..limit {
+            assert_eq!(read_u32(&mut stream)?, REQUEST_MAGIC);
+            read_u16(&mut stream)?;
+            let command = read_u16(&mut stream)?;
+            let handle = read_u64(&mut stream)?;
+            let offset = read_u64(&mut stream)? as usize;
+            let length = read_u32(&mut stream)? as usize;
+            let mut reply = SIMPLE_REPLY_MAGIC.to_be_bytes().to_vec();
+            reply.extend_from_slice(&0u32.to_be_bytes());
+            reply.extend_from_slice(&handle.to_be_bytes());
+            let mut image = image.lock().unwrap();
+            match command {
+                CMD_READ => reply.extend_from_slice(&image[offset..offset + length]),
+                CMD_WRITE => stream.read_exact(&mut image[offset..offset + length])?,
+                CMD_TRIM => image[offset..offset + length]
+                    .iter_mut()
+                    .for_each(|it| *it = 0),
+                CMD_DISC => return Ok(()),
+                _ => {}
+            }
+            stream.write_all(&reply)?;
+        }
+        Ok(())
+    }
+
+    let tempdir = tempdir()?;
+    let socket = tempdir.path().join("nbd.sock");
+    let listener = UnixListener::bind(&socket)?;
+    let image = Arc::new(Mutex::new(vec![0u8; 1 << 20]));
+    let server_image = image.clone();
+    let server = thread::spawn(move || {
+        // the first connection drops after a few requests
+        let limits = [4, usize::MAX];
+        for &limit in &limits {
+            let (stream, _) = listener.accept().unwrap();
+            let _ = serve(stream, &server_image, limit);
+        }
+    });
+
+    let uri = format!("nbd+unix:///disk?socket={}", socket.display());
+    let nbd = Nbd::connect(NbdTarget::parse(&uri).unwrap(), false)?;
+    assert_eq!(nbd.size()?, 1 << 20);
+    write_all_at(&nbd, b"hello world", 4096)?;
+    let mut buffer = [0u8; 11];
+    read_all_at(&nbd, &mut buffer, 4096)?;
+    assert_eq!(&buffer, b"hello world");
+
+    // the server hangs up in the middle of the batch, it is sent again on a new connection
+    let blocks = (0..8u8).map(|it| vec![it; 4096]).collect::<Vec<_>>();
+    let writes = blocks
+        .iter()
+        .enumerate()
+        .map(|(i, it)| ((i as u64 + 2) * 4096, it.as_slice()))
+        .collect::<Vec<_>>();
+    nbd.write_batch(&writes)?;
+    assert_eq!(image.lock().unwrap()[7 * 4096], 5);
+    nbd.discard(8192, 4096)?;
+    nbd.flush()?;
+    assert_eq!(read_all_at(&nbd, &mut buffer, 8192)?, 11);
+    assert_eq!(buffer, [0u8; 11]);
+    assert_eq!(read_all_at(&nbd, &mut buffer, (1 << 20) - 5)?, 5);
+    assert_eq!(
+        nbd.write_at(b"x", 1 << 20).unwrap_err().raw_os_error(),
+        Some(ENOSPC)
+    );
+    assert!(nbd.set_size(1 << 21).is_err());
+    drop(nbd);
+    server.join().unwrap();
+    Ok(())
+}
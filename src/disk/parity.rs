@@ -0,0 +1,848 @@
+//! Stripes data over several members with one or two parity chunks per stripe, like RAID 5 and 6.
+//!
+//! Stripe `s` takes `PARITY_CHUNK_SIZE` bytes at offset `s * PARITY_CHUNK_SIZE` of every member.
+//! Going round the members from member `s % N`, its data chunks come first, then P, the XOR of the
+//! data chunks, then with two parity chunks Q, their Reed-Solomon syndrome over GF(2^8). Parity is
+//! spread over all members that way, and any `parity` members may be missing or failed: their
+//! chunks are computed from the others.
+//!
+//! Every member starts with a header chunk holding the geometry of the array, the generation of
+//! the member and the stripes written since the last flush. The generation is raised on the healthy
+//! members before the first write of a mount and whenever a member drops out, so only members at
+//! the newest generation are trusted. The parity of stripes still listed after a crash is
+//! recomputed before the array is written again.
+
+use crate::disk::device::{read_all_at, write_all_at, BlockDevice};
+use crate::util::{align, random_uuid};
+use libc::EIO;
+use std::cmp::min;
+use std::collections::BTreeSet;
+use std::convert::TryInto;
+use std::io;
+use std::io::ErrorKind;
+use std::mem;
+use std::sync::Mutex;
+
+pub const PARITY_CHUNK_SIZE: u64 = 4096;
+
+const PARITY_MAGIC: &[u8; 8] = b"DUMBPRTY";
+/// Bytes in front of the stripes of every member, for its header.
+const HEADER_SIZE: u64 = PARITY_CHUNK_SIZE;
+/// Bytes of the header before the list of dirty stripes.
+const HEADER_FIXED: usize = 56;
+/// Dirty stripes a header lists, with more the whole array counts as dirty.
+const DIRTY_CAPACITY: usize = (HEADER_SIZE as usize - HEADER_FIXED) / 8;
+const FLAG_ALL_DIRTY: u32 = 1;
+
+/// Powers of the generator `2` of GF(2^8) modulo `x^8 + x^4 + x^3 + x^2 + 1`, twice over so that
+/// the sum of two logarithms can index it directly.
+static GF_EXP: [u8; 512] = gf_exp();
+static GF_LOG: [u8; 256] = gf_log();
+
+const fn gf_exp() -> [u8; 512] {
+    let mut exp = [0u8; 512];
+    let mut value = 1u16;
+    let mut i = 0;
+    while i < 512 {
+        exp[i] = value as u8;
+        value <<= 1;
+        if value & 0x100 != 0 {
+            value ^= 0x11d;
+        }
+        i += 1;
+    }
+    exp
+}
+
+const fn gf_log() -> [u8; 256] {
+    let exp = gf_exp();
+    let mut log = [0u8; 256];
+    let mut i = 0;
+    while i < 255 {
+        log[exp[i] as usize] = i as u8;
+        i += 1;
+    }
+    log
+}
+
+fn gf_mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        0
+    } else {
+        GF_EXP[GF_LOG[a as usize] as usize + GF_LOG[b as usize] as usize]
+    }
+}
+
+fn gf_inv(a: u8) -> u8 {
+    GF_EXP[255 - GF_LOG[a as usize] as usize]
+}
+
+/// The generator to the power of `n`.
+fn gf_pow(n: usize) -> u8 {
+    GF_EXP[n % 255]
+}
+
+/// What a member stores in front of its stripes.
+#[derive(Debug, Clone)]
+struct Header {
+    /// Tells the members of one array from those of another.
+    id: [u8; 16],
+    members: u32,
+    parity: u32,
+    /// Position of the member in the array.
+    index: u32,
+    generation: u64,
+    /// Stripes written since the last flush, their parity may not match after a crash.
+    dirty: BTreeSet<u64>,
+    /// More stripes than fit the list were written since the last flush.
+    all_dirty: bool,
+}
+
+impl Header {
+    fn encode(&self, index: usize) -> Vec<u8> {
+        let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+        header.extend_from_slice(PARITY_MAGIC);
+        header.extend_from_slice(&self.id);
+        header.extend_from_slice(&self.members.to_le_bytes());
+        header.extend_from_slice(&self.parity.to_le_bytes());
+        header.extend_from_slice(&(index as u32).to_le_bytes());
+        let flags = if self.all_dirty { FLAG_ALL_DIRTY } else { 0 };
+        header.extend_from_slice(&flags.to_le_bytes());
+        header.extend_from_slice(&self.generation.to_le_bytes());
+        header.extend_from_slice(&(self.dirty.len() as u32).to_le_bytes());
+        header.extend_from_slice(&[0u8; 4]);
+        for stripe in &self.dirty {
+            header.extend_from_slice(&stripe.to_le_bytes());
+        }
+        header.resize(HEADER_SIZE as usize, 0);
+        header
+    }
+    /// Reads the header of `device`, `None` for a member that has none yet.
+    fn load(device: &dyn BlockDevice) -> io::Result<Option<Self>> {
+        let mut header = vec![0u8; HEADER_SIZE as usize];
+        read_all_at(device, &mut header, 0)?;
+        if &header[..8] != PARITY_MAGIC {
+            return Ok(None);
+        }
+        let u32_at = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_le_bytes(header[at..at + 8].try_into().unwrap());
+        let count = min(u32_at(48) as usize, DIRTY_CAPACITY);
+        Ok(Some(Header {
+            id: header[8..24].try_into().unwrap(),
+            members: u32_at(24),
+            parity: u32_at(28),
+            index: u32_at(32),
+            all_dirty: u32_at(36) & FLAG_ALL_DIRTY != 0,
+            generation: u64_at(40),
+            dirty: (0..count).map(|i| u64_at(HEADER_FIXED + i * 8)).collect(),
+        }))
+    }
+}
+
+/// Where chunk `stripe` of a member starts.
+fn chunk_offset(stripe: u64) -> u64 {
+    HEADER_SIZE + stripe * PARITY_CHUNK_SIZE
+}
+
+struct ParityState {
+    members: Vec<Option<Box<dyn BlockDevice>>>,
+    /// Whether each member takes part in I/O. Members that are missing, failed or wait for a
+    /// rebuild do not.
+    healthy: Vec<bool>,
+    /// What the healthy members store in front of their stripes, but for their index.
+    header: Header,
+    /// Whether this mount raised the generation before writing yet.
+    written: bool,
+}
+
+impl ParityState {
+    fn fail(&mut self, member: usize, e: &io::Error) {
+        warn!("parity member {} failed and needs a rebuild: {}", member, e);
+        self.healthy[member] = false;
+        // the others move on, so the failed member stays out on the next mount; before the first
+        // write that happens anyway
+        if self.written {
+            self.header.generation += 1;
+            self.write_headers();
+        }
+    }
+    /// Stores the header on every healthy member and makes it durable.
+    fn write_headers(&mut self) {
+        let mut failed = vec![];
+        for member in 0..self.members.len() {
+            if self.healthy[member] {
+                let device = self.members[member].as_ref().unwrap();
+                let written = write_all_at(&**device, &self.header.encode(member), 0)
+                    .and_then(|_| device.flush());
+                if let Err(e) = written {
+                    failed.push((member, e));
+                }
+            }
+        }
+        for (member, e) in failed {
+            if self.healthy[member] {
+                self.fail(member, &e);
+            }
+        }
+    }
+    fn read_chunk(&mut self, member: usize, stripe: u64, chunk: &mut [u8]) -> bool {
+        if !self.healthy[member] {
+            return false;
+        }
+        let device = self.members[member].as_ref().unwrap();
+        match read_all_at(&**device, chunk, chunk_offset(stripe)) {
+            Ok(read) => {
+                chunk[read..].iter_mut().for_each(|it| *it = 0);
+                true
+            }
+            Err(e) => {
+                self.fail(member, &e);
+                false
+            }
+        }
+    }
+    fn write_chunk(&mut self, member: usize, stripe: u64, chunk: &[u8]) {
+        if !self.healthy[member] {
+            return;
+        }
+        let device = self.members[member].as_ref().unwrap();
+        if let Err(e) = write_all_at(&**device, chunk, chunk_offset(stripe)) {
+            self.fail(member, &e);
+        }
+    }
+}
+
+pub struct Parity {
+    parity: usize,
+    state: Mutex<ParityState>,
+}
+
+impl Parity {
+    /// Stripes over `members` with `parity` parity chunks per stripe, `0` for what the members
+    /// store, `None` for a missing member. Fails when the members disagree on the geometry. Members
+    /// behind the newest generation, or shorter than the longest at it, missed writes and are left
+    /// out until they are rebuilt.
+    pub fn new(members: Vec<Option<Box<dyn BlockDevice>>>, parity: usize) -> io::Result<Self> {
+        let mut headers = vec![];
+        let generations = members
+            .iter()
+            .enumerate()
+            .map(|(i, it)| {
+                let device = it.as_ref()?;
+                match Header::load(&**device) {
+                    Ok(Some(header)) => {
+                        let generation = header.generation;
+                        headers.push((i, header));
+                        Some(generation)
+                    }
+                    Ok(None) => Some(0),
+                    Err(e) => {
+                        warn!("cannot read the header of parity member {}: {}", i, e);
+                        None
+                    }
+                }
+            })
+            .collect::<Vec<_>>();
+        let newest = headers.iter().max_by_key(|(_, it)| it.generation).cloned();
+        if let Some((_, newest)) = &newest {
+            for (i, header) in &headers {
+                if header.id != newest.id
+                    || header.members as usize != members.len()
+                    || header.parity != newest.parity
+                    || header.index as usize != *i
+                {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "parity member {} was member {} of {} with {} parity chunks of \
+                             another array or order",
+                            i, header.index, header.members, header.parity
+                        ),
+                    ));
+                }
+            }
+            if parity != 0 && parity != newest.parity as usize {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "the array has {} parity chunks per stripe, not {}",
+                        newest.parity, parity
+                    ),
+                ));
+            }
+        }
+        let parity = match &newest {
+            Some((_, newest)) => newest.parity as usize,
+            None if parity == 0 => 1,
+            None => parity,
+        };
+        if !(1..=2).contains(&parity) || members.len() < parity + 2 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "{} parity chunks need at least {} members",
+                    parity,
+                    parity + 2
+                ),
+            ));
+        }
+        let generation = generations.iter().flatten().copied().max().unwrap_or(0);
+        let sizes = members
+            .iter()
+            .zip(&generations)
+            .map(|(it, &member)| match member {
+                Some(member) if member == generation => it.as_ref().unwrap().size().map(Some),
+                _ => Ok(None),
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let size = sizes.iter().filter_map(|&it| it).max().unwrap_or(0);
+        let healthy = sizes
+            .iter()
+            .enumerate()
+            .map(|(i, &it)| {
+                match it {
+                    _ if members[i].is_none() => warn!("parity member {} is missing", i),
+                    Some(it) if it == size => {}
+                    _ => warn!("parity member {} is out of date and needs a rebuild", i),
+                }
+                it == Some(size)
+            })
+            .collect::<Vec<_>>();
+        let unhealthy = healthy.iter().filter(|&&it| !it).count();
+        if unhealthy > parity {
+            return Err(io::Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "{} of {} members are unusable, at most {} may be",
+                    unhealthy,
+                    healthy.len(),
+                    parity
+                ),
+            ));
+        }
+        let header = match newest {
+            Some((_, newest)) => newest,
+            None => Header {
+                id: random_uuid()?,
+                members: members.len() as u32,
+                parity: parity as u32,
+                index: 0,
+                generation,
+                dirty: BTreeSet::new(),
+                all_dirty: false,
+            },
+        };
+        Ok(Parity {
+            parity,
+            state: Mutex::new(ParityState {
+                members,
+                healthy,
+                header,
+                written: false,
+            }),
+        })
+    }
+    /// Whether each member takes part in reads and writes.
+    pub fn healthy(&self) -> Vec<bool> {
+        self.state.lock().unwrap().healthy.clone()
+    }
+    /// Recomputes every chunk of `member`, after it was replaced or missed writes.
+    pub fn rebuild(&self, member: usize) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        match state.members.get(member) {
+            Some(Some(_)) => {}
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("there is no member {} to rebuild", member),
+                ))
+            }
+        }
+        state.healthy[member] = false;
+        let stripes = self.stripes(&state)?;
+        let target = state.members[member].take().unwrap();
+        let rebuilt = self.rebuild_onto(&mut state, member, &*target, stripes);
+        state.members[member] = Some(target);
+        rebuilt?;
+        state.healthy[member] = true;
+        info!("parity member {} is rebuilt", member);
+        Ok(())
+    }
+    fn rebuild_onto(
+        &self,
+        state: &mut ParityState,
+        member: usize,
+        target: &dyn BlockDevice,
+        stripes: u64,
+    ) -> io::Result<()> {
+        target.set_size(chunk_offset(stripes))?;
+        let mut data = vec![vec![0u8; PARITY_CHUNK_SIZE as usize]; self.data_chunks(state)];
+        for stripe in 0..stripes {
+            self.load(state, stripe, &mut data)?;
+            let chunks = self.with_parity(data.clone());
+            let at = self
+                .order(state, stripe)
+                .iter()
+                .position(|&it| it == member);
+            write_all_at(target, &chunks[at.unwrap()], chunk_offset(stripe))?;
+        }
+        target.flush()?;
+        // the header goes last, a rebuild cut short leaves the member behind
+        write_all_at(target, &state.header.encode(member), 0)?;
+        target.flush()
+    }
+    /// Lists stripes `first..last` as dirty on every healthy member before they are written. The
+    /// first write of a mount raises the generation and recomputes the parity of the stripes a
+    /// crash left dirty.
+    fn mark_dirty(&self, state: &mut ParityState, first: u64, last: u64) -> io::Result<()> {
+        let starting = !state.written;
+        let mut changed = starting;
+        if starting {
+            state.written = true;
+            state.header.generation += 1;
+        }
+        let stale = if starting {
+            Some((state.header.dirty.clone(), state.header.all_dirty))
+        } else {
+            None
+        };
+        if !state.header.all_dirty {
+            if last - first > DIRTY_CAPACITY as u64 {
+                state.header.all_dirty = true;
+                changed = true;
+            }
+            for stripe in first..last {
+                if state.header.all_dirty {
+                    break;
+                }
+                changed |= state.header.dirty.insert(stripe);
+                if state.header.dirty.len() > DIRTY_CAPACITY {
+                    state.header.all_dirty = true;
+                }
+            }
+            if state.header.all_dirty {
+                state.header.dirty.clear();
+            }
+        }
+        if changed {
+            state.write_headers();
+        }
+        if let Some((dirty, all_dirty)) = stale {
+            if all_dirty || !dirty.is_empty() {
+                self.resync(state, dirty, all_dirty)?;
+            }
+        }
+        self.check(state)
+    }
+    /// Recomputes the parity of `stripes`, or of every stripe, from their data chunks.
+    fn resync(&self, state: &mut ParityState, stripes: BTreeSet<u64>, all: bool) -> io::Result<()> {
+        if state.healthy.contains(&false) {
+            warn!("stripes written when the array went down may not match their parity");
+            return Ok(());
+        }
+        let stripes: Vec<u64> = if all {
+            (0..self.stripes(state)?).collect()
+        } else {
+            stripes.into_iter().collect()
+        };
+        warn!(
+            "recompute the parity of {} stripes after a crash",
+            stripes.len()
+        );
+        let mut data = vec![vec![0u8; PARITY_CHUNK_SIZE as usize]; self.data_chunks(state)];
+        for stripe in stripes {
+            self.load(state, stripe, &mut data)?;
+            let order = self.order(state, stripe);
+            let chunks = self.with_parity(mem::take(&mut data));
+            for (&member, chunk) in order.iter().zip(&chunks).skip(self.data_chunks(state)) {
+                state.write_chunk(member, stripe, chunk);
+            }
+            data = chunks;
+            data.truncate(self.data_chunks(state));
+        }
+        Ok(())
+    }
+    fn data_chunks(&self, state: &ParityState) -> usize {
+        state.members.len() - self.parity
+    }
+    fn stripe_size(&self, state: &ParityState) -> u64 {
+        self.data_chunks(state) as u64 * PARITY_CHUNK_SIZE
+    }
+    /// Stripes the healthy members hold.
+    fn stripes(&self, state: &ParityState) -> io::Result<u64> {
+        match state.healthy.iter().position(|&it| it) {
+            Some(i) => Ok(state.members[i]
+                .as_ref()
+                .unwrap()
+                .size()?
+                .saturating_sub(HEADER_SIZE)
+                / PARITY_CHUNK_SIZE),
+            None => Err(io::Error::from_raw_os_error(EIO)),
+        }
+    }
+    /// The members holding the chunks of `stripe`, data chunks first, then P and Q.
+    fn order(&self, state: &ParityState, stripe: u64) -> Vec<usize> {
+        let count = state.members.len();
+        let first = (stripe % count as u64) as usize;
+        (0..count).map(|i| (first + i) % count).collect()
+    }
+    /// The data chunks followed by their parity chunks.
+    fn with_parity(&self, data: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        let mut p = vec![0u8; PARITY_CHUNK_SIZE as usize];
+        let mut q = vec![0u8; PARITY_CHUNK_SIZE as usize];
+        for (j, chunk) in data.iter().enumerate() {
+            let factor = gf_pow(j);
+            for (i, &byte) in chunk.iter().enumerate() {
+                p[i] ^= byte;
+                q[i] ^= gf_mul(factor, byte);
+            }
+        }
+        let mut chunks = data;
+        chunks.push(p);
+        if self.parity == 2 {
+            chunks.push(q);
+        }
+        chunks
+    }
+    /// Reads the data chunks of `stripe`, computing the ones on members that are unusable.
+    fn load(&self, state: &mut ParityState, stripe: u64, data: &mut [Vec<u8>]) -> io::Result<()> {
+        let order = self.order(state, stripe);
+        let mut missing = vec![];
+        for (j, chunk) in data.iter_mut().enumerate() {
+            if !state.read_chunk(order[j], stripe, chunk) {
+                missing.push(j);
+            }
+        }
+        if missing.is_empty() {
+            return Ok(());
+        }
+        let k = data.len();
+        let mut p = vec![0u8; PARITY_CHUNK_SIZE as usize];
+        let mut q = vec![0u8; PARITY_CHUNK_SIZE as usize];
+        let has_p = state.read_chunk(order[k], stripe, &mut p);
+        let has_q = self.parity == 2 && state.read_chunk(order[k + 1], stripe, &mut q);
+        // what is left of P and Q without the chunks at hand
+        for (j, chunk) in data.iter().enumerate() {
+            if !missing.contains(&j) {
+                let factor = gf_pow(j);
+                for (i, &byte) in chunk.iter().enumerate() {
+                    p[i] ^= byte;
+                    q[i] ^= gf_mul(factor, byte);
+                }
+            }
+        }
+        match (missing.as_slice(), has_p, has_q) {
+            (&[x], true, _) => data[x].copy_from_slice(&p),
+            (&[x], false, true) => {
+                let factor = gf_inv(gf_pow(x));
+                for (i, byte) in data[x].iter_mut().enumerate() {
+                    *byte = gf_mul(q[i], factor);
+                }
+            }
+            (&[x, y], true, true) => {
+                // P = Dx + Dy and Q = g^x Dx + g^y Dy
+                let (gx, gy) = (gf_pow(x), gf_pow(y));
+                let factor = gf_inv(gx ^ gy);
+                for i in 0..p.len() {
+                    let dx = gf_mul(q[i] ^ gf_mul(gy, p[i]), factor);
+                    data[x][i] = dx;
+                    data[y][i] = p[i] ^ dx;
+                }
+            }
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "stripe {} lost more chunks than its parity restores",
+                        stripe
+                    ),
+                ))
+            }
+        }
+        Ok(())
+    }
+    /// Writes `data` with its parity to every usable member, fails once too many are gone.
+    fn store(&self, state: &mut ParityState, stripe: u64, data: &[Vec<u8>]) -> io::Result<()> {
+        self.mark_dirty(state, stripe, stripe + 1)?;
+        let order = self.order(state, stripe);
+        for (member, chunk) in order.into_iter().zip(self.with_parity(data.to_vec())) {
+            state.write_chunk(member, stripe, &chunk);
+        }
+        self.check(state)
+    }
+    /// Fails once more members are unusable than parity makes up for.
+    fn check(&self, state: &ParityState) -> io::Result<()> {
+        if state.healthy.iter().filter(|&&it| !it).count() > self.parity {
+            Err(io::Error::from_raw_os_error(EIO))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl BlockDevice for Parity {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let stripe_size = self.stripe_size(&state);
+        let size = self.stripes(&state)? * stripe_size;
+        if offset >= size {
+            return Ok(0);
+        }
+        let end = min(size, offset + buf.len() as u64);
+        let mut data = vec![vec![0u8; PARITY_CHUNK_SIZE as usize]; self.data_chunks(&state)];
+        let mut position = offset;
+        while position < end {
+            let stripe = position / stripe_size;
+            self.load(&mut state, stripe, &mut data)?;
+            let stop = min(end, (stripe + 1) * stripe_size);
+            while position < stop {
+                let within = position - stripe * stripe_size;
+                let chunk = &data[(within / PARITY_CHUNK_SIZE) as usize];
+                let from = (within % PARITY_CHUNK_SIZE) as usize;
+                let length = min(
+                    PARITY_CHUNK_SIZE as usize - from,
+                    (stop - position) as usize,
+                );
+                let at = (position - offset) as usize;
+                buf[at..at + length].copy_from_slice(&chunk[from..from + length]);
+                position += length as u64;
+            }
+        }
+        Ok((end - offset) as usize)
+    }
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let stripe_size = self.stripe_size(&state);
+        let stripes = self.stripes(&state)?;
+        let end = offset + buf.len() as u64;
+        let mut data = vec![vec![0u8; PARITY_CHUNK_SIZE as usize]; self.data_chunks(&state)];
+        let mut position = offset;
+        while position < end {
+            let stripe = position / stripe_size;
+            let start = stripe * stripe_size;
+            let stop = min(end, start + stripe_size);
+            if stripe < stripes && (position > start || stop < start + stripe_size) {
+                self.load(&mut state, stripe, &mut data)?;
+            } else {
+                data.iter_mut()
+                    .for_each(|chunk| chunk.iter_mut().for_each(|it| *it = 0));
+            }
+            while position < stop {
+                let within = position - start;
+                let chunk = &mut data[(within / PARITY_CHUNK_SIZE) as usize];
+                let from = (within % PARITY_CHUNK_SIZE) as usize;
+                let length = min(
+                    PARITY_CHUNK_SIZE as usize - from,
+                    (stop - position) as usize,
+                );
+                let at = (position - offset) as usize;
+                chunk[from..from + length].copy_from_slice(&buf[at..at + length]);
+                position += length as u64;
+            }
+            self.store(&mut state, stripe, &data)?;
+        }
+        Ok(buf.len())
+    }
+    fn flush(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        for member in 0..state.members.len() {
+            if state.healthy[member] {
+                if let Err(e) = state.members[member].as_ref().unwrap().flush() {
+                    state.fail(member, &e);
+                }
+            }
+        }
+        // everything up to here is durable, no stripe is dirty any more
+        if state.written && (state.header.all_dirty || !state.header.dirty.is_empty()) {
+            state.header.dirty.clear();
+            state.header.all_dirty = false;
+            state.write_headers();
+        }
+        self.check(&state)
+    }
+    fn discard(&self, offset: u64, length: u64) -> io::Result<()> {
+        let stripe_size = self.stripe_size(&self.state.lock().unwrap());
+        let end = min(offset.saturating_add(length), self.size()?);
+        if offset >= end {
+            return Ok(());
+        }
+        let first = align(offset, stripe_size) / stripe_size;
+        let last = end / stripe_size;
+        if first >= last {
+            return write_all_at(self, &vec![0u8; (end - offset) as usize], offset);
+        }
+        // partial stripes are zeroed, whole ones discarded on every member: zero data has zero
+        // parity
+        let head = first * stripe_size - offset;
+        write_all_at(self, &vec![0u8; head as usize], offset)?;
+        let tail = end - last * stripe_size;
+        write_all_at(self, &vec![0u8; tail as usize], last * stripe_size)?;
+        let mut state = self.state.lock().unwrap();
+        self.mark_dirty(&mut state, first, last)?;
+        let (from, to) = (chunk_offset(first), chunk_offset(last));
+        for member in 0..state.members.len() {
+            if !state.healthy[member] {
+                continue;
+            }
+            let device = state.members[member].as_ref().unwrap();
+            let to = min(to, device.size()?);
+            if from >= to {
+                continue;
+            }
+            if device.discard(from, to - from).is_err() {
+                // the chunks have to read back as zeroes for the parity to hold
+                let zeroes = vec![0u8; PARITY_CHUNK_SIZE as usize];
+                for stripe in first..(to - HEADER_SIZE) / PARITY_CHUNK_SIZE {
+                    state.write_chunk(member, stripe, &zeroes);
+                }
+            }
+        }
+        self.check(&state)
+    }
+    fn size(&self) -> io::Result<u64> {
+        let state = self.state.lock().unwrap();
+        Ok(self.stripes(&state)? * self.stripe_size(&state))
+    }
+    /// Sizes are rounded up to whole stripes.
+    fn set_size(&self, size: u64) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let stripes = self.stripes(&state)?;
+        self.mark_dirty(&mut state, stripes, stripes)?;
+        let stripe_size = self.stripe_size(&state);
+        let member_size = chunk_offset(align(size, stripe_size) / stripe_size);
+        for member in 0..state.members.len() {
+            if state.healthy[member] {
+                if let Err(e) = state.members[member]
+                    .as_ref()
+                    .unwrap()
+                    .set_size(member_size)
+                {
+                    state.fail(member, &e);
+                }
+            }
+        }
+        self.check(&state)
+    }
+}
+
+#[test]
+fn test_parity() -> io::Result<()> {
+    use crate::disk::device::Memory;
+    use crate::disk::fault::{Fault, FaultRule, FaultSchedule, Faulty, Op};
+    use std::sync::Arc;
+
+    let shared = |count| {
+        (0..count)
+            .map(|_| Arc::new(Memory::default()))
+            .collect::<Vec<_>>()
+    };
+    let parity_over = |members: &[Arc<Memory>], parity, absent: &[usize]| {
+        let members = members
+            .iter()
+            .enumerate()
+            .map(|(i, it)| {
+                if absent.contains(&i) {
+                    None
+                } else {
+                    Some(Box::new(it.clone()) as Box<dyn BlockDevice>)
+                }
+            })
+            .collect();
+        Parity::new(members, parity)
+    };
+    let read_back = |parity: &Parity, length| -> io::Result<Vec<u8>> {
+        let mut buffer = vec![0u8; length];
+        assert_eq!(read_all_at(parity, &mut buffer, 0)?, length);
+        Ok(buffer)
+    };
+    let content = (0..30000).map(|it| (it % 251) as u8).collect::<Vec<_>>();
+    for &(count, parity) in &[(3, 1), (4, 2), (5, 2)] {
+        let members = shared(count);
+        let striped = parity_over(&members, parity, &[])?;
+        write_all_at(&striped, &content, 0)?;
+        write_all_at(&striped, b"hello world", 4090)?;
+        let mut expected = content.clone();
+        expected[4090..4101].copy_from_slice(b"hello world");
+        assert_eq!(read_back(&striped, expected.len())?, expected);
+
+        // every combination of `parity` missing members still reads back
+        for a in 0..count {
+            for b in a..count {
+                let absent = if parity == 1 { vec![a] } else { vec![a, b] };
+                let degraded = parity_over(&members, parity, &absent)?;
+                assert_eq!(read_back(&degraded, expected.len())?, expected);
+            }
+        }
+        let absent = (0..=parity).collect::<Vec<_>>();
+        assert!(parity_over(&members, parity, &absent).is_err());
+
+        // writes to a degraded array land in the parity of the remaining members
+        let degraded = parity_over(&members, parity, &[1])?;
+        write_all_at(&degraded, b"degraded", 20000)?;
+        expected[20000..20008].copy_from_slice(b"degraded");
+        assert_eq!(read_back(&degraded, expected.len())?, expected);
+
+        // a replaced member is rebuilt from the others
+        members[1].set_size(0)?;
+        let replaced = parity_over(&members, parity, &[])?;
+        assert!(!replaced.healthy()[1]);
+        replaced.rebuild(1)?;
+        assert!(replaced.healthy().iter().all(|&it| it));
+        let absent = if parity == 1 { vec![0] } else { vec![0, 2] };
+        let degraded = parity_over(&members, parity, &absent)?;
+        assert_eq!(read_back(&degraded, expected.len())?, expected);
+
+        // discarded stripes read back as zeroes and keep their parity
+        let striped = parity_over(&members, parity, &[])?;
+        striped.discard(100, 20000)?;
+        expected[100..20100].iter_mut().for_each(|it| *it = 0);
+        assert_eq!(read_back(&striped, expected.len())?, expected);
+        let degraded = parity_over(&members, parity, &[1])?;
+        assert_eq!(read_back(&degraded, expected.len())?, expected);
+    }
+
+    // the members know their array and position
+    let members = shared(3);
+    let striped = parity_over(&members, 0, &[])?;
+    write_all_at(&striped, &content, 0)?;
+    let swapped = [members[1].clone(), members[0].clone(), members[2].clone()];
+    let e = parity_over(&swapped, 0, &[]).err().unwrap();
+    assert_eq!(e.kind(), ErrorKind::InvalidData);
+    assert!(parity_over(&members[..2], 0, &[]).is_err());
+    assert!(parity_over(&members, 2, &[]).is_err());
+
+    // parity torn by a crash is recomputed before the next write
+    members[2].write_at(&[0xff; PARITY_CHUNK_SIZE as usize], chunk_offset(0))?;
+    let striped = parity_over(&members, 0, &[])?;
+    write_all_at(&striped, b"x", 20000)?;
+    striped.flush()?;
+    let mut expected = content.clone();
+    expected[20000] = b'x';
+    let degraded = parity_over(&members, 0, &[0])?;
+    assert_eq!(read_back(&degraded, expected.len())?, expected);
+
+    // a member failing its reads drops out, is computed from the others and stays out on the next
+    // mount
+    let schedule = FaultSchedule::new(1);
+    let failing: Arc<dyn BlockDevice> = Arc::new(Faulty::new(Memory::default(), schedule.clone()));
+    let members = [
+        failing,
+        Arc::new(Memory::default()),
+        Arc::new(Memory::default()),
+    ];
+    let mount = || {
+        let members = members
+            .iter()
+            .map(|it| Some(Box::new(it.clone()) as Box<dyn BlockDevice>))
+            .collect();
+        Parity::new(members, 1)
+    };
+    let striped = mount()?;
+    write_all_at(&striped, &content, 0)?;
+    schedule.inject(FaultRule::new(Op::Read, Fault::Error));
+    assert_eq!(read_back(&striped, content.len())?, content);
+    assert_eq!(striped.healthy(), vec![false, true, true]);
+    schedule.clear();
+    assert_eq!(mount()?.healthy(), vec![false, true, true]);
+    Ok(())
+}
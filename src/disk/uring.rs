@@ -0,0 +1,520 @@
+//! Reads and writes through io_uring.
+//!
+//! Every thread doing I/O pushes its requests into one shared submission queue, so requests of
+//! independent FUSE handlers are in flight together. Whichever waiting thread gets there first
+//! reaps the completion queue for everyone else.
+
+use crate::disk::device::{write_all_at, BlockDevice};
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Condvar, Mutex};
+
+/// Entries of the submission queue, requests in flight at most.
+pub const QUEUE_DEPTH: u32 = 64;
+
+const IORING_OFF_SQ_RING: i64 = 0;
+const IORING_OFF_CQ_RING: i64 = 0x800_0000;
+const IORING_OFF_SQES: i64 = 0x1000_0000;
+const IORING_FEAT_SINGLE_MMAP: u32 = 1;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_OP_READV: u8 = 1;
+const IORING_OP_WRITEV: u8 = 2;
+const IORING_OP_FSYNC: u8 = 3;
+const IORING_FSYNC_DATASYNC: u32 = 1;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqRingOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqRingOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+/// `struct io_uring_params` from `linux/io_uring.h`.
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqRingOffsets,
+    cq_off: CqRingOffsets,
+}
+
+/// `struct io_uring_sqe`, only the fields of the operations used here.
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    rw_flags: u32,
+    user_data: u64,
+    pad: [u64; 3],
+}
+
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+struct Mapping {
+    address: *mut u8,
+    length: usize,
+}
+
+impl Mapping {
+    fn new(fd: RawFd, length: usize, offset: i64) -> io::Result<Self> {
+        let address = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                length,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if address == libc::MAP_FAILED {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(Mapping {
+                address: address as *mut u8,
+                length,
+            })
+        }
+    }
+    fn at<T>(&self, offset: u32) -> *mut T {
+        unsafe { self.address.add(offset as usize) as *mut T }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.address as *mut _, self.length) };
+    }
+}
+
+/// An io_uring instance with its queues mapped into memory.
+pub struct Ring {
+    params: Params,
+    sq_ring: Mapping,
+    cq_ring: Option<Mapping>,
+    sqes: Mapping,
+    fd: RawFd,
+}
+
+impl Ring {
+    /// Sets up a ring, fails where the kernel has no io_uring or it is disabled.
+    pub fn new(entries: u32) -> io::Result<Self> {
+        let mut params = Params::default();
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_setup,
+                entries,
+                &mut params as *mut Params,
+            )
+        } as RawFd;
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let sq_length = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let cq_length =
+            params.cq_off.cqes as usize + params.cq_entries as usize * mem::size_of::<Cqe>();
+        let single = params.features & IORING_FEAT_SINGLE_MMAP != 0;
+        let mapped = (|| {
+            let sq_ring = if single {
+                Mapping::new(fd, sq_length.max(cq_length), IORING_OFF_SQ_RING)?
+            } else {
+                Mapping::new(fd, sq_length, IORING_OFF_SQ_RING)?
+            };
+            let cq_ring = if single {
+                None
+            } else {
+                Some(Mapping::new(fd, cq_length, IORING_OFF_CQ_RING)?)
+            };
+            let sqes_length = params.sq_entries as usize * mem::size_of::<Sqe>();
+            let sqes = Mapping::new(fd, sqes_length, IORING_OFF_SQES)?;
+            Ok((sq_ring, cq_ring, sqes))
+        })();
+        match mapped {
+            Ok((sq_ring, cq_ring, sqes)) => Ok(Ring {
+                params,
+                sq_ring,
+                cq_ring,
+                sqes,
+                fd,
+            }),
+            Err(e) => {
+                unsafe { libc::close(fd) };
+                Err(e)
+            }
+        }
+    }
+    fn cq_ring(&self) -> &Mapping {
+        self.cq_ring.as_ref().unwrap_or(&self.sq_ring)
+    }
+    fn sq_field(&self, offset: u32) -> &AtomicU32 {
+        unsafe { &*self.sq_ring.at::<AtomicU32>(offset) }
+    }
+    fn cq_field(&self, offset: u32) -> &AtomicU32 {
+        unsafe { &*self.cq_ring().at::<AtomicU32>(offset) }
+    }
+    fn enter(&self, to_submit: u32, min_complete: u32, flags: u32) -> io::Result<u32> {
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_enter,
+                self.fd,
+                to_submit,
+                min_complete,
+                flags,
+                ptr::null::<libc::sigset_t>(),
+                0,
+            )
+        };
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(result as u32)
+        }
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+fn retryable(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EINTR) | Some(libc::EAGAIN) | Some(libc::EBUSY)
+    )
+}
+
+struct Completions {
+    results: HashMap<u64, i32>,
+    in_flight: u32,
+    reaping: bool,
+}
+
+/// A backing store read and written through io_uring. Everything besides reads, writes and
+/// flushes goes to the store itself.
+pub struct Uring<D: BlockDevice + AsRawFd> {
+    device: D,
+    ring: Ring,
+    /// Our copy of the submission queue tail and the next `user_data`.
+    submission: Mutex<(u32, u64)>,
+    completions: Mutex<Completions>,
+    completed: Condvar,
+}
+
+// The ring is only touched through the atomics the kernel shares with us and under the locks above.
+unsafe impl<D: BlockDevice + AsRawFd> Send for Uring<D> {}
+unsafe impl<D: BlockDevice + AsRawFd> Sync for Uring<D> {}
+
+impl<D: BlockDevice + AsRawFd> Uring<D> {
+    pub fn new(device: D, ring: Ring) -> Self {
+        let tail = ring
+            .sq_field(ring.params.sq_off.tail)
+            .load(Ordering::Acquire);
+        Uring {
+            device,
+            ring,
+            submission: Mutex::new((tail, 0)),
+            completions: Mutex::new(Completions {
+                results: HashMap::new(),
+                in_flight: 0,
+                reaping: false,
+            }),
+            completed: Condvar::new(),
+        }
+    }
+    fn depth(&self) -> usize {
+        self.ring.params.sq_entries as usize
+    }
+    /// Waits until `count` more requests fit into the queues.
+    fn reserve(&self, count: u32) {
+        let mut completions = self.completions.lock().unwrap();
+        while completions.in_flight + count > self.ring.params.sq_entries {
+            completions = self.completed.wait(completions).unwrap();
+        }
+        completions.in_flight += count;
+    }
+    /// Queues and submits `sqes` as one batch, returns the ids of those the kernel took.
+    fn push(&self, sqes: &mut [Sqe]) -> (Vec<u64>, io::Result<()>) {
+        let ring = &self.ring;
+        let off = &ring.params.sq_off;
+        let mut submission = self.submission.lock().unwrap();
+        let (ref mut tail, ref mut next_id) = *submission;
+        let mask = unsafe { *ring.sq_ring.at::<u32>(off.ring_mask) };
+        let array = ring.sq_ring.at::<u32>(off.array);
+        let mut ids = Vec::with_capacity(sqes.len());
+        for sqe in sqes.iter_mut() {
+            sqe.user_data = *next_id;
+            ids.push(*next_id);
+            *next_id += 1;
+            let index = *tail & mask;
+            unsafe {
+                ptr::write(ring.sqes.at::<Sqe>(0).add(index as usize), *sqe);
+                ptr::write(array.add(index as usize), index);
+            }
+            *tail = tail.wrapping_add(1);
+        }
+        ring.sq_field(off.tail).store(*tail, Ordering::Release);
+        let mut submitted = 0;
+        while submitted < sqes.len() {
+            match ring.enter((sqes.len() - submitted) as u32, 0, 0) {
+                Ok(count) => submitted += count as usize,
+                Err(ref e) if retryable(e) => {}
+                Err(e) => {
+                    // take back what the kernel did not pick up, it must not see the buffers later,
+                    // `run` fails those requests one by one
+                    *tail = ring.sq_field(off.head).load(Ordering::Acquire);
+                    ring.sq_field(off.tail).store(*tail, Ordering::Release);
+                    ids.truncate(submitted);
+                    return (ids, Err(e));
+                }
+            }
+        }
+        (ids, Ok(()))
+    }
+    fn reap(&self, completions: &mut Completions) {
+        let ring = &self.ring;
+        let off = &ring.params.cq_off;
+        let mask = unsafe { *ring.cq_ring().at::<u32>(off.ring_mask) };
+        let cqes = ring.cq_ring().at::<Cqe>(off.cqes);
+        let mut head = ring.cq_field(off.head).load(Ordering::Relaxed);
+        let tail = ring.cq_field(off.tail).load(Ordering::Acquire);
+        while head != tail {
+            let cqe = unsafe { &*cqes.add((head & mask) as usize) };
+            completions.results.insert(cqe.user_data, cqe.res);
+            head = head.wrapping_add(1);
+        }
+        ring.cq_field(off.head).store(head, Ordering::Release);
+    }
+    fn wait(&self, id: u64) -> i32 {
+        let mut completions = self.completions.lock().unwrap();
+        loop {
+            if let Some(result) = completions.results.remove(&id) {
+                completions.in_flight -= 1;
+                self.completed.notify_all();
+                return result;
+            }
+            if completions.reaping {
+                completions = self.completed.wait(completions).unwrap();
+                continue;
+            }
+            completions.reaping = true;
+            drop(completions);
+            // the request still owns its buffer, so waiting is the only way out even on errors
+            if let Err(e) = self.ring.enter(0, 1, IORING_ENTER_GETEVENTS) {
+                if !retryable(&e) {
+                    warn!("waiting for io_uring completions failed: {}", e);
+                }
+            }
+            completions = self.completions.lock().unwrap();
+            self.reap(&mut completions);
+            completions.reaping = false;
+            self.completed.notify_all();
+        }
+    }
+    /// Runs `sqes` in batches of at most the queue depth, one result per request in the same
+    /// order. Requests the kernel did not take fail with the error of their submission.
+    fn run(&self, sqes: &mut [Sqe]) -> Vec<io::Result<usize>> {
+        let mut results = Vec::with_capacity(sqes.len());
+        for batch in sqes.chunks_mut(self.depth()) {
+            self.reserve(batch.len() as u32);
+            let (ids, pushed) = self.push(batch);
+            let taken = ids.len();
+            for id in ids {
+                results.push(check(self.wait(id)));
+            }
+            if let Err(e) = pushed {
+                let mut completions = self.completions.lock().unwrap();
+                completions.in_flight -= (batch.len() - taken) as u32;
+                self.completed.notify_all();
+                drop(completions);
+                results.extend((taken..batch.len()).map(|_| Err(refused(&e))));
+            }
+        }
+        results
+    }
+    fn rw(&self, opcode: u8, iovec: &libc::iovec, offset: u64) -> Sqe {
+        Sqe {
+            opcode,
+            fd: self.device.as_raw_fd(),
+            off: offset,
+            addr: iovec as *const libc::iovec as u64,
+            len: 1,
+            ..Sqe::default()
+        }
+    }
+}
+
+fn check(result: i32) -> io::Result<usize> {
+    if result < 0 {
+        Err(io::Error::from_raw_os_error(-result))
+    } else {
+        Ok(result as usize)
+    }
+}
+
+/// The error of a submission, for each request it left behind.
+fn refused(e: &io::Error) -> io::Error {
+    match e.raw_os_error() {
+        Some(code) => io::Error::from_raw_os_error(code),
+        None => io::Error::new(e.kind(), e.to_string()),
+    }
+}
+
+impl<D: BlockDevice + AsRawFd> BlockDevice for Uring<D> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let iovec = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut _,
+            iov_len: buf.len(),
+        };
+        self.run(&mut [self.rw(IORING_OP_READV, &iovec, offset)])
+            .remove(0)
+    }
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        let iovec = libc::iovec {
+            iov_base: buf.as_ptr() as *mut _,
+            iov_len: buf.len(),
+        };
+        self.run(&mut [self.rw(IORING_OP_WRITEV, &iovec, offset)])
+            .remove(0)
+    }
+    fn write_batch(&self, writes: &[(u64, &[u8])]) -> io::Result<()> {
+        let iovecs: Vec<libc::iovec> = writes
+            .iter()
+            .map(|(_, buf)| libc::iovec {
+                iov_base: buf.as_ptr() as *mut _,
+                iov_len: buf.len(),
+            })
+            .collect();
+        let mut sqes: Vec<Sqe> = writes
+            .iter()
+            .zip(&iovecs)
+            .map(|(&(offset, _), iovec)| self.rw(IORING_OP_WRITEV, iovec, offset))
+            .collect();
+        // every write is tried and every failure reported, the first one is returned
+        let mut failed = None;
+        for (&(offset, buf), result) in writes.iter().zip(self.run(&mut sqes)) {
+            let result = result
+                .and_then(|written| write_all_at(self, &buf[written..], offset + written as u64));
+            if let Err(e) = result {
+                error!(
+                    "cannot write {} bytes at {} through io_uring: {}",
+                    buf.len(),
+                    offset,
+                    e
+                );
+                failed = failed.or(Some(e));
+            }
+        }
+        match failed {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+    fn flush(&self) -> io::Result<()> {
+        let sqe = Sqe {
+            opcode: IORING_OP_FSYNC,
+            fd: self.device.as_raw_fd(),
+            rw_flags: IORING_FSYNC_DATASYNC,
+            ..Sqe::default()
+        };
+        self.run(&mut [sqe]).remove(0).map(|_| ())
+    }
+    fn discard(&self, offset: u64, length: u64) -> io::Result<()> {
+        self.device.discard(offset, length)
+    }
+    fn size(&self) -> io::Result<u64> {
+        self.device.size()
+    }
+    fn set_size(&self, size: u64) -> io::Result<()> {
+        self.device.set_size(size)
+    }
+}
+
+#[test]
+fn test_uring() -> io::Result<()> {
+    use crate::disk::device::HostFile;
+    use std::sync::Arc;
+    use std::thread;
+    use tempfile::tempfile;
+    let ring = match Ring::new(8) {
+        Ok(ring) => ring,
+        // the kernel or a seccomp filter may not allow io_uring
+        Err(_) => return Ok(()),
+    };
+    let uring = Arc::new(Uring::new(HostFile(tempfile()?), ring));
+    let threads: Vec<_> = (0..4u8)
+        .map(|i| {
+            let uring = uring.clone();
+            thread::spawn(move || {
+                for j in 0..16u64 {
+                    let offset = (j * 4 + i as u64) * 512;
+                    write_all_at(&*uring, &[i; 512], offset).unwrap();
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    uring.flush()?;
+    assert_eq!(uring.size()?, 64 * 512);
+    let mut buffer = [0u8; 512];
+    assert_eq!(uring.read_at(&mut buffer, 6 * 512)?, 512);
+    assert_eq!(buffer, [2u8; 512]);
+
+    let blocks: Vec<Vec<u8>> = (0..20u8).map(|i| vec![i; 512]).collect();
+    let writes: Vec<(u64, &[u8])> = blocks
+        .iter()
+        .enumerate()
+        .map(|(i, block)| (i as u64 * 1024, &block[..]))
+        .collect();
+    uring.write_batch(&writes)?;
+    assert_eq!(uring.read_at(&mut buffer, 19 * 1024)?, 512);
+    assert_eq!(buffer, [19u8; 512]);
+    assert_eq!(uring.read_at(&mut buffer, 64 * 512)?, 0);
+    Ok(())
+}
@@ -1,20 +1,31 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::convert::TryFrom;
+use std::io;
+use std::io::{ErrorKind, Read};
+use std::time::SystemTime;
 
+use crate::disk::encode::{invalid_data, Decode, Decoder, Encode, Encoder};
 use fuse::{FileAttr, FileType};
-use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub enum FileTypeDump {
     Directory,
     RegularFile,
+    /// The target is kept as the content.
+    Symlink,
 }
 
-impl From<FileType> for FileTypeDump {
-    fn from(origin: FileType) -> Self {
+impl TryFrom<FileType> for FileTypeDump {
+    type Error = io::Error;
+
+    fn try_from(origin: FileType) -> io::Result<Self> {
         match origin {
-            FileType::Directory => FileTypeDump::Directory,
-            FileType::RegularFile => FileTypeDump::RegularFile,
-            _ => unimplemented!("Not supported now"),
+            FileType::Directory => Ok(FileTypeDump::Directory),
+            FileType::RegularFile => Ok(FileTypeDump::RegularFile),
+            FileType::Symlink => Ok(FileTypeDump::Symlink),
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("dumbfs cannot store a {:?}", origin),
+            )),
         }
     }
 }
@@ -24,11 +35,33 @@ impl Into<FileType> for FileTypeDump {
         match self {
             FileTypeDump::Directory => FileType::Directory,
             FileTypeDump::RegularFile => FileType::RegularFile,
+            FileTypeDump::Symlink => FileType::Symlink,
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+impl Encode for FileTypeDump {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.u8(match self {
+            FileTypeDump::Directory => 1,
+            FileTypeDump::RegularFile => 2,
+            FileTypeDump::Symlink => 3,
+        })
+    }
+}
+
+impl Decode for FileTypeDump {
+    fn decode<R: Read>(decoder: &mut Decoder<R>) -> io::Result<Self> {
+        match decoder.u8()? {
+            1 => Ok(FileTypeDump::Directory),
+            2 => Ok(FileTypeDump::RegularFile),
+            3 => Ok(FileTypeDump::Symlink),
+            _ => invalid_data("unknown file type"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct FileAttrDump {
     pub ino: u64,
     pub size: u64,
@@ -67,9 +100,51 @@ impl Default for FileAttrDump {
     }
 }
 
-impl From<FileAttr> for FileAttrDump {
-    fn from(origin: FileAttr) -> Self {
-        FileAttrDump {
+impl Encode for FileAttrDump {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.u64(self.ino);
+        encoder.u64(self.size);
+        encoder.u64(self.blocks);
+        encoder.time(self.atime);
+        encoder.time(self.mtime);
+        encoder.time(self.ctime);
+        encoder.time(self.crtime);
+        self.kind.encode(encoder);
+        encoder.u16(self.perm);
+        encoder.u32(self.nlink);
+        encoder.u32(self.uid);
+        encoder.u32(self.gid);
+        encoder.u32(self.rdev);
+        encoder.u32(self.flags);
+    }
+}
+
+impl Decode for FileAttrDump {
+    fn decode<R: Read>(decoder: &mut Decoder<R>) -> io::Result<Self> {
+        Ok(FileAttrDump {
+            ino: decoder.u64()?,
+            size: decoder.u64()?,
+            blocks: decoder.u64()?,
+            atime: decoder.time()?,
+            mtime: decoder.time()?,
+            ctime: decoder.time()?,
+            crtime: decoder.time()?,
+            kind: FileTypeDump::decode(decoder)?,
+            perm: decoder.u16()?,
+            nlink: decoder.u32()?,
+            uid: decoder.u32()?,
+            gid: decoder.u32()?,
+            rdev: decoder.u32()?,
+            flags: decoder.u32()?,
+        })
+    }
+}
+
+impl TryFrom<FileAttr> for FileAttrDump {
+    type Error = io::Error;
+
+    fn try_from(origin: FileAttr) -> io::Result<Self> {
+        Ok(FileAttrDump {
             ino: origin.ino,
             size: origin.size,
             blocks: origin.blocks,
@@ -77,14 +152,14 @@ impl From<FileAttr> for FileAttrDump {
             mtime: origin.mtime,
             ctime: origin.ctime,
             crtime: origin.crtime,
-            kind: origin.kind.into(),
+            kind: FileTypeDump::try_from(origin.kind)?,
             perm: origin.perm,
             nlink: origin.nlink,
             uid: origin.uid,
             gid: origin.gid,
             rdev: origin.rdev,
             flags: origin.flags,
-        }
+        })
     }
 }
 
@@ -111,6 +186,7 @@ impl Into<FileAttr> for FileAttrDump {
 
 #[test]
 fn test_encode_decode() {
+    use std::time::UNIX_EPOCH;
     let file_attr = FileAttr {
         ino: 1,
         size: 1024,
@@ -127,12 +203,20 @@ fn test_encode_decode() {
         rdev: 0,
         flags: 0,
     };
-    let encoded: Vec<u8> = bincode::serialize::<FileAttrDump>(&file_attr.into()).unwrap();
-    assert_eq!(encoded.len(), 98);
-    let decoded: FileAttr = bincode::deserialize::<FileAttrDump>(&encoded[..])
+    let mut encoder = Encoder::default();
+    FileAttrDump::try_from(file_attr)
+        .unwrap()
+        .encode(&mut encoder);
+    let encoded = encoder.into_inner();
+    assert_eq!(encoded.len(), 95);
+    assert_eq!(&encoded[8..16], &1024u64.to_le_bytes());
+    assert_eq!(encoded[72], 2);
+    assert_eq!(&encoded[79..83], &501u32.to_le_bytes());
+    let decoded: FileAttr = FileAttrDump::decode(&mut Decoder::new(&encoded[..]))
         .unwrap()
         .into();
     assert_eq!(decoded.size, 1024);
-    let dump: FileAttrDump = decoded.into();
+    let dump = FileAttrDump::try_from(decoded).unwrap();
     assert_eq!(dump.kind, FileTypeDump::RegularFile);
+    assert!(FileTypeDump::try_from(FileType::Socket).is_err());
 }
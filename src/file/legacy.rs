@@ -0,0 +1,169 @@
+//! Read-only access to images written with the original `bincode` layout: a superblock of
+//! `magic`, `next_ino` and `next_free_address`, then sibling-linked nodes with their content
+//! stored right behind them. Only kept around so `dumbfs upgrade` can convert such images.
+
+use crate::disk::encode::invalid_data;
+use crate::disk::Disk;
+use crate::file::dump_file_attr;
+use bincode::{deserialize_from, serialized_size};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::cmp::min;
+use std::io;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::time::SystemTime;
+
+/// Bytes of a node with an empty name, no node is smaller.
+pub const MIN_NODE_SIZE: u64 = 122;
+
+fn load_at<D: DeserializeOwned>(disk: &Disk, location: u64) -> io::Result<D> {
+    let mut disk = disk.clone();
+    disk.seek(SeekFrom::Start(location))?;
+    deserialize_from(disk).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DumbFsMeta {
+    pub magic: u32,
+    pub next_ino: u64,
+    pub next_free_address: u64,
+}
+
+impl DumbFsMeta {
+    pub fn load(disk: &Disk) -> io::Result<Self> {
+        load_at(disk, 0)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub enum FileTypeDump {
+    Directory,
+    RegularFile,
+}
+
+impl From<FileTypeDump> for dump_file_attr::FileTypeDump {
+    fn from(origin: FileTypeDump) -> Self {
+        match origin {
+            FileTypeDump::Directory => dump_file_attr::FileTypeDump::Directory,
+            FileTypeDump::RegularFile => dump_file_attr::FileTypeDump::RegularFile,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileAttrDump {
+    pub ino: u64,
+    pub size: u64,
+    pub blocks: u64,
+    pub atime: SystemTime,
+    pub mtime: SystemTime,
+    pub ctime: SystemTime,
+    pub crtime: SystemTime,
+    pub kind: FileTypeDump,
+    pub perm: u16,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub rdev: u32,
+    pub flags: u32,
+}
+
+impl From<FileAttrDump> for dump_file_attr::FileAttrDump {
+    fn from(origin: FileAttrDump) -> Self {
+        dump_file_attr::FileAttrDump {
+            ino: origin.ino,
+            size: origin.size,
+            blocks: origin.blocks,
+            atime: origin.atime,
+            mtime: origin.mtime,
+            ctime: origin.ctime,
+            crtime: origin.crtime,
+            kind: origin.kind.into(),
+            perm: origin.perm,
+            nlink: origin.nlink,
+            uid: origin.uid,
+            gid: origin.gid,
+            rdev: origin.rdev,
+            flags: origin.flags,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileMeta {
+    pub first_child: u64,
+    pub next_sibling: u64,
+    pub file_attr: FileAttrDump,
+    pub filename: String,
+}
+
+pub struct File {
+    address: u64,
+    cursor: u64,
+    pub meta: FileMeta,
+    disk: Disk,
+}
+
+pub struct FileIterator {
+    address: Option<u64>,
+    /// Nodes the image has room for, a longer chain must loop.
+    remaining: u64,
+    disk: Disk,
+}
+
+impl File {
+    pub fn load(disk: &Disk, address: u64) -> io::Result<Self> {
+        load_at(disk, address).map(|meta| File {
+            meta,
+            address,
+            cursor: 0,
+            disk: disk.clone(),
+        })
+    }
+    pub fn children(&self) -> FileIterator {
+        FileIterator {
+            address: if self.meta.first_child == 0 {
+                None
+            } else {
+                Some(self.meta.first_child)
+            },
+            remaining: self.disk.len().unwrap_or(0) / MIN_NODE_SIZE,
+            disk: self.disk.clone(),
+        }
+    }
+}
+
+impl Read for File {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.meta.file_attr.size.saturating_sub(self.cursor);
+        let length = min(buf.len() as u64, remaining) as usize;
+        if length == 0 {
+            return Ok(0);
+        }
+        let meta_size = serialized_size(&self.meta).unwrap();
+        self.disk
+            .seek(SeekFrom::Start(self.address + meta_size + self.cursor))?;
+        let read = self.disk.read(&mut buf[..length])?;
+        self.cursor += read as u64;
+        Ok(read)
+    }
+}
+
+impl Iterator for FileIterator {
+    type Item = io::Result<File>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let address = self.address?;
+        if self.remaining == 0 {
+            self.address = None;
+            return Some(invalid_data("the sibling chain loops"));
+        }
+        self.remaining -= 1;
+        let this_file = File::load(&self.disk, address);
+        self.address = match &this_file {
+            Ok(file) if file.meta.next_sibling != 0 => Some(file.meta.next_sibling),
+            _ => None,
+        };
+        Some(this_file)
+    }
+}
@@ -1,26 +1,101 @@
 use crate::disk::dump::DumpToFixedLocation;
-use crate::disk::Disk;
-use crate::file::dump_file_attr::FileAttrDump;
+use crate::disk::encode::{invalid_data, Decode, Decoder, Encode, Encoder};
+use crate::disk::{checked_offset, Disk};
+use crate::file::dump_file_attr::{FileAttrDump, FileTypeDump};
+use crate::file::xattr::Xattrs;
 use crate::util::align;
-use bincode::{serialized_size, Error};
-use fuse::FileType;
-use serde::{Deserialize, Serialize};
 use std::borrow::Borrow;
-use std::cmp::max;
+use std::cmp::{max, min};
+use std::ffi::{OsStr, OsString};
 use std::io;
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::time::SystemTime;
 
 pub mod dump_file_attr;
+pub mod legacy;
+pub mod xattr;
 
-#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+/// Every node takes exactly one 512-byte block, its content lives in a separate data extent.
+pub const NODE_SIZE: u64 = 512;
+/// Bytes reserved for the name inside a node.
+pub const NAME_CAPACITY: usize = 255;
+/// Where the extended attribute extent is recorded inside a node, after the room for the name.
+const XATTR_OFFSET: usize = 141 + NAME_CAPACITY;
+
+#[derive(Debug, Clone, Default)]
 pub struct FileMeta {
     pub first_child: u64,
     pub next_sibling: u64,
+    /// Start of the content, `0` while no extent is allocated.
+    pub data_address: u64,
+    /// Bytes reserved at `data_address`, always a multiple of 512.
+    pub data_capacity: u64,
     pub file_attr: FileAttrDump,
-    pub filename: String,
+    /// Project quota the node is accounted to, `0` when it belongs to no project.
+    pub project_id: u32,
+    /// Bumped every time the inode number is reused, so file handles of a deleted node go stale.
+    pub generation: u64,
+    pub filename: OsString,
+    /// Start of the extended attributes, `0` when the node has none.
+    pub xattr_address: u64,
+    /// Bytes reserved at `xattr_address`, always a multiple of 512.
+    pub xattr_capacity: u64,
+}
+
+impl Encode for FileMeta {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.u64(self.first_child);
+        encoder.u64(self.next_sibling);
+        encoder.u64(self.data_address);
+        encoder.u64(self.data_capacity);
+        self.file_attr.encode(encoder);
+        encoder.u32(self.project_id);
+        encoder.u64(self.generation);
+        // `File::sync` refuses longer names before they get here
+        let name = &self.filename.as_bytes()[..min(self.filename.len(), NAME_CAPACITY)];
+        encoder.u16(name.len() as u16);
+        encoder.bytes(name);
+        encoder.pad_to(XATTR_OFFSET);
+        encoder.u64(self.xattr_address);
+        encoder.u64(self.xattr_capacity);
+        encoder.pad_to(NODE_SIZE as usize);
+    }
+}
+
+impl Decode for FileMeta {
+    fn decode<R: Read>(decoder: &mut Decoder<R>) -> io::Result<Self> {
+        let first_child = decoder.u64()?;
+        let next_sibling = decoder.u64()?;
+        let data_address = decoder.u64()?;
+        let data_capacity = decoder.u64()?;
+        let file_attr = FileAttrDump::decode(decoder)?;
+        let project_id = decoder.u32()?;
+        let generation = decoder.u64()?;
+        let name_length = decoder.u16()? as usize;
+        if name_length > NAME_CAPACITY {
+            return invalid_data("name length exceeds the node");
+        }
+        let filename = OsString::from_vec(decoder.bytes(name_length)?);
+        decoder.bytes(NAME_CAPACITY - name_length)?;
+        let xattr_address = decoder.u64()?;
+        let xattr_capacity = decoder.u64()?;
+        Ok(FileMeta {
+            first_child,
+            next_sibling,
+            data_address,
+            data_capacity,
+            file_attr,
+            project_id,
+            generation,
+            filename,
+            xattr_address,
+            xattr_capacity,
+        })
+    }
 }
 
+#[derive(Clone)]
 pub struct File {
     address: u64,
     cursor: u64,
@@ -30,6 +105,8 @@ pub struct File {
 
 pub struct FileIterator {
     address: Option<u64>,
+    /// Nodes the image has room for, a longer chain must loop.
+    remaining: u64,
     disk: Disk,
 }
 
@@ -42,7 +119,7 @@ impl DumpToFixedLocation<FileMeta> for File {
         self.address
     }
 
-    fn load(disk: &Disk, address: u64) -> Result<Self, Error> {
+    fn load(disk: &Disk, address: u64) -> io::Result<Self> {
         disk.load_at(address).map(|meta| File {
             meta,
             address,
@@ -50,64 +127,99 @@ impl DumpToFixedLocation<FileMeta> for File {
             disk: disk.clone(),
         })
     }
+
+    /// Fails with `ENAMETOOLONG` rather than writing a name the node has no room for.
+    fn sync(&self, disk: &Disk) -> io::Result<()> {
+        if self.meta.filename.len() > NAME_CAPACITY {
+            return Err(io::Error::from_raw_os_error(libc::ENAMETOOLONG));
+        }
+        disk.dump_fixed_location(self)
+    }
 }
 
 impl Seek for File {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        match pos {
-            SeekFrom::Start(address) => self.cursor = address,
-            SeekFrom::Current(offset) => self.cursor = (self.address as i64 + offset) as _,
-            SeekFrom::End(offset) => self.cursor = (self.meta.file_attr.size as i64 + offset) as _,
+        let cursor = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => checked_offset(self.cursor, offset),
+            SeekFrom::End(offset) => checked_offset(self.meta.file_attr.size, offset),
         };
+        self.cursor = cursor
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "seek to a negative offset"))?;
         Ok(self.cursor)
     }
 }
 
 impl Read for File {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.meta.file_attr.size.saturating_sub(self.cursor);
+        let length = min(buf.len() as u64, remaining) as usize;
+        if length == 0 {
+            return Ok(0);
+        }
         self.disk
-            .seek(SeekFrom::Start(
-                self.address + serialized_size(&self.meta).unwrap() + self.cursor,
-            ))
-            .unwrap();
-        self.disk.read(buf)
+            .seek(SeekFrom::Start(self.meta.data_address + self.cursor))?;
+        let read = self.disk.read(&mut buf[..length])?;
+        self.cursor += read as u64;
+        Ok(read)
     }
 }
 
 impl Write for File {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let room = self.meta.data_capacity.saturating_sub(self.cursor);
+        let length = min(buf.len() as u64, room) as usize;
+        if length == 0 && !buf.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::Other,
+                "no room left in the data extent",
+            ));
+        }
         self.disk
-            .seek(SeekFrom::Start(
-                self.address + serialized_size(&self.meta).unwrap() + self.cursor,
-            ))
-            .unwrap();
-        self.cursor += buf.len() as u64;
+            .seek(SeekFrom::Start(self.meta.data_address + self.cursor))?;
+        let written = self.disk.write(&buf[..length])?;
+        self.cursor += written as u64;
         self.meta.file_attr.size = max(self.cursor, self.meta.file_attr.size);
-        self.sync(&self.disk);
-        self.disk.write(buf)
+        self.sync(&self.disk)?;
+        Ok(written)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.disk.dump_fixed_location(self);
+        self.sync(&self.disk)?;
         self.disk.flush()
     }
 }
 
-impl<'a> Iterator for FileIterator {
-    type Item = File;
+impl FileIterator {
+    fn new(disk: &Disk, address: u64) -> Self {
+        FileIterator {
+            address: if address == 0 { None } else { Some(address) },
+            remaining: disk.len().unwrap_or(0) / NODE_SIZE,
+            disk: disk.clone(),
+        }
+    }
+}
+
+/// Yields an error and stops at the first node that cannot be loaded.
+impl Iterator for FileIterator {
+    type Item = io::Result<File>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some(address) = self.address {
-            let this_file = File::load(&self.disk, address).unwrap();
-            self.address = if this_file.meta.next_sibling == 0 {
-                None
-            } else {
-                Some(this_file.meta.next_sibling)
-            };
-            Some(this_file)
-        } else {
-            None
+        let address = self.address.take()?;
+        if address % NODE_SIZE != 0 {
+            return Some(invalid_data("link to a misaligned node"));
+        }
+        if self.remaining == 0 {
+            return Some(invalid_data("the sibling chain loops"));
+        }
+        self.remaining -= 1;
+        let file = File::load(&self.disk, address);
+        if let Ok(file) = &file {
+            if file.meta.next_sibling != 0 {
+                self.address = Some(file.meta.next_sibling);
+            }
         }
+        Some(file)
     }
 }
 
@@ -122,11 +234,14 @@ impl FileBuilder {
         FileBuilder {
             disk: disk.clone(),
             address,
-            meta: FileMeta::default(),
+            meta: FileMeta {
+                generation: 1,
+                ..FileMeta::default()
+            },
         }
     }
-    pub fn filename(mut self, filename: &str) -> Self {
-        self.meta.filename = filename.to_string();
+    pub fn filename<S: AsRef<OsStr>>(mut self, filename: S) -> Self {
+        self.meta.filename = filename.as_ref().to_os_string();
         self
     }
     pub fn first_child(mut self, address: u64) -> Self {
@@ -145,8 +260,33 @@ impl FileBuilder {
         self.meta.file_attr.size = size;
         self
     }
-    pub fn kind(mut self, kind: FileType) -> Self {
-        self.meta.file_attr.kind = kind.into();
+    pub fn kind(mut self, kind: FileTypeDump) -> Self {
+        self.meta.file_attr.kind = kind;
+        self
+    }
+    pub fn generation(mut self, generation: u64) -> Self {
+        self.meta.generation = generation;
+        self
+    }
+    pub fn project_id(mut self, project_id: u32) -> Self {
+        self.meta.project_id = project_id;
+        self
+    }
+    pub fn uid(mut self, uid: u32) -> Self {
+        self.meta.file_attr.uid = uid;
+        self
+    }
+    pub fn gid(mut self, gid: u32) -> Self {
+        self.meta.file_attr.gid = gid;
+        self
+    }
+    pub fn perm(mut self, perm: u16) -> Self {
+        self.meta.file_attr.perm = perm;
+        self
+    }
+    pub fn data(mut self, address: u64, capacity: u64) -> Self {
+        self.meta.data_address = address;
+        self.meta.data_capacity = capacity;
         self
     }
     pub fn build(&self) -> File {
@@ -156,8 +296,7 @@ impl FileBuilder {
             meta: self.meta.clone(),
             disk: self.disk.clone(),
         };
-        let size = align(file.dump_size() + file.meta.file_attr.size, 512);
-        file.meta.file_attr.blocks = size / 512;
+        file.meta.file_attr.blocks = file.allocated_blocks();
         file.meta.file_attr.crtime = SystemTime::now();
         file.meta.file_attr.ctime = SystemTime::now();
         file.meta.file_attr.mtime = SystemTime::now();
@@ -167,25 +306,121 @@ impl FileBuilder {
 }
 
 impl File {
-    pub fn children(&self) -> FileIterator {
-        FileIterator {
-            address: if self.meta.first_child == 0 {
-                None
-            } else {
-                Some(self.meta.first_child)
-            },
-            disk: self.disk.clone(),
+    /// Number of 512-byte blocks taken by the node, its data and its extended attributes.
+    pub fn allocated_blocks(&self) -> u64 {
+        (NODE_SIZE + self.meta.data_capacity + self.meta.xattr_capacity) / 512
+    }
+    pub fn xattrs(&self) -> io::Result<Xattrs> {
+        if self.meta.xattr_capacity == 0 {
+            Ok(Xattrs::default())
+        } else {
+            self.disk.load_at(self.meta.xattr_address)
+        }
+    }
+    /// Writes `buf` at `offset` into the data extent without touching the node,
+    /// the caller records the new size.
+    pub fn write_data(&self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        if offset + buf.len() as u64 > self.meta.data_capacity {
+            return Err(io::Error::new(
+                ErrorKind::Other,
+                "no room left in the data extent",
+            ));
         }
+        let mut disk = self.disk.clone();
+        disk.seek(SeekFrom::Start(self.meta.data_address + offset))?;
+        disk.write_all(buf)
+    }
+    /// Makes the data extent `capacity` bytes long in place, the caller made sure the space after
+    /// it is free.
+    pub fn extend_data(&mut self, capacity: u64) -> io::Result<()> {
+        self.meta.data_capacity = capacity;
+        self.meta.file_attr.blocks = self.allocated_blocks();
+        self.sync(&self.disk)
+    }
+    /// Copies the content into a new data extent and returns the old `(address, capacity)`.
+    pub fn move_data(&mut self, address: u64, capacity: u64) -> io::Result<(u64, u64)> {
+        assert!(capacity >= self.meta.file_attr.size);
+        if self.meta.file_attr.size != 0 {
+            let mut content = vec![0u8; self.meta.file_attr.size as usize];
+            self.disk.seek(SeekFrom::Start(self.meta.data_address))?;
+            self.disk.read_exact(&mut content)?;
+            self.disk.seek(SeekFrom::Start(address))?;
+            self.disk.write_all(&content)?;
+        }
+        let old = (self.meta.data_address, self.meta.data_capacity);
+        self.meta.data_address = address;
+        self.meta.data_capacity = capacity;
+        self.meta.file_attr.blocks = self.allocated_blocks();
+        self.sync(&self.disk)?;
+        Ok(old)
+    }
+    /// Writes `xattrs` into the extent of `capacity` bytes at `address` and records it in the node,
+    /// an empty set takes no extent. Returns the `(address, length)` of the old extent.
+    pub fn move_xattrs(
+        &mut self,
+        xattrs: &Xattrs,
+        address: u64,
+        capacity: u64,
+    ) -> io::Result<(u64, u64)> {
+        if capacity != 0 {
+            let mut encoder = Encoder::default();
+            xattrs.encode(&mut encoder);
+            encoder.pad_to(capacity as usize);
+            self.disk.seek(SeekFrom::Start(address))?;
+            self.disk.write_all(&encoder.into_inner())?;
+        }
+        let old = (self.meta.xattr_address, self.meta.xattr_capacity);
+        self.meta.xattr_address = address;
+        self.meta.xattr_capacity = capacity;
+        self.meta.file_attr.blocks = self.allocated_blocks();
+        self.sync(&self.disk)?;
+        Ok(old)
+    }
+    /// Truncates or extends the content to `size` bytes, which must fit into the data extent.
+    /// Shrinking zeroes what is cut off in the last block, so it does not show up again when the
+    /// file grows, and gives up the whole blocks after it. Returns the `(address, length)` given up.
+    pub fn set_len(&mut self, size: u64) -> io::Result<(u64, u64)> {
+        assert!(size <= self.meta.data_capacity);
+        let mut released = (0, 0);
+        if size < self.meta.file_attr.size {
+            let capacity = align(size, 512);
+            let tail = min(capacity, self.meta.file_attr.size) - size;
+            self.disk
+                .seek(SeekFrom::Start(self.meta.data_address + size))?;
+            self.disk.write_all(&vec![0u8; tail as usize])?;
+            released = (
+                self.meta.data_address + capacity,
+                self.meta.data_capacity - capacity,
+            );
+            self.meta.data_capacity = capacity;
+            if capacity == 0 {
+                self.meta.data_address = 0;
+            }
+        }
+        self.meta.file_attr.size = size;
+        self.meta.file_attr.blocks = self.allocated_blocks();
+        self.sync(&self.disk)?;
+        Ok(released)
+    }
+    /// Iterates the nodes linked through `next_sibling`, starting with the one at `address`.
+    pub fn chain(disk: &Disk, address: u64) -> FileIterator {
+        FileIterator::new(disk, address)
+    }
+    pub fn children(&self) -> FileIterator {
+        FileIterator::new(&self.disk, self.meta.first_child)
     }
     pub fn siblings(&self) -> FileIterator {
-        FileIterator {
-            address: if self.meta.next_sibling == 0 {
-                None
-            } else {
-                Some(self.meta.first_child)
-            },
-            disk: self.disk.clone(),
+        FileIterator::new(&self.disk, self.meta.next_sibling)
+    }
+    /// The child called `name`.
+    pub fn child(&self, name: &OsStr) -> io::Result<Option<File>> {
+        for child in self.children() {
+            let child = child?;
+            if child.meta.filename == name {
+                return Ok(Some(child));
+            }
         }
+        Ok(None)
     }
 }
 
@@ -194,7 +429,7 @@ fn prepare_test_data() -> io::Result<Disk> {
     use tempfile::tempdir;
     let tempdir = tempdir()?;
     let file_path = tempdir.path().join("temp.img");
-    let disk = Disk::new(&file_path);
+    let disk = Disk::new(&file_path)?;
 
     let mut root = FileBuilder::new(&disk, 512)
         .ino(1)
@@ -218,12 +453,13 @@ fn prepare_test_data() -> io::Result<Disk> {
     let mut file2 = FileBuilder::new(&disk, 2560)
         .ino(5)
         .filename("file2.txt")
+        .data(3072, 512)
         .build();
-    root.flush().unwrap();
-    dir1.flush().unwrap();
-    dir2.flush().unwrap();
-    file1.flush().unwrap();
-    file2.flush().unwrap();
+    root.flush()?;
+    dir1.flush()?;
+    dir2.flush()?;
+    file1.flush()?;
+    file2.flush()?;
     Ok(disk)
 }
 
@@ -231,10 +467,13 @@ fn prepare_test_data() -> io::Result<Disk> {
 fn test_file() {
     let disk = prepare_test_data().unwrap();
     let root = File::load(&disk, 512).unwrap();
-    let children: Vec<_> = root.children().collect();
+    let children = root.children().collect::<io::Result<Vec<_>>>().unwrap();
     assert_eq!(children.len(), 3);
     assert_eq!(children[2].meta.filename, "file1.txt");
-    let mut children: Vec<_> = children[0].children().collect();
+    let mut children = children[0]
+        .children()
+        .collect::<io::Result<Vec<_>>>()
+        .unwrap();
     assert_eq!(children.len(), 1);
     assert_eq!(children[0].meta.filename, "file2.txt");
     children[0].write_all(b"hello world").unwrap();
@@ -242,4 +481,58 @@ fn test_file() {
     children[0].seek(SeekFrom::Start(6)).unwrap();
     children[0].read_exact(&mut buffer).unwrap();
     assert_eq!(buffer[0], b'w');
+    assert_eq!(children[0].read(&mut buffer).unwrap(), 0);
+    assert_eq!(children[0].meta.file_attr.blocks, 2);
+    children[0].move_data(4096, 1024).unwrap();
+    assert_eq!(children[0].set_len(5).unwrap(), (4096 + 512, 512));
+    let file2 = File::load(&disk, 2560).unwrap();
+    assert_eq!(file2.meta.data_address, 4096);
+    assert_eq!(file2.meta.file_attr.size, 5);
+    assert_eq!(file2.meta.file_attr.blocks, 2);
+    let mut content = vec![];
+    children[0].seek(SeekFrom::Start(0)).unwrap();
+    children[0].read_to_end(&mut content).unwrap();
+    assert_eq!(content, b"hello");
+    assert_eq!(file2.dump_size(), NODE_SIZE);
+}
+
+#[test]
+fn test_non_utf8_filename() {
+    use std::os::unix::ffi::OsStrExt;
+    let disk = prepare_test_data().unwrap();
+    let name = OsStr::from_bytes(b"caf\xe9.txt");
+    let mut file = File::load(&disk, 2048).unwrap();
+    file.meta.filename = name.to_os_string();
+    file.sync(&disk).unwrap();
+    let file = File::load(&disk, 2048).unwrap();
+    assert_eq!(file.meta.filename, name);
+    let root = File::load(&disk, 512).unwrap();
+    assert!(root.child(name).unwrap().is_some());
+
+    // a sibling link back to the first child must not hang the walk
+    let mut file1 = File::load(&disk, 2048).unwrap();
+    file1.meta.next_sibling = 1024;
+    file1.sync(&disk).unwrap();
+    assert!(root.children().any(|it| it.is_err()));
+    assert!(root.child(OsStr::new("missing")).is_err());
+}
+
+#[test]
+fn test_node_io() {
+    use crate::disk::device::Memory;
+    use crate::disk::fault::{Fault, FaultRule, FaultSchedule, Faulty, Op};
+    let schedule = FaultSchedule::new(0);
+    let mut disk = Disk::with_device(Faulty::new(Memory::default(), schedule.clone()));
+    disk.write_all(&[0u8; 4096]).unwrap();
+    let mut file = FileBuilder::new(&disk, 512).filename("node").build();
+    file.sync(&disk).unwrap();
+
+    // loading a node reads that node only
+    schedule.inject(FaultRule::new(Op::Read, Fault::Error).within(1024, 3072));
+    assert_eq!(File::load(&disk, 512).unwrap().meta.filename, "node");
+
+    file.meta.filename = OsString::from("x".repeat(NAME_CAPACITY + 1));
+    let error = file.sync(&disk).unwrap_err();
+    assert_eq!(error.raw_os_error(), Some(libc::ENAMETOOLONG));
+    assert_eq!(File::load(&disk, 512).unwrap().meta.filename, "node");
 }
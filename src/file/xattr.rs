@@ -0,0 +1,127 @@
+//! Extended attributes of a node, kept in an extent of their own.
+
+use crate::disk::encode::{invalid_data, Decode, Decoder, Encode, Encoder};
+use libc::{E2BIG, ERANGE};
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::io::Read;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+/// Longest attribute name, like Linux's `XATTR_NAME_MAX`.
+pub const XATTR_NAME_MAX: usize = 255;
+/// Largest attribute value, like Linux's `XATTR_SIZE_MAX`.
+pub const XATTR_SIZE_MAX: usize = 65536;
+
+/// Name and value pairs sorted by name, so the same attributes always encode the same.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Xattrs {
+    entries: Vec<(OsString, Vec<u8>)>,
+}
+
+impl Xattrs {
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    pub fn get(&self, name: &OsStr) -> Option<&[u8]> {
+        self.entries
+            .iter()
+            .find(|it| it.0 == name)
+            .map(|it| &it.1[..])
+    }
+    /// Adds `name` or replaces its value.
+    pub fn set(&mut self, name: &OsStr, value: &[u8]) -> io::Result<()> {
+        if name.is_empty() || name.len() > XATTR_NAME_MAX {
+            return Err(io::Error::from_raw_os_error(ERANGE));
+        } else if value.len() > XATTR_SIZE_MAX {
+            return Err(io::Error::from_raw_os_error(E2BIG));
+        }
+        match self
+            .entries
+            .binary_search_by(|it| it.0.as_os_str().cmp(name))
+        {
+            Ok(i) => self.entries[i].1 = value.to_vec(),
+            Err(i) => self
+                .entries
+                .insert(i, (name.to_os_string(), value.to_vec())),
+        }
+        Ok(())
+    }
+    /// Drops `name`, `false` if there is no such attribute.
+    pub fn remove(&mut self, name: &OsStr) -> bool {
+        let length = self.entries.len();
+        self.entries.retain(|it| it.0 != name);
+        self.entries.len() != length
+    }
+    /// The names each followed by a NUL, the way `listxattr` returns them.
+    pub fn names(&self) -> Vec<u8> {
+        let mut names = vec![];
+        for (name, _) in &self.entries {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        names
+    }
+}
+
+impl Encode for Xattrs {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.u32(self.entries.len() as u32);
+        for (name, value) in &self.entries {
+            encoder.u8(name.len() as u8);
+            encoder.u32(value.len() as u32);
+            encoder.bytes(name.as_bytes());
+            encoder.bytes(value);
+        }
+    }
+}
+
+impl Decode for Xattrs {
+    fn decode<R: Read>(decoder: &mut Decoder<R>) -> io::Result<Self> {
+        let count = decoder.u32()?;
+        let mut xattrs = Xattrs::default();
+        for _ in 0..count {
+            let name_length = decoder.u8()? as usize;
+            let value_length = decoder.u32()? as usize;
+            if name_length == 0 || value_length > XATTR_SIZE_MAX {
+                return invalid_data("bad extended attribute");
+            }
+            let name = OsString::from_vec(decoder.bytes(name_length)?);
+            let value = decoder.bytes(value_length)?;
+            xattrs.set(&name, &value)?;
+        }
+        Ok(xattrs)
+    }
+}
+
+#[test]
+fn test_xattrs() -> io::Result<()> {
+    let mut xattrs = Xattrs::default();
+    xattrs.set(OsStr::new("user.b"), b"2")?;
+    xattrs.set(OsStr::new("user.a"), b"")?;
+    xattrs.set(OsStr::new("user.b"), b"two")?;
+    assert_eq!(xattrs.names(), b"user.a\0user.b\0");
+    xattrs.set(OsStr::new("user.d"), b"4")?;
+    assert!(xattrs.remove(OsStr::new("user.d")));
+    assert!(!xattrs.remove(OsStr::new("user.d")));
+    assert_eq!(
+        xattrs.set(OsStr::new(""), b"x").unwrap_err().raw_os_error(),
+        Some(ERANGE)
+    );
+    let big = vec![0u8; XATTR_SIZE_MAX + 1];
+    assert_eq!(
+        xattrs
+            .set(OsStr::new("user.c"), &big)
+            .unwrap_err()
+            .raw_os_error(),
+        Some(E2BIG)
+    );
+    let mut encoder = Encoder::default();
+    xattrs.encode(&mut encoder);
+    let encoded = encoder.into_inner();
+    assert_eq!(encoded.len() as u64, xattrs.encoded_size());
+    let decoded = Xattrs::decode(&mut Decoder::new(&encoded[..]))?;
+    assert_eq!(decoded, xattrs);
+    assert_eq!(decoded.get(OsStr::new("user.b")), Some(&b"two"[..]));
+    assert_eq!(decoded.get(OsStr::new("user.c")), None);
+    Ok(())
+}
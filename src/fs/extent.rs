@@ -0,0 +1,95 @@
+use crate::util::align;
+use std::collections::BTreeMap;
+
+/// Ranges below the next free address that no node uses, by address. Kept in memory only, they
+/// are found again from the gaps between the extents in use on mount.
+#[derive(Debug, Clone, Default)]
+pub struct FreeExtents {
+    extents: BTreeMap<u64, u64>,
+    total: u64,
+}
+
+impl FreeExtents {
+    /// Free bytes over all extents.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+    /// Gives `length` bytes at `address` back, merging them with the free neighbours.
+    pub fn insert(&mut self, mut address: u64, mut length: u64) {
+        if length == 0 {
+            return;
+        }
+        self.total += length;
+        if let Some((&before, &before_length)) = self.extents.range(..address).next_back() {
+            if before + before_length == address {
+                self.extents.remove(&before);
+                address = before;
+                length += before_length;
+            }
+        }
+        if let Some(after_length) = self.extents.remove(&(address + length)) {
+            length += after_length;
+        }
+        self.extents.insert(address, length);
+    }
+    /// Takes `length` bytes starting at a multiple of `alignment` from the first extent they fit
+    /// into.
+    pub fn take(&mut self, length: u64, alignment: u64) -> Option<u64> {
+        let address = self.extents.iter().find_map(|(&start, &extent_length)| {
+            let address = align(start, alignment);
+            if address + length <= start + extent_length {
+                Some(address)
+            } else {
+                None
+            }
+        })?;
+        self.take_at(address, length);
+        Some(address)
+    }
+    /// Takes the `length` bytes at `address` if they are all free.
+    pub fn take_at(&mut self, address: u64, length: u64) -> bool {
+        let (start, extent_length) = match self.extents.range(..=address).next_back() {
+            Some((&start, &extent_length)) if address + length <= start + extent_length => {
+                (start, extent_length)
+            }
+            _ => return false,
+        };
+        self.extents.remove(&start);
+        self.total -= extent_length;
+        self.insert(start, address - start);
+        self.insert(address + length, start + extent_length - address - length);
+        true
+    }
+    /// Takes the extent that ends at `end`, returns where it started.
+    pub fn take_last(&mut self, end: u64) -> Option<u64> {
+        let (&start, &length) = self.extents.range(..end).next_back()?;
+        if start + length != end {
+            return None;
+        }
+        self.extents.remove(&start);
+        self.total -= length;
+        Some(start)
+    }
+}
+
+#[test]
+fn test_free_extents() {
+    let mut free = FreeExtents::default();
+    free.insert(1024, 512);
+    free.insert(2048, 1024);
+    free.insert(1536, 512);
+    assert_eq!(free.total(), 2048);
+    assert_eq!(free.extents.len(), 1);
+    assert_eq!(free.take(4096, 512), None);
+    assert_eq!(free.take(512, 1024), Some(1024));
+    assert_eq!(free.take(1024, 1024), Some(2048));
+    assert_eq!(free.total(), 512);
+    assert!(!free.take_at(1024, 512));
+    assert!(free.take_at(1536, 512));
+    assert_eq!(free.total(), 0);
+    free.insert(4096, 512);
+    free.insert(5120, 512);
+    assert_eq!(free.take_last(4096), None);
+    assert_eq!(free.take_last(5632), Some(5120));
+    assert_eq!(free.total(), 512);
+}
@@ -0,0 +1,266 @@
+use crate::disk::dump::DumpToFixedLocation;
+use crate::file::{FileBuilder, NODE_SIZE};
+use crate::fs::extent::FreeExtents;
+use crate::fs::inode::FreeInodes;
+use crate::fs::meta::{
+    DumbFsMeta, DEFAULT_BLOCK_SIZE, DEFAULT_FEATURES, FEATURE_QUOTA, KNOWN_FEATURES,
+    LABEL_CAPACITY, MAGIC,
+};
+use crate::fs::quota::{owners, QuotaTable};
+use crate::fs::DumbFS;
+use crate::util::random_uuid;
+use std::io;
+use std::io::ErrorKind;
+
+/// Names of the features `mkfs.dumbfs -O` takes.
+const FEATURES: &[(&str, u32)] = &[("quota", FEATURE_QUOTA)];
+
+/// Parameters of a new filesystem, the defaults are what a mount of an empty image picks.
+#[derive(Debug, Clone)]
+pub struct FormatOptions {
+    /// Capacity in bytes, the size of the first device when unset. An image of size `0` grows
+    /// with use.
+    pub size: Option<u64>,
+    /// Unit data extents are allocated in, a power of two from 512 to 65536.
+    pub block_size: u32,
+    pub label: Vec<u8>,
+    /// A random one when unset.
+    pub uuid: Option<[u8; 16]>,
+    /// Inode numbers after the root's that are never handed out.
+    pub reserved_inodes: u64,
+    pub features: u32,
+    pub root_uid: u32,
+    pub root_gid: u32,
+    pub root_mode: u16,
+    /// Replace a filesystem the image already holds.
+    pub force: bool,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        FormatOptions {
+            size: None,
+            block_size: DEFAULT_BLOCK_SIZE,
+            label: vec![],
+            uuid: None,
+            reserved_inodes: 0,
+            features: DEFAULT_FEATURES,
+            root_uid: 0,
+            root_gid: 0,
+            root_mode: 0o777,
+            force: false,
+        }
+    }
+}
+
+impl FormatOptions {
+    fn check(&self) -> io::Result<()> {
+        let invalid = |message: String| Err(io::Error::new(ErrorKind::InvalidInput, message));
+        if !self.block_size.is_power_of_two() || self.block_size < 512 || self.block_size > 65536 {
+            invalid(format!("bad block size {}", self.block_size))
+        } else if self.label.len() > LABEL_CAPACITY || self.label.contains(&0) {
+            invalid(format!("labels take up to {} bytes", LABEL_CAPACITY))
+        } else if self.features & !KNOWN_FEATURES != 0 {
+            invalid(format!("unknown features {:#x}", self.features))
+        } else if self.root_mode > 0o7777 {
+            invalid(format!("bad mode {:o}", self.root_mode))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Sets or clears the features named in the comma-separated `list`, a name starting with `^` is
+/// cleared.
+pub fn parse_features(list: &str, mut features: u32) -> Option<u32> {
+    for name in list.split(',') {
+        let (clear, name) = match name {
+            _ if name.starts_with('^') => (true, &name[1..]),
+            _ => (false, name),
+        };
+        let &(_, feature) = FEATURES.iter().find(|it| it.0 == name)?;
+        if clear {
+            features &= !feature;
+        } else {
+            features |= feature;
+        }
+    }
+    Some(features)
+}
+
+/// Reads `8-4-4-4-12` hex digits.
+pub fn parse_uuid(text: &str) -> Option<[u8; 16]> {
+    let groups = text.split('-').map(|it| it.len()).collect::<Vec<_>>();
+    if groups != [8, 4, 4, 4, 12] {
+        return None;
+    }
+    let digits = text.replace('-', "");
+    let mut uuid = [0u8; 16];
+    for (i, byte) in uuid.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(digits.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(uuid)
+}
+
+pub fn format_uuid(uuid: &[u8; 16]) -> String {
+    let hex = uuid
+        .iter()
+        .map(|it| format!("{:02x}", it))
+        .collect::<String>();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+impl DumbFS {
+    /// Capacity in 512-byte blocks, `0` for an image that grows with use.
+    pub fn block_count(&self) -> u64 {
+        self.meta.block_count
+    }
+    pub fn uuid(&self) -> [u8; 16] {
+        self.meta.uuid
+    }
+    /// Writes a new filesystem over the image.
+    pub fn format(&mut self, options: &FormatOptions) -> io::Result<()> {
+        options.check()?;
+        if self.read_only() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "the read-only image holds no filesystem",
+            ));
+        }
+        if !options.force {
+            if let Ok(meta) = DumbFsMeta::load(&self.disk, 0) {
+                if meta.magic == MAGIC || meta.legacy() {
+                    return Err(io::Error::new(
+                        ErrorKind::AlreadyExists,
+                        "the image already holds a dumbfs filesystem",
+                    ));
+                }
+            }
+        }
+        // a new filesystem starts on its first device, the others are added by `grow`
+        let first = self.disk.device_sizes()?[0];
+        let size = match options.size {
+            Some(size) if size / 512 * 512 != first => {
+                self.disk.set_len(size / 512 * 512)?;
+                size / 512 * 512
+            }
+            _ => first,
+        };
+        info!("init filesystem");
+        self.meta = DumbFsMeta::default();
+        self.free_extents = FreeExtents::default();
+        self.meta.block_count = size / 512;
+        self.meta.block_size = options.block_size;
+        self.meta.features = options.features;
+        self.meta.uuid = match options.uuid {
+            Some(uuid) => uuid,
+            None => random_uuid()?,
+        };
+        self.meta.label = options.label.clone();
+        let ino = self.meta.acquire_next_ino();
+        assert_eq!(ino, 1);
+        self.meta
+            .reserve_inos_below(ino + 1 + options.reserved_inodes);
+        let root_address = self.allocate(NODE_SIZE)?;
+        let root_dir = FileBuilder::new(&self.disk, root_address)
+            .ino(ino)
+            .uid(options.root_uid)
+            .gid(options.root_gid)
+            .perm(options.root_mode)
+            .build();
+        self.free_inodes = FreeInodes::new(0);
+        root_dir.sync(&self.disk)?;
+        self.meta.sync(&self.disk)?;
+        self.quota = QuotaTable::new(0);
+        self.quota.account(
+            &owners(&root_dir.meta),
+            root_dir.meta.file_attr.blocks as _,
+            1,
+        );
+        self.sync_quota()
+    }
+}
+
+#[test]
+fn test_format() -> io::Result<()> {
+    use crate::file::dump_file_attr::FileTypeDump;
+    use crate::file::File;
+    use std::ffi::OsStr;
+    use tempfile::tempdir;
+
+    assert_eq!(
+        parse_uuid("0123abcd-4567-89ef-0011-223344556677").map(|it| format_uuid(&it)),
+        Some("0123abcd-4567-89ef-0011-223344556677".to_string())
+    );
+    assert_eq!(parse_uuid("0123abcd-4567-89ef-0011-2233445566"), None);
+    assert_eq!(parse_uuid("0123abcg-4567-89ef-0011-223344556677"), None);
+    assert_eq!(parse_features("^quota", DEFAULT_FEATURES), Some(0));
+    assert_eq!(parse_features("quota", 0), Some(FEATURE_QUOTA));
+    assert_eq!(parse_features("quota,extents", 0), None);
+
+    let tempdir = tempdir()?;
+    let image = tempdir.path().join("image");
+    let options = FormatOptions {
+        size: Some(1 << 20),
+        block_size: 4096,
+        label: b"backup".to_vec(),
+        uuid: parse_uuid("0123abcd-4567-89ef-0011-223344556677"),
+        reserved_inodes: 10,
+        features: 0,
+        root_uid: 1000,
+        root_gid: 100,
+        root_mode: 0o750,
+        force: false,
+    };
+    let mut dumbfs = DumbFS::new(&image)?;
+    dumbfs.format(&options)?;
+    dumbfs.close_filesystem()?;
+
+    let mut dumbfs = DumbFS::new(&image)?;
+    dumbfs.open_filesystem()?;
+    assert_eq!(dumbfs.disk.len()?, 1 << 20);
+    assert_eq!(dumbfs.meta.block_count, 2048);
+    assert_eq!(dumbfs.meta.block_size(), 4096);
+    assert_eq!(dumbfs.meta.label, b"backup");
+    assert_eq!(dumbfs.meta.uuid, options.uuid.unwrap());
+    assert!(!dumbfs.quota_enabled());
+    assert_eq!(dumbfs.meta.quota_address, 0);
+    assert_eq!(dumbfs.acquire_ino()?.0, 12);
+    let root = File::load(&dumbfs.disk, 512)?;
+    assert_eq!(root.meta.file_attr.uid, 1000);
+    assert_eq!(root.meta.file_attr.gid, 100);
+    assert_eq!(root.meta.file_attr.perm, 0o750);
+    let regular = FileTypeDump::RegularFile;
+    let file = dumbfs
+        .make_node(0, 0, 1, OsStr::new("file"), regular)
+        .unwrap();
+    let fh = dumbfs.open_handle(file);
+    let file = dumbfs.prepare_write(0, fh, 100).unwrap();
+    assert_eq!(file.meta.data_address % 4096, 0);
+    assert_eq!(file.meta.data_capacity, 4096);
+
+    // an existing filesystem is only replaced when forced
+    let e = dumbfs.format(&FormatOptions::default()).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::AlreadyExists);
+    let bad = FormatOptions {
+        block_size: 1000,
+        force: true,
+        ..FormatOptions::default()
+    };
+    assert!(dumbfs.format(&bad).is_err());
+    dumbfs.format(&FormatOptions {
+        force: true,
+        ..FormatOptions::default()
+    })?;
+    assert!(dumbfs.quota_enabled());
+    assert_eq!(dumbfs.meta.block_count, 2048);
+    assert_ne!(dumbfs.meta.uuid, options.uuid.unwrap());
+    Ok(())
+}
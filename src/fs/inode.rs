@@ -0,0 +1,95 @@
+use crate::disk::dump::DumpToFixedLocation;
+use crate::disk::encode::{Decode, Decoder, Encode, Encoder};
+use crate::disk::Disk;
+use std::collections::VecDeque;
+use std::io;
+use std::io::Read;
+
+/// Inode numbers of deleted nodes with the generation they had, oldest first.
+#[derive(Debug, Clone, Default)]
+pub struct FreeInodesDump {
+    pub entries: VecDeque<(u64, u64)>,
+}
+
+impl Encode for FreeInodesDump {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.u64(self.entries.len() as u64);
+        for &(ino, generation) in &self.entries {
+            encoder.u64(ino);
+            encoder.u64(generation);
+        }
+    }
+}
+
+impl Decode for FreeInodesDump {
+    fn decode<R: Read>(decoder: &mut Decoder<R>) -> io::Result<Self> {
+        let mut entries = VecDeque::new();
+        for _ in 0..decoder.u64()? {
+            entries.push_back((decoder.u64()?, decoder.u64()?));
+        }
+        Ok(FreeInodesDump { entries })
+    }
+}
+
+pub struct FreeInodes {
+    address: u64,
+    pub inodes: FreeInodesDump,
+}
+
+impl FreeInodes {
+    pub fn new(address: u64) -> Self {
+        FreeInodes {
+            address,
+            inodes: FreeInodesDump::default(),
+        }
+    }
+    pub fn move_to(&mut self, address: u64) {
+        self.address = address;
+    }
+    pub fn len(&self) -> u64 {
+        self.inodes.entries.len() as u64
+    }
+    /// Takes the inode number freed longest ago, together with the generation its next node gets.
+    /// Reusing the oldest one first keeps stale handles to recently deleted nodes around longest.
+    pub fn take(&mut self) -> Option<(u64, u64)> {
+        self.inodes
+            .entries
+            .pop_front()
+            .map(|(ino, generation)| (ino, generation + 1))
+    }
+    pub fn give_back(&mut self, ino: u64, generation: u64) {
+        self.inodes.entries.push_back((ino, generation));
+    }
+}
+
+impl DumpToFixedLocation<FreeInodesDump> for FreeInodes {
+    fn dump_part(&self) -> FreeInodesDump {
+        self.inodes.clone()
+    }
+
+    fn location(&self) -> u64 {
+        self.address
+    }
+
+    fn load(disk: &Disk, address: u64) -> io::Result<Self> {
+        disk.load_at(address)
+            .map(|inodes| FreeInodes { address, inodes })
+    }
+}
+
+#[test]
+fn test_free_inodes() -> io::Result<()> {
+    let disk = Disk::memory();
+    let mut free_inodes = FreeInodes::new(1024);
+    assert_eq!(free_inodes.take(), None);
+    free_inodes.give_back(7, 1);
+    free_inodes.give_back(3, 4);
+    assert_eq!(free_inodes.dump_size(), 8 + 2 * 16);
+    free_inodes.sync(&disk)?;
+    let mut free_inodes = FreeInodes::load(&disk, 1024)?;
+    assert_eq!(free_inodes.len(), 2);
+    assert_eq!(free_inodes.take(), Some((7, 2)));
+    assert_eq!(free_inodes.take(), Some((3, 5)));
+    assert_eq!(free_inodes.take(), None);
+    Ok(())
+}
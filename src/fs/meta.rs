@@ -1,25 +1,86 @@
 use crate::disk::dump::DumpToFixedLocation;
+use crate::disk::encode::{Decode, Decoder, Encode, Encoder};
 use crate::disk::Disk;
-use bincode::Error;
-use serde::{Deserialize, Serialize};
-#[cfg(test)]
+use crate::file::NAME_CAPACITY;
+use std::cmp::{max, min};
 use std::io;
+use std::io::Read;
 
-pub const MAGIC: u32 = 0xAA55_9669;
+/// `DFS2` in ASCII.
+pub const MAGIC: u32 = 0x3253_4644;
+/// Magic of images written with the `bincode` based layout.
+pub const LEGACY_MAGIC: u32 = 0xAA55_9669;
+pub const FORMAT_VERSION: u32 = 3;
+pub const SUPERBLOCK_SIZE: usize = 512;
+pub const DEFAULT_NAME_MAX: u32 = NAME_CAPACITY as u32;
+/// Share of a new filesystem only root can allocate, like ext4 does.
+pub const DEFAULT_RESERVED_PERCENT: u8 = 5;
+pub const DEFAULT_BLOCK_SIZE: u32 = 512;
+pub const LABEL_CAPACITY: usize = 32;
+/// Usage is accounted and limits are kept in the quota table.
+pub const FEATURE_QUOTA: u32 = 1 << 0;
+/// Every feature this version knows, filesystems using others are refused.
+pub const KNOWN_FEATURES: u32 = FEATURE_QUOTA;
+/// Features of a new filesystem, and of those from before features were recorded.
+pub const DEFAULT_FEATURES: u32 = FEATURE_QUOTA;
+/// `DFSD` in ASCII.
+pub const DEVICE_MAGIC: u32 = 0x4453_4644;
+/// Bytes at the start of every device but the first holding its `DeviceLabel`, the first one holds
+/// the superblock there.
+pub const DEVICE_LABEL_SIZE: u64 = 512;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Debug, Clone)]
 pub struct DumbFsMeta {
     pub magic: u32,
+    pub version: u32,
     next_ino: u64,
     pub next_free_address: u64,
+    /// Capacity of the filesystem in 512-byte blocks, `0` for an unsized image that grows with use.
+    pub block_count: u64,
+    pub quota_address: u64,
+    pub quota_capacity: u64,
+    pub free_inodes_address: u64,
+    pub free_inodes_capacity: u64,
+    /// Longest file name accepted, in bytes.
+    pub name_max: u32,
+    /// Cleared while mounted, a filesystem found unclean on mount gets its quota usage recomputed.
+    pub clean: bool,
+    /// First node of the list of unlinked nodes still open, linked through `next_sibling`.
+    pub orphan_head: u64,
+    /// Percentage of `block_count` only root can allocate.
+    pub reserved_percent: u8,
+    /// Devices the filesystem spans, `0` on images older than spanning which have one.
+    pub device_count: u32,
+    /// Unit data extents are allocated in, `0` on images older than it which use 512 bytes.
+    pub block_size: u32,
+    /// Optional features in use, only meaningful with a `block_size`.
+    pub features: u32,
+    pub uuid: [u8; 16],
+    /// At most `LABEL_CAPACITY` bytes.
+    pub label: Vec<u8>,
 }
 
 impl Default for DumbFsMeta {
     fn default() -> Self {
         DumbFsMeta {
-            magic: 0xAA55_9669,
+            magic: MAGIC,
+            version: FORMAT_VERSION,
             next_ino: 1,
             next_free_address: 512,
+            block_count: 0,
+            quota_address: 0,
+            quota_capacity: 0,
+            free_inodes_address: 0,
+            free_inodes_capacity: 0,
+            name_max: DEFAULT_NAME_MAX,
+            clean: true,
+            orphan_head: 0,
+            reserved_percent: DEFAULT_RESERVED_PERCENT,
+            device_count: 1,
+            block_size: DEFAULT_BLOCK_SIZE,
+            features: DEFAULT_FEATURES,
+            uuid: [0; 16],
+            label: vec![],
         }
     }
 }
@@ -30,8 +91,140 @@ impl DumbFsMeta {
         self.next_ino += 1;
         result
     }
+    /// Never hands out inode numbers below `ino`, used when importing existing nodes.
+    pub fn reserve_inos_below(&mut self, ino: u64) {
+        self.next_ino = max(self.next_ino, ino);
+    }
+    pub fn allocated_inos(&self) -> u64 {
+        self.next_ino - 1
+    }
+    pub fn name_max(&self) -> u32 {
+        min(self.name_max, NAME_CAPACITY as u32)
+    }
     pub fn valid(&self) -> bool {
-        self.magic == MAGIC
+        self.magic == MAGIC && self.version == FORMAT_VERSION
+    }
+    pub fn legacy(&self) -> bool {
+        self.magic == LEGACY_MAGIC
+    }
+    pub fn device_count(&self) -> usize {
+        max(self.device_count, 1) as usize
+    }
+    pub fn block_size(&self) -> u64 {
+        if self.block_size == 0 {
+            u64::from(DEFAULT_BLOCK_SIZE)
+        } else {
+            u64::from(self.block_size)
+        }
+    }
+    pub fn features(&self) -> u32 {
+        if self.block_size == 0 {
+            DEFAULT_FEATURES
+        } else {
+            self.features
+        }
+    }
+    pub fn has_feature(&self, feature: u32) -> bool {
+        self.features() & feature != 0
+    }
+    /// Blocks kept free for root.
+    pub fn reserved_blocks(&self) -> u64 {
+        self.block_count * u64::from(self.reserved_percent) / 100
+    }
+}
+
+impl Encode for DumbFsMeta {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.u32(self.magic);
+        encoder.u32(self.version);
+        encoder.u64(self.next_ino);
+        encoder.u64(self.next_free_address);
+        encoder.u64(self.block_count);
+        encoder.u64(self.quota_address);
+        encoder.u64(self.quota_capacity);
+        encoder.u64(self.free_inodes_address);
+        encoder.u64(self.free_inodes_capacity);
+        encoder.u32(self.name_max);
+        encoder.bool(self.clean);
+        encoder.u64(self.orphan_head);
+        encoder.u8(self.reserved_percent);
+        encoder.u32(self.device_count);
+        encoder.u32(self.block_size);
+        encoder.u32(self.features);
+        encoder.bytes(&self.uuid);
+        let label = &self.label[..min(self.label.len(), LABEL_CAPACITY)];
+        encoder.bytes(label);
+        encoder.bytes(&[0; LABEL_CAPACITY][label.len()..]);
+        encoder.pad_to(SUPERBLOCK_SIZE);
+    }
+}
+
+impl Decode for DumbFsMeta {
+    fn decode<R: Read>(decoder: &mut Decoder<R>) -> io::Result<Self> {
+        let mut meta = DumbFsMeta {
+            magic: decoder.u32()?,
+            version: decoder.u32()?,
+            next_ino: decoder.u64()?,
+            next_free_address: decoder.u64()?,
+            block_count: decoder.u64()?,
+            quota_address: decoder.u64()?,
+            quota_capacity: decoder.u64()?,
+            free_inodes_address: decoder.u64()?,
+            free_inodes_capacity: decoder.u64()?,
+            name_max: decoder.u32()?,
+            clean: decoder.bool()?,
+            orphan_head: decoder.u64()?,
+            reserved_percent: decoder.u8()?,
+            device_count: decoder.u32()?,
+            block_size: decoder.u32()?,
+            features: decoder.u32()?,
+            ..DumbFsMeta::default()
+        };
+        meta.uuid.copy_from_slice(&decoder.bytes(16)?);
+        meta.label = decoder.bytes(LABEL_CAPACITY)?;
+        while meta.label.last() == Some(&0) {
+            meta.label.pop();
+        }
+        Ok(meta)
+    }
+}
+
+/// Names the filesystem and position a device after the first belongs to.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeviceLabel {
+    pub magic: u32,
+    pub uuid: [u8; 16],
+    pub index: u32,
+}
+
+impl DeviceLabel {
+    pub fn new(uuid: [u8; 16], index: usize) -> Self {
+        DeviceLabel {
+            magic: DEVICE_MAGIC,
+            uuid,
+            index: index as u32,
+        }
+    }
+}
+
+impl Encode for DeviceLabel {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.u32(self.magic);
+        encoder.bytes(&self.uuid);
+        encoder.u32(self.index);
+        encoder.pad_to(DEVICE_LABEL_SIZE as usize);
+    }
+}
+
+impl Decode for DeviceLabel {
+    fn decode<R: Read>(decoder: &mut Decoder<R>) -> io::Result<Self> {
+        let mut label = DeviceLabel {
+            magic: decoder.u32()?,
+            ..DeviceLabel::default()
+        };
+        label.uuid.copy_from_slice(&decoder.bytes(16)?);
+        label.index = decoder.u32()?;
+        Ok(label)
     }
 }
 
@@ -44,7 +237,7 @@ impl DumpToFixedLocation<DumbFsMeta> for DumbFsMeta {
         0
     }
 
-    fn load(disk: &Disk, address: u64) -> Result<Self, Error> {
+    fn load(disk: &Disk, address: u64) -> io::Result<Self> {
         assert_eq!(address, 0);
         disk.load_at(address)
     }
@@ -55,18 +248,31 @@ fn test_meta() -> io::Result<()> {
     use tempfile::tempdir;
     let tempdir = tempdir()?;
     let file_path = tempdir.path().join("temp.img");
-    let disk = Disk::new(file_path);
+    let disk = Disk::new(file_path)?;
     let new_meta = DumbFsMeta::default();
-    new_meta.sync(&disk);
+    assert_eq!(new_meta.dump_size(), SUPERBLOCK_SIZE as u64);
+    new_meta.sync(&disk)?;
     let mut meta = DumbFsMeta::load(&disk, 0).unwrap();
     assert_eq!(meta.next_free_address, 512);
     meta.next_free_address = 1024;
     assert_eq!(meta.acquire_next_ino(), 1);
     assert_eq!(meta.acquire_next_ino(), 2);
-    meta.sync(&disk);
+    meta.sync(&disk)?;
     let mut meta = DumbFsMeta::load(&disk, 0).unwrap();
     assert!(meta.valid());
     assert_eq!(meta.acquire_next_ino(), 3);
     assert_eq!(meta.next_free_address, 1024);
+    assert_eq!(meta.reserved_percent, DEFAULT_RESERVED_PERCENT);
+    assert_eq!(meta.device_count(), 1);
+    assert_eq!(meta.block_size(), 512);
+    assert!(meta.has_feature(FEATURE_QUOTA));
+    meta.label = b"backup".to_vec();
+    meta.uuid = [7; 16];
+    meta.sync(&disk)?;
+    let mut meta = DumbFsMeta::load(&disk, 0).unwrap();
+    assert_eq!(meta.label, b"backup");
+    assert_eq!(meta.uuid, [7; 16]);
+    meta.block_count = 2000;
+    assert_eq!(meta.reserved_blocks(), 100);
     Ok(())
 }
@@ -1,135 +1,1158 @@
+use crate::disk::concat::{address, split};
 use crate::disk::dump::DumpToFixedLocation;
+use crate::disk::encode::{invalid_data, Encode};
 use crate::disk::Disk;
 use crate::file::dump_file_attr::FileAttrDump;
-use crate::file::{dump_file_attr::FileTypeDump, File, FileBuilder};
-use crate::fs::meta::DumbFsMeta;
+use crate::file::xattr::Xattrs;
+use crate::file::{dump_file_attr::FileTypeDump, File, FileBuilder, NODE_SIZE};
+use crate::fs::extent::FreeExtents;
+use crate::fs::inode::FreeInodes;
+use crate::fs::meta::{
+    DeviceLabel, DumbFsMeta, DEVICE_LABEL_SIZE, DEVICE_MAGIC, FEATURE_QUOTA, FORMAT_VERSION,
+    KNOWN_FEATURES, MAGIC,
+};
+use crate::fs::options::MountOptions;
+use crate::fs::quota::{owners, QuotaTable};
 use crate::util::align;
 use fuse::{
     Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
-    ReplyOpen, ReplyWrite, Request,
+    ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request,
+};
+use libc::{
+    c_int, EBADF, EEXIST, EFBIG, EINVAL, EIO, EISDIR, ENAMETOOLONG, ENODATA, ENOENT, ENOSPC,
+    ENOSYS, ENOTDIR, ENOTEMPTY, EPERM, ERANGE, EROFS, EUCLEAN, O_ACCMODE, O_RDONLY, O_TRUNC,
+    XATTR_CREATE, XATTR_REPLACE,
 };
-use libc::{EINVAL, EIO, ENOENT, ENOSYS, EPERM};
+use std::cmp::{max, min};
 use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
 use std::fs::FileType;
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
+mod extent;
+pub mod format;
+mod inode;
 mod meta;
+pub mod options;
+mod orphan;
+pub mod populate;
+pub mod quota;
+pub mod resize;
+pub mod threaded;
+pub mod upgrade;
 
 const TTL: Duration = Duration::from_secs(1);
 
+/// Capacity of a data extent that has to hold `size` bytes, in whole blocks. It grows
+/// geometrically, so appending does not move the content every time, but by at most 1 MiB per step.
+fn grown_capacity(capacity: u64, size: u64, block_size: u64) -> u64 {
+    align(
+        max(size, min(capacity * 2, capacity + (1 << 20))),
+        block_size,
+    )
+}
+
+/// What mounting an image without a valid superblock fails with.
+fn no_filesystem() -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        "the image holds no dumbfs filesystem, create one with `dumbfs mkfs`",
+    )
+}
+
+/// Rejects names that cannot be stored as a single directory entry.
+fn check_name(name: &OsStr, name_max: u32) -> Result<(), c_int> {
+    let bytes = name.as_bytes();
+    if bytes.len() > name_max as usize {
+        Err(ENAMETOOLONG)
+    } else if bytes.is_empty() || bytes.contains(&b'/') || bytes.contains(&0) {
+        Err(EINVAL)
+    } else {
+        Ok(())
+    }
+}
+
+/// The errno reported for a failed operation on the image. Corrupted structures are `EUCLEAN`.
+fn errno(e: io::Error) -> c_int {
+    warn!("I/O error: {}", e);
+    match e.kind() {
+        ErrorKind::InvalidData | ErrorKind::UnexpectedEof => EUCLEAN,
+        ErrorKind::WriteZero => ENOSPC,
+        _ => e.raw_os_error().unwrap_or(EIO),
+    }
+}
+
+/// Where a write of `length` bytes at `offset` ends, `EFBIG` past the largest file offset.
+fn write_end(offset: i64, length: usize) -> Result<u64, c_int> {
+    if offset < 0 {
+        return Err(EINVAL);
+    }
+    match offset.checked_add(length as i64) {
+        Some(end) => Ok(end as u64),
+        None => Err(EFBIG),
+    }
+}
+
 pub struct DumbFS {
     disk: Disk,
     meta: DumbFsMeta,
+    options: MountOptions,
+    pub quota: QuotaTable,
+    free_inodes: FreeInodes,
+    free_extents: FreeExtents,
     next_file_handler: u64,
     opened_files: HashMap<u64, File>,
+    /// Grow requests handled so far, see `resize::request_grow`.
+    grow_requests: u64,
 }
 
 impl DumbFS {
-    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+    pub fn new<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::with_options(path, MountOptions::default())
+    }
+    pub fn with_options<P: AsRef<Path>>(path: P, options: MountOptions) -> io::Result<Self> {
+        Ok(Self::with_disk(Disk::open(path, &options.disk)?, options))
+    }
+    /// A filesystem on any backing store, see `disk::device`.
+    pub fn with_disk(disk: Disk, options: MountOptions) -> Self {
         DumbFS {
-            disk: Disk::new(path),
+            disk,
             meta: DumbFsMeta::default(),
+            options,
+            quota: QuotaTable::new(0),
+            free_inodes: FreeInodes::new(0),
+            free_extents: FreeExtents::default(),
             next_file_handler: 1,
             opened_files: HashMap::new(),
+            grow_requests: resize::grow_requests(),
         }
     }
-    fn init_filesystem(&mut self) {
-        info!("init filesystem");
-        self.meta = DumbFsMeta::default();
-        let ino = self.meta.acquire_next_ino();
-        assert_eq!(ino, 1);
-        let root_dir = FileBuilder::new(&self.disk, self.meta.next_free_address)
-            .ino(ino)
-            .build();
-        self.meta.next_free_address += 512;
-        root_dir.sync(&self.disk);
-        self.meta.sync(&self.disk);
-    }
-    fn find_file_with_root(&self, ino: u64, root: File) -> Option<File> {
-        let kind = root.meta.file_attr.kind.clone();
-        match kind {
-            FileTypeDump::Directory => {
-                if root.meta.file_attr.ino == ino {
-                    Some(root)
-                } else {
-                    root.children()
-                        .find_map(|it| self.find_file_with_root(ino, it))
+    /// Loads the superblock, refusing images that hold no filesystem this version can mount.
+    pub fn load_superblock(&mut self) -> io::Result<()> {
+        let meta = match DumbFsMeta::load(&self.disk, 0) {
+            Ok(meta) => meta,
+            // too short to hold a superblock
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Err(no_filesystem()),
+            Err(e) => return Err(e),
+        };
+        if meta.legacy() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "the image uses the legacy on-disk layout, convert it with `dumbfs upgrade`",
+            ));
+        } else if meta.magic == MAGIC && meta.version != FORMAT_VERSION {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported on-disk format version {}", meta.version),
+            ));
+        } else if !meta.valid() {
+            return Err(no_filesystem());
+        } else if meta.features() & !KNOWN_FEATURES != 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "the filesystem uses unknown features {:#x}",
+                    meta.features() & !KNOWN_FEATURES
+                ),
+            ));
+        }
+        self.meta = meta;
+        Ok(())
+    }
+    /// Loads the superblock and quota table, refusing images that hold no filesystem.
+    pub fn open_filesystem(&mut self) -> io::Result<()> {
+        self.load_superblock()?;
+        let devices = self.disk.device_sizes()?.len();
+        if devices < self.meta.device_count() {
+            return Err(io::Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "the filesystem spans {} devices, only {} are listed",
+                    self.meta.device_count(),
+                    devices
+                ),
+            ));
+        }
+        self.check_devices()?;
+        self.grow()?;
+        self.free_inodes = if self.meta.free_inodes_address == 0 {
+            FreeInodes::new(0)
+        } else {
+            FreeInodes::load(&self.disk, self.meta.free_inodes_address)?
+        };
+        let quota = if self.meta.quota_address == 0 {
+            None
+        } else {
+            Some(QuotaTable::load(&self.disk, self.meta.quota_address)?)
+        };
+        let stale = match quota {
+            Some(quota) if self.meta.clean => {
+                self.quota = quota;
+                false
+            }
+            Some(quota) => {
+                warn!("filesystem was not cleanly unmounted, recomputing quota usage");
+                self.quota = quota;
+                true
+            }
+            None => {
+                self.quota = QuotaTable::new(0);
+                self.meta.has_feature(FEATURE_QUOTA)
+            }
+        };
+        if self.read_only() {
+            // orphans stay where they are until the next read-write mount
+            if stale {
+                self.count_usage()?;
+            }
+            return self.find_free_extents();
+        }
+        if stale {
+            self.recompute_quota()?;
+        }
+        self.reclaim_orphans()?;
+        self.find_free_extents()?;
+        self.meta.clean = false;
+        self.meta.sync(&self.disk)?;
+        // a crash from here on has to find the image marked as in use
+        self.write_barrier()
+    }
+    /// Writes back what the block cache holds, for updates a crash must neither lose nor see
+    /// out of order.
+    fn write_barrier(&self) -> io::Result<()> {
+        self.disk.clone().flush()
+    }
+    pub fn close_filesystem(&mut self) -> io::Result<()> {
+        if !self.read_only() {
+            self.sync_quota()?;
+            self.meta.clean = true;
+            self.meta.sync(&self.disk)?;
+            self.disk.clone().flush()?;
+        }
+        if let Some(stats) = self.disk.cache_stats() {
+            info!(
+                "block cache: {} hits, {} misses, {} write-backs",
+                stats.hits, stats.misses, stats.write_backs
+            );
+        }
+        Ok(())
+    }
+    /// Reserves `length` bytes and returns their address, reusing freed space before taking more
+    /// at the end of the used area. Fails with `ENOSPC` past the capacity of a sized image.
+    fn allocate(&mut self, length: u64) -> io::Result<u64> {
+        self.allocate_aligned(length, 512)
+    }
+    /// Like `allocate`, for a data extent, which starts on a block boundary.
+    fn allocate_data(&mut self, length: u64) -> io::Result<u64> {
+        self.allocate_aligned(length, self.meta.block_size())
+    }
+    fn allocate_aligned(&mut self, length: u64, alignment: u64) -> io::Result<u64> {
+        self.grow_if_requested()?;
+        if let Some(address) = self.free_extents.take(length, alignment) {
+            return Ok(address);
+        }
+        let address = self.placement(length, alignment)?;
+        let (device, _) = split(self.meta.next_free_address);
+        // what alignment or a device too full to hold the allocation skips stays free
+        let skipped_to = if split(address).0 == device {
+            address
+        } else {
+            self.device_end(device)?
+        };
+        self.free_extents.insert(
+            self.meta.next_free_address,
+            skipped_to - self.meta.next_free_address,
+        );
+        self.meta.next_free_address = address + length;
+        self.meta.sync(&self.disk)?;
+        Ok(address)
+    }
+    /// Gives an extent back for `allocate` to hand out again.
+    fn free_extent(&mut self, address: u64, length: u64) -> io::Result<()> {
+        if length == 0 {
+            return Ok(());
+        }
+        self.discard(address, length);
+        self.free_extents.insert(address, length);
+        if let Some(start) = self.free_extents.take_last(self.meta.next_free_address) {
+            self.meta.next_free_address = start;
+            self.meta.sync(&self.disk)?;
+        }
+        Ok(())
+    }
+    /// Makes the extent that ends at `end` longer by `length` bytes if they are free.
+    fn extend(&mut self, end: u64, length: u64) -> io::Result<bool> {
+        if self.free_extents.take_at(end, length) {
+            return Ok(true);
+        } else if end != self.meta.next_free_address || self.placement(length, 512)? != end {
+            return Ok(false);
+        }
+        self.meta.next_free_address = end + length;
+        self.meta.sync(&self.disk)?;
+        Ok(true)
+    }
+    /// Address just past what `allocate` may hand out on `device`.
+    fn device_end(&self, device: usize) -> io::Result<u64> {
+        Ok(address(device, self.capacities()?[device]))
+    }
+    /// Finds the space below the next free address that nothing uses, see `FreeExtents`.
+    fn find_free_extents(&mut self) -> io::Result<()> {
+        let mut free_extents = FreeExtents::default();
+        let (last_device, _) = split(self.meta.next_free_address);
+        let mut ends = vec![];
+        for device in 0..last_device {
+            ends.push(self.device_end(device)?);
+        }
+        let extents = self.used_extents()?;
+        // a crash may have lost the superblock write that covered the last allocations
+        let end = extents.iter().map(|it| it.0 + it.1).max().unwrap_or(0);
+        if end > self.meta.next_free_address {
+            warn!("extents in use past the next free address {}", end);
+            self.meta.next_free_address = end;
+            for device in ends.len()..split(end).0 {
+                ends.push(self.device_end(device)?);
+            }
+            if !self.read_only() {
+                self.meta.sync(&self.disk)?;
+            }
+        }
+        ends.push(self.meta.next_free_address);
+        let mut extents = extents.into_iter().peekable();
+        let mut free_from = 512;
+        for (device, &end) in ends.iter().enumerate() {
+            if device != 0 {
+                free_from = address(device, 0);
+            }
+            while let Some(&(start, length)) = extents.peek().filter(|it| it.0 < end) {
+                if start > free_from {
+                    free_extents.insert(free_from, start - free_from);
+                }
+                free_from = max(free_from, start + length);
+                extents.next();
+            }
+            if end > free_from {
+                free_extents.insert(free_from, end - free_from);
+            }
+        }
+        self.free_extents = free_extents;
+        Ok(())
+    }
+    /// Where `allocate_aligned` puts the next `length` bytes.
+    fn placement(&self, length: u64, alignment: u64) -> io::Result<u64> {
+        let (mut device, offset) = split(self.meta.next_free_address);
+        let mut offset = align(offset, alignment);
+        if self.meta.block_count == 0 {
+            return Ok(address(device, offset));
+        }
+        let capacities = self.capacities()?;
+        // the rest of a device too full for the allocation stays unused
+        while offset + length > capacities[device] {
+            if device + 1 >= capacities.len() {
+                return Err(io::Error::from_raw_os_error(ENOSPC));
+            }
+            device += 1;
+            offset = DEVICE_LABEL_SIZE;
+        }
+        Ok(address(device, offset))
+    }
+    /// Bytes each device holds, the first one holding what the others leave of `block_count`.
+    fn capacities(&self) -> io::Result<Vec<u64>> {
+        let mut capacities = self.disk.device_sizes()?;
+        capacities.truncate(self.meta.device_count());
+        let others: u64 = capacities[1..].iter().sum();
+        capacities[0] = (self.meta.block_count * 512).saturating_sub(others);
+        Ok(capacities)
+    }
+    /// Bytes allocated, those below the next free address less the free extents.
+    fn used_bytes(&self) -> io::Result<u64> {
+        let (device, offset) = split(self.meta.next_free_address);
+        let below = if device == 0 {
+            offset
+        } else {
+            self.capacities()?[..device].iter().sum::<u64>() + offset
+        };
+        Ok(below - self.free_extents.total())
+    }
+    /// Whether the capacity is fixed, an unsized filesystem grows with its image instead.
+    pub fn sized(&self) -> bool {
+        self.meta.block_count != 0
+    }
+    /// Whether usage is accounted and limits kept, see `FEATURE_QUOTA`.
+    pub fn quota_enabled(&self) -> bool {
+        self.meta.has_feature(FEATURE_QUOTA)
+    }
+    /// Fails unless every device after the first carries the label of this filesystem and its
+    /// position, so a renamed or swapped device is not mistaken for another.
+    fn check_devices(&self) -> io::Result<()> {
+        for device in 1..self.meta.device_count() {
+            let label: DeviceLabel = self.disk.load_at(address(device, 0))?;
+            if label != DeviceLabel::new(self.meta.uuid, device) {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "the device listed at position {} is not that device of the filesystem",
+                        device
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+    /// Labels the devices listed after the ones in use, refusing those of another filesystem.
+    fn label_devices(&self, count: usize) -> io::Result<()> {
+        for device in self.meta.device_count()..count {
+            let label: DeviceLabel = self.disk.load_at(address(device, 0))?;
+            if label.magic == DEVICE_MAGIC {
+                return Err(io::Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("device {} already belongs to a filesystem", device),
+                ));
+            }
+            self.disk.dump_at(
+                address(device, 0),
+                &DeviceLabel::new(self.meta.uuid, device),
+            )?;
+        }
+        // the labels are in place before the superblock counts the devices
+        self.write_barrier()
+    }
+    /// Picks up a backing store that was enlarged and devices that were added since the mount or
+    /// the last grow. Read-only mounts leave added devices alone.
+    pub fn grow(&mut self) -> io::Result<()> {
+        self.disk.refresh_devices()?;
+        let mut sizes = self.disk.device_sizes()?;
+        if self.meta.block_count != 0 && sizes.len() > self.meta.device_count() && !self.read_only()
+        {
+            self.label_devices(sizes.len())?;
+            info!("add {} devices", sizes.len() - self.meta.device_count());
+            self.meta.device_count = sizes.len() as u32;
+        }
+        sizes.truncate(self.meta.device_count());
+        let block_count = sizes.iter().sum::<u64>() / 512;
+        if self.meta.block_count != 0 && block_count > self.meta.block_count {
+            info!(
+                "grow filesystem from {} to {} blocks",
+                self.meta.block_count, block_count
+            );
+            self.meta.block_count = block_count;
+            if !self.read_only() {
+                self.meta.sync(&self.disk)?;
+            }
+        }
+        Ok(())
+    }
+    /// The root directory followed by every orphan, the starting points of a walk over all nodes.
+    fn roots(&self) -> io::Result<Vec<File>> {
+        let mut roots = vec![File::load(&self.disk, 512)?];
+        for orphan in self.orphans() {
+            roots.push(orphan?);
+        }
+        Ok(roots)
+    }
+    /// Walks the whole tree and rebuilds the quota usage counters, keeping the configured limits.
+    pub fn recompute_quota(&mut self) -> io::Result<()> {
+        self.count_usage()?;
+        self.sync_quota()
+    }
+    /// Rebuilds the quota usage counters in memory only.
+    fn count_usage(&mut self) -> io::Result<()> {
+        info!("recompute quota usage");
+        self.quota.reset_usage();
+        let mut pending = self.roots()?;
+        while let Some(file) = pending.pop() {
+            self.quota
+                .account(&owners(&file.meta), file.meta.file_attr.blocks as _, 1);
+            if file.meta.file_attr.kind == FileTypeDump::Directory {
+                for child in file.children() {
+                    pending.push(child?);
                 }
             }
-            FileTypeDump::RegularFile => {
-                if root.meta.file_attr.ino == ino {
-                    Some(root)
+        }
+        Ok(())
+    }
+    /// Writes the quota table back, moving it to a bigger area first if it outgrew its current one.
+    pub fn sync_quota(&mut self) -> io::Result<()> {
+        if !self.quota_enabled() {
+            return Ok(());
+        }
+        let size = self.quota.dump_size();
+        if size > self.meta.quota_capacity {
+            let capacity = align(size * 2, 512);
+            let address = self.allocate(capacity)?;
+            let old = (self.meta.quota_address, self.meta.quota_capacity);
+            self.meta.quota_capacity = capacity;
+            self.meta.quota_address = address;
+            self.quota.move_to(address);
+            self.quota.sync(&self.disk)?;
+            self.meta.sync(&self.disk)?;
+            return self.free_extent(old.0, old.1);
+        }
+        self.quota.sync(&self.disk)
+    }
+    /// Writes the free inode list back, moving it to a bigger area first if it outgrew its current one.
+    fn sync_free_inodes(&mut self) -> io::Result<()> {
+        let size = self.free_inodes.dump_size();
+        if size > self.meta.free_inodes_capacity {
+            let capacity = align(size * 2, 512);
+            let address = self.allocate(capacity)?;
+            let old = (
+                self.meta.free_inodes_address,
+                self.meta.free_inodes_capacity,
+            );
+            self.meta.free_inodes_capacity = capacity;
+            self.meta.free_inodes_address = address;
+            self.free_inodes.move_to(address);
+            self.free_inodes.sync(&self.disk)?;
+            self.meta.sync(&self.disk)?;
+            return self.free_extent(old.0, old.1);
+        }
+        self.free_inodes.sync(&self.disk)
+    }
+    /// Hands out an inode number with its generation, reusing the numbers of deleted nodes.
+    fn acquire_ino(&mut self) -> io::Result<(u64, u64)> {
+        if let Some(reused) = self.free_inodes.take() {
+            self.sync_free_inodes()?;
+            Ok(reused)
+        } else {
+            Ok((self.meta.acquire_next_ino(), 1))
+        }
+    }
+    /// Makes `ino` available again, the next node using it gets a higher generation.
+    fn release_ino(&mut self, ino: u64, generation: u64) -> io::Result<()> {
+        self.free_inodes.give_back(ino, generation);
+        self.sync_free_inodes()
+    }
+    fn read_only(&self) -> bool {
+        self.options.disk.read_only
+    }
+    /// `EROFS` for every operation that changes the image when it is mounted read-only.
+    fn writable(&self) -> Result<(), c_int> {
+        if self.read_only() {
+            Err(EROFS)
+        } else {
+            Ok(())
+        }
+    }
+    fn used_inos(&self) -> u64 {
+        self.meta.allocated_inos() - self.free_inodes.len()
+    }
+    fn find_file_with_root(&self, ino: u64, root: File) -> io::Result<Option<File>> {
+        if root.meta.file_attr.ino == ino {
+            return Ok(Some(root));
+        }
+        if root.meta.file_attr.kind == FileTypeDump::Directory {
+            for child in root.children() {
+                if let Some(found) = self.find_file_with_root(ino, child?)? {
+                    return Ok(Some(found));
+                }
+            }
+        }
+        Ok(None)
+    }
+    /// Resolves a path relative to the root directory.
+    pub fn find_path(&self, path: &Path) -> io::Result<Option<File>> {
+        let mut file = File::load(&self.disk, 512)?;
+        for name in path.iter().filter(|it| *it != "/") {
+            match file.child(name)? {
+                Some(child) => file = child,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(file))
+    }
+    /// Assigns `project_id` to `root` and everything below it, then recomputes usage.
+    pub fn set_project(&mut self, root: File, project_id: u32) -> io::Result<()> {
+        let mut pending = vec![root];
+        while let Some(mut file) = pending.pop() {
+            file.meta.project_id = project_id;
+            file.sync(&self.disk)?;
+            if file.meta.file_attr.kind == FileTypeDump::Directory {
+                for child in file.children() {
+                    pending.push(child?);
+                }
+            }
+        }
+        self.recompute_quota()
+    }
+    /// Keeps `percent` of the capacity for root, like `tune2fs -m`.
+    pub fn set_reserved_percent(&mut self, percent: u8) -> io::Result<()> {
+        self.meta.reserved_percent = percent;
+        self.meta.sync(&self.disk)
+    }
+    /// Every `(address, length)` region in use besides the superblock, device labels included,
+    /// sorted by address.
+    fn used_extents(&self) -> io::Result<Vec<(u64, u64)>> {
+        let mut extents = vec![];
+        for device in 1..self.meta.device_count() {
+            extents.push((address(device, 0), DEVICE_LABEL_SIZE));
+        }
+        if self.meta.quota_address != 0 {
+            extents.push((self.meta.quota_address, self.meta.quota_capacity));
+        }
+        if self.meta.free_inodes_address != 0 {
+            extents.push((
+                self.meta.free_inodes_address,
+                self.meta.free_inodes_capacity,
+            ));
+        }
+        let mut pending = self.roots()?;
+        while let Some(file) = pending.pop() {
+            extents.push((file.location(), NODE_SIZE));
+            if file.meta.data_capacity != 0 {
+                extents.push((file.meta.data_address, file.meta.data_capacity));
+            }
+            if file.meta.xattr_capacity != 0 {
+                extents.push((file.meta.xattr_address, file.meta.xattr_capacity));
+            }
+            if file.meta.file_attr.kind == FileTypeDump::Directory {
+                for child in file.children() {
+                    pending.push(child?);
+                }
+            }
+        }
+        extents.sort();
+        Ok(extents)
+    }
+    /// Discards every range of the backing store that no node uses, like `fstrim`.
+    /// Returns the number of bytes discarded.
+    pub fn trim(&mut self) -> io::Result<u64> {
+        let mut trimmed = 0;
+        let mut free_from = 512;
+        let mut extents = self.used_extents()?;
+        let sizes = self.disk.device_sizes()?;
+        let end = address(sizes.len() - 1, sizes[sizes.len() - 1]);
+        extents.push((max(end, self.meta.next_free_address), 0));
+        for (address, length) in extents {
+            if address > free_from {
+                self.disk.discard(free_from, address - free_from)?;
+                trimmed += address - free_from;
+            }
+            free_from = max(free_from, address + length);
+        }
+        Ok(trimmed)
+    }
+    /// Discards a freed extent if mounted with `discard`, a failure only costs the space.
+    fn discard(&self, address: u64, length: u64) {
+        if self.options.discard {
+            if let Err(e) = self.disk.discard(address, length) {
+                warn!("discard {}+{} failed: {}", address, length, e);
+            }
+        }
+    }
+    /// Links the node at `address` in as the last child of `parent`.
+    fn append_child(&mut self, parent: &mut File, address: u64) -> io::Result<()> {
+        let mut last_child = None;
+        for child in parent.children() {
+            last_child = Some(child?);
+        }
+        if let Some(mut last_child) = last_child {
+            last_child.meta.next_sibling = address;
+            last_child.sync(&self.disk)?;
+            self.refresh_opened(&last_child);
+        } else {
+            parent.meta.first_child = address;
+            parent.sync(&self.disk)?;
+            self.refresh_opened(parent);
+        }
+        Ok(())
+    }
+    /// Unlinks the child called `name` from `parent` and returns it.
+    fn detach(&mut self, parent: &mut File, name: &OsStr) -> io::Result<Option<File>> {
+        let mut previous: Option<File> = None;
+        for child in parent.children() {
+            let child = child?;
+            if child.meta.filename == name {
+                if let Some(mut previous) = previous {
+                    previous.meta.next_sibling = child.meta.next_sibling;
+                    previous.sync(&self.disk)?;
+                    self.refresh_opened(&previous);
                 } else {
-                    None
+                    parent.meta.first_child = child.meta.next_sibling;
+                    parent.sync(&self.disk)?;
+                    self.refresh_opened(parent);
+                }
+                return Ok(Some(child));
+            }
+            previous = Some(child);
+        }
+        Ok(None)
+    }
+    /// Gives the space of a detached node back.
+    fn release_node(&mut self, file: &File) -> io::Result<()> {
+        let blocks = file.meta.file_attr.blocks;
+        self.quota
+            .account(&owners(&file.meta), -(blocks as i64), -1);
+        self.sync_quota()?;
+        self.release_ino(file.meta.file_attr.ino, file.meta.generation)?;
+        self.free_extent(file.location(), NODE_SIZE)?;
+        self.free_extent(file.meta.data_address, file.meta.data_capacity)?;
+        self.free_extent(file.meta.xattr_address, file.meta.xattr_capacity)
+    }
+    /// `ENOSPC` unless `length` more bytes fit, only root may take the reserved blocks.
+    fn check_space(&self, uid: u32, length: u64) -> Result<(), c_int> {
+        if self.meta.block_count == 0 {
+            return Ok(());
+        }
+        let mut usable = self.meta.block_count;
+        if uid != 0 {
+            usable -= self.meta.reserved_blocks();
+        }
+        if self.used_bytes().map_err(errno)? + length > usable * 512 {
+            Err(ENOSPC)
+        } else {
+            Ok(())
+        }
+    }
+    /// Makes sure `file` can hold `size` bytes for `uid`, moving its content to a bigger extent
+    /// if needed.
+    fn reserve(&mut self, uid: u32, file: &mut File, size: u64) -> Result<(), c_int> {
+        if size <= file.meta.data_capacity {
+            return Ok(());
+        }
+        let block_size = self.meta.block_size();
+        let mut capacity = grown_capacity(file.meta.data_capacity, size, block_size);
+        if self.check_space(uid, capacity).is_err() {
+            // close to full, do without the headroom for further appends
+            capacity = align(size, block_size);
+            self.check_space(uid, capacity)?;
+        }
+        let grown_blocks = (capacity - file.meta.data_capacity) / 512;
+        self.quota
+            .charge(&owners(&file.meta), grown_blocks as i64, 0)?;
+        let end = file.meta.data_address + file.meta.data_capacity;
+        let length = capacity - file.meta.data_capacity;
+        if file.meta.data_address != 0 && self.extend(end, length).map_err(errno)? {
+            file.extend_data(capacity).map_err(errno)?;
+        } else {
+            let address = self.allocate_data(capacity).map_err(errno)?;
+            let (old_address, old_capacity) = file.move_data(address, capacity).map_err(errno)?;
+            self.free_extent(old_address, old_capacity).map_err(errno)?;
+        }
+        self.sync_quota().map_err(errno)
+    }
+    /// Other handles of the same file keep their own copy of the node, bring them up to date.
+    fn refresh_opened(&mut self, file: &File) {
+        for opened in self.opened_files.values_mut() {
+            if opened.meta.file_attr.ino == file.meta.file_attr.ino {
+                opened.meta = file.meta.clone();
+            }
+        }
+    }
+    /// Makes room for a write by `uid` through `fh` that ends at `end`, returns a copy of the handle to
+    /// write the data with.
+    fn prepare_write(&mut self, uid: u32, fh: u64, end: u64) -> Result<File, c_int> {
+        self.writable()?;
+        let mut file = self.opened_files.get(&fh).cloned().ok_or(EBADF)?;
+        self.reserve(uid, &mut file, end)?;
+        self.refresh_opened(&file);
+        Ok(file)
+    }
+    /// Records the size after the data of a write through `fh` that ends at `end` has landed.
+    fn finish_write(&mut self, fh: u64, end: u64) -> Result<(), c_int> {
+        if let Some(file) = self.opened_files.get_mut(&fh) {
+            if end > file.meta.file_attr.size {
+                file.meta.file_attr.size = end;
+                file.sync(&self.disk).map_err(errno)?;
+                let file = file.clone();
+                self.refresh_opened(&file);
+            }
+        }
+        Ok(())
+    }
+    /// Creates an empty node called `name` in `parent` for the user behind `req`.
+    fn make_node(
+        &mut self,
+        uid: u32,
+        gid: u32,
+        parent: u64,
+        name: &OsStr,
+        kind: FileTypeDump,
+    ) -> Result<File, c_int> {
+        self.writable()?;
+        check_name(name, self.meta.name_max())?;
+        let mut parent = self.get_file(parent)?;
+        if parent.meta.file_attr.kind != FileTypeDump::Directory {
+            return Err(ENOTDIR);
+        }
+        self.check_space(uid, NODE_SIZE)?;
+        let (ino, generation) = self.acquire_ino().map_err(errno)?;
+        let at_address = self.allocate(NODE_SIZE).map_err(errno)?;
+        let new_created = FileBuilder::new(&self.disk, at_address)
+            .ino(ino)
+            .generation(generation)
+            .kind(kind)
+            .filename(name)
+            .uid(uid)
+            .gid(gid)
+            .project_id(parent.meta.project_id)
+            .build();
+        let blocks = new_created.meta.file_attr.blocks;
+        if let Err(e) = self
+            .quota
+            .charge(&owners(&new_created.meta), blocks as _, 1)
+        {
+            self.release_ino(ino, generation).map_err(errno)?;
+            self.free_extent(at_address, NODE_SIZE).map_err(errno)?;
+            return Err(e);
+        }
+        new_created.sync(&self.disk).map_err(errno)?;
+        self.append_child(&mut parent, at_address).map_err(errno)?;
+        self.sync_quota().map_err(errno)?;
+        Ok(new_created)
+    }
+    fn remove(&mut self, parent: u64, name: &OsStr, directory: bool) -> Result<(), c_int> {
+        self.writable()?;
+        check_name(name, self.meta.name_max())?;
+        let mut parent = self.get_file(parent)?;
+        let found = parent.child(name).map_err(errno)?.ok_or(ENOENT)?;
+        let is_directory = found.meta.file_attr.kind == FileTypeDump::Directory;
+        if directory && !is_directory {
+            return Err(ENOTDIR);
+        } else if !directory && is_directory {
+            return Err(EISDIR);
+        } else if found.meta.first_child != 0 {
+            return Err(ENOTEMPTY);
+        }
+        let removed = self.detach(&mut parent, name).map_err(errno)?;
+        self.drop_node(removed.ok_or(ENOENT)?).map_err(errno)
+    }
+    fn set_attr(
+        &mut self,
+        uid: u32,
+        ino: u64,
+        mode: Option<u32>,
+        size: Option<u64>,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+    ) -> Result<File, c_int> {
+        self.writable()?;
+        let mut file = self.get_file(ino)?;
+        if let Some(size) = size {
+            if file.meta.file_attr.kind == FileTypeDump::Directory {
+                return Err(EISDIR);
+            }
+            self.reserve(uid, &mut file, size)?;
+            let old_blocks = file.meta.file_attr.blocks;
+            let (address, length) = file.set_len(size).map_err(errno)?;
+            let released_blocks = old_blocks - file.meta.file_attr.blocks;
+            self.quota
+                .account(&owners(&file.meta), -(released_blocks as i64), 0);
+            self.free_extent(address, length).map_err(errno)?;
+            self.sync_quota().map_err(errno)?;
+        }
+        if let Some(mode) = mode {
+            file.meta.file_attr.perm = (mode & 0o7777) as u16;
+        }
+        if let Some(atime) = atime {
+            file.meta.file_attr.atime = atime;
+        }
+        if let Some(mtime) = mtime {
+            file.meta.file_attr.mtime = mtime;
+        }
+        file.meta.file_attr.ctime = SystemTime::now();
+        file.sync(&self.disk).map_err(errno)?;
+        self.refresh_opened(&file);
+        Ok(file)
+    }
+    /// Gives `ino` to another user or group and moves its blocks and inode to their quotas. Only
+    /// root changes the user, an owner may change the group to their own.
+    fn change_owner(
+        &mut self,
+        uid: u32,
+        gid: u32,
+        ino: u64,
+        owner: Option<u32>,
+        group: Option<u32>,
+    ) -> Result<(), c_int> {
+        if owner.is_none() && group.is_none() {
+            return Ok(());
+        }
+        self.writable()?;
+        let mut file = self.get_file(ino)?;
+        let attr = &file.meta.file_attr;
+        let owner = owner.unwrap_or(attr.uid);
+        let group = group.unwrap_or(attr.gid);
+        if uid != 0 && (uid != attr.uid || owner != attr.uid || (group != attr.gid && group != gid))
+        {
+            return Err(EPERM);
+        }
+        let old_owners = owners(&file.meta);
+        file.meta.file_attr.uid = owner;
+        file.meta.file_attr.gid = group;
+        let new_owners = owners(&file.meta);
+        let gained: Vec<_> = new_owners
+            .iter()
+            .filter(|it| !old_owners.contains(it))
+            .cloned()
+            .collect();
+        let lost: Vec<_> = old_owners
+            .iter()
+            .filter(|it| !new_owners.contains(it))
+            .cloned()
+            .collect();
+        let blocks = file.meta.file_attr.blocks as i64;
+        self.quota.charge(&gained, blocks, 1)?;
+        self.quota.account(&lost, -blocks, -1);
+        file.meta.file_attr.ctime = SystemTime::now();
+        file.sync(&self.disk).map_err(errno)?;
+        self.refresh_opened(&file);
+        self.sync_quota().map_err(errno)
+    }
+    fn find_file(&self, ino: u64) -> io::Result<Option<File>> {
+        let root = File::load(&self.disk, 512)?;
+        if root.meta.file_attr.ino != 1 {
+            return invalid_data("the root node does not have inode 1");
+        }
+        if let Some(found) = self.find_file_with_root(ino, root)? {
+            return Ok(Some(found));
+        }
+        for orphan in self.orphans() {
+            let orphan = orphan?;
+            if orphan.meta.file_attr.ino == ino {
+                return Ok(Some(orphan));
+            }
+        }
+        Ok(None)
+    }
+    /// Like `find_file`, a missing node is `ENOENT`.
+    fn get_file(&self, ino: u64) -> Result<File, c_int> {
+        self.find_file(ino).map_err(errno)?.ok_or(ENOENT)
+    }
+    /// The directory containing `ino`, the root is its own parent.
+    fn find_parent(&self, ino: u64) -> io::Result<Option<File>> {
+        let root = File::load(&self.disk, 512)?;
+        if ino == root.meta.file_attr.ino {
+            return Ok(Some(root));
+        }
+        let mut pending = vec![root];
+        while let Some(dir) = pending.pop() {
+            for child in dir.children() {
+                let child = child?;
+                if child.meta.file_attr.ino == ino {
+                    return Ok(Some(dir));
+                } else if child.meta.file_attr.kind == FileTypeDump::Directory {
+                    pending.push(child);
                 }
             }
         }
+        Ok(None)
+    }
+    fn open_handle(&mut self, file: File) -> u64 {
+        let fh = self.next_file_handler;
+        self.next_file_handler += 1;
+        self.opened_files.insert(fh, file);
+        fh
+    }
+    fn read_link(&self, ino: u64) -> Result<Vec<u8>, c_int> {
+        let mut file = self.get_file(ino)?;
+        if file.meta.file_attr.kind != FileTypeDump::Symlink {
+            return Err(EINVAL);
+        }
+        let mut target = vec![];
+        file.read_to_end(&mut target).map_err(errno)?;
+        Ok(target)
+    }
+    fn get_xattr(&self, ino: u64, name: &OsStr) -> Result<Vec<u8>, c_int> {
+        let xattrs = self.get_file(ino)?.xattrs().map_err(errno)?;
+        xattrs.get(name).map(|it| it.to_vec()).ok_or(ENODATA)
+    }
+    fn list_xattr(&self, ino: u64) -> Result<Vec<u8>, c_int> {
+        Ok(self.get_file(ino)?.xattrs().map_err(errno)?.names())
+    }
+    /// Sets `name` on `ino`, `flags` are `XATTR_CREATE` or `XATTR_REPLACE` as for `setxattr(2)`.
+    fn set_xattr(
+        &mut self,
+        uid: u32,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32,
+    ) -> Result<(), c_int> {
+        self.writable()?;
+        let mut file = self.get_file(ino)?;
+        let mut xattrs = file.xattrs().map_err(errno)?;
+        let exists = xattrs.get(name).is_some();
+        if flags as c_int & XATTR_CREATE != 0 && exists {
+            return Err(EEXIST);
+        } else if flags as c_int & XATTR_REPLACE != 0 && !exists {
+            return Err(ENODATA);
+        }
+        xattrs.set(name, value).map_err(errno)?;
+        self.store_xattrs(uid, &mut file, &xattrs)
     }
-    fn find_file(&self, ino: u64) -> Option<File> {
-        let root = File::load(&self.disk, 512).unwrap();
-        assert_eq!(root.meta.file_attr.ino, 1);
-        self.find_file_with_root(ino, root)
+    fn remove_xattr(&mut self, uid: u32, ino: u64, name: &OsStr) -> Result<(), c_int> {
+        self.writable()?;
+        let mut file = self.get_file(ino)?;
+        let mut xattrs = file.xattrs().map_err(errno)?;
+        if !xattrs.remove(name) {
+            return Err(ENODATA);
+        }
+        self.store_xattrs(uid, &mut file, &xattrs)
+    }
+    /// Writes `xattrs` to a new extent of `file` and gives the old one back, so a crash leaves
+    /// either set in place. The owners are charged for the blocks it grows by.
+    fn store_xattrs(&mut self, uid: u32, file: &mut File, xattrs: &Xattrs) -> Result<(), c_int> {
+        let capacity = if xattrs.is_empty() {
+            0
+        } else {
+            align(xattrs.encoded_size(), 512)
+        };
+        if capacity > file.meta.xattr_capacity {
+            self.check_space(uid, capacity)?;
+        }
+        let owners = owners(&file.meta);
+        let grown_blocks = (capacity as i64 - file.meta.xattr_capacity as i64) / 512;
+        self.quota.charge(&owners, grown_blocks, 0)?;
+        let address = match capacity {
+            0 => Ok(0),
+            _ => self.allocate(capacity),
+        };
+        let address = match address {
+            Ok(address) => address,
+            Err(e) => {
+                self.quota.account(&owners, -grown_blocks, 0);
+                return Err(errno(e));
+            }
+        };
+        file.meta.file_attr.ctime = SystemTime::now();
+        let (old_address, old_capacity) =
+            file.move_xattrs(xattrs, address, capacity).map_err(errno)?;
+        self.free_extent(old_address, old_capacity).map_err(errno)?;
+        self.refresh_opened(file);
+        self.sync_quota().map_err(errno)
+    }
+    /// Creates a symlink called `name` in `parent` pointing to `target`.
+    fn make_symlink(
+        &mut self,
+        uid: u32,
+        gid: u32,
+        parent: u64,
+        name: &OsStr,
+        target: &Path,
+    ) -> Result<File, c_int> {
+        let mut file = self.make_node(uid, gid, parent, name, FileTypeDump::Symlink)?;
+        if let Err(e) = self.write_target(uid, &mut file, target.as_os_str().as_bytes()) {
+            self.remove(parent, name, false)?;
+            return Err(e);
+        }
+        Ok(file)
+    }
+    fn write_target(&mut self, uid: u32, file: &mut File, target: &[u8]) -> Result<(), c_int> {
+        self.reserve(uid, file, target.len() as u64)?;
+        file.write_data(0, target).map_err(errno)?;
+        file.meta.file_attr.size = target.len() as u64;
+        file.meta.file_attr.perm = 0o777;
+        file.sync(&self.disk).map_err(errno)
+    }
+}
+
+/// Answers an xattr request for a buffer of `size` bytes, `0` asks for the size only.
+fn reply_xattr(reply: ReplyXattr, size: u32, value: Result<Vec<u8>, c_int>) {
+    match value {
+        Ok(value) if size == 0 => reply.size(value.len() as u32),
+        Ok(value) if value.len() > size as usize => reply.error(ERANGE),
+        Ok(value) => reply.data(&value),
+        Err(e) => reply.error(e),
     }
 }
 
 impl Filesystem for DumbFS {
     fn init(&mut self, _req: &Request<'_>) -> Result<(), i32> {
-        let meta = DumbFsMeta::load(&self.disk, 0);
-        match meta {
-            Ok(meta) => {
-                if meta.valid() {
-                    self.meta = meta
-                } else {
-                    self.init_filesystem();
-                }
-            }
-            Err(_) => self.init_filesystem(),
+        self.open_filesystem().map_err(|e| {
+            error!("cannot open filesystem: {}", e);
+            errno(e)
+        })
+    }
+
+    fn destroy(&mut self, _req: &Request<'_>) {
+        if let Err(e) = self.close_filesystem() {
+            error!("cannot close filesystem: {}", e);
         }
-        Ok(())
     }
 
     fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
         debug!("lookup {:?} in ino={}", name, parent);
-        let parent = self.find_file(parent);
-        if let Some(parent) = parent {
-            let found = parent
-                .children()
-                .find(|it| &it.meta.filename == name.to_str().unwrap());
-            if let Some(found) = found {
-                reply.entry(&TTL, &found.meta.file_attr.into(), 1)
+        let found = check_name(name, self.meta.name_max()).and_then(|_| {
+            let parent = self.get_file(parent)?;
+            // "." and ".." are looked up when an exported file handle is turned back into a dentry
+            let found = if name == "." {
+                Some(parent)
+            } else if name == ".." {
+                self.find_parent(parent.meta.file_attr.ino).map_err(errno)?
             } else {
-                reply.error(ENOENT);
-            }
-        } else {
-            reply.error(ENOENT)
+                parent.child(name).map_err(errno)?
+            };
+            found.ok_or(ENOENT)
+        });
+        match found {
+            Ok(found) => reply.entry(&TTL, &found.meta.file_attr.into(), found.meta.generation),
+            Err(e) => reply.error(e),
         }
     }
 
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
         info!("getattr for ino={}", ino);
-        let file = self.find_file(ino);
-        if let Some(file) = file {
-            info!("ino={}'s size = {}", ino, file.meta.file_attr.size);
-            reply.attr(&TTL, &file.meta.file_attr.into())
-        } else {
-            error!("getattr failed for ino={}", ino);
-            reply.error(ENOENT)
+        match self.get_file(ino) {
+            Ok(file) => {
+                info!("ino={}'s size = {}", ino, file.meta.file_attr.size);
+                reply.attr(&TTL, &file.meta.file_attr.into())
+            }
+            Err(e) => {
+                error!("getattr failed for ino={}", ino);
+                reply.error(e)
+            }
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        match self.read_link(ino) {
+            Ok(target) => reply.data(&target),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let set = self
+            .change_owner(req.uid(), req.gid(), ino, uid, gid)
+            .and_then(|_| self.set_attr(req.uid(), ino, mode, size, atime, mtime));
+        match set {
+            Ok(file) => reply.attr(&TTL, &file.meta.file_attr.into()),
+            Err(e) => reply.error(e),
         }
     }
 
     fn open(&mut self, _req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
-        let file = self.find_file(ino);
-        if let Some(file) = file {
-            let fh = self.next_file_handler;
-            self.next_file_handler += 1;
-            self.opened_files.insert(fh, file);
-            info!("open ino={}, return fh={}", ino, fh);
-            reply.opened(fh, 0);
-        } else {
-            reply.error(ENOENT)
+        let writing = flags as c_int & (O_ACCMODE | O_TRUNC) != O_RDONLY;
+        if writing && self.read_only() {
+            return reply.error(EROFS);
+        }
+        match self.get_file(ino) {
+            Ok(file) => {
+                let fh = self.open_handle(file);
+                info!("open ino={}, return fh={}", ino, fh);
+                reply.opened(fh, 0);
+            }
+            Err(e) => reply.error(e),
         }
     }
 
@@ -145,18 +1168,24 @@ impl Filesystem for DumbFS {
         info!("read with fh={}", fh);
         let file = self.opened_files.get_mut(&fh);
         if let Some(file) = file {
-            let mut buffer = vec![0u8; size as usize];
-            file.seek(SeekFrom::Start(offset as _)).unwrap();
-            file.read_exact(&mut buffer).unwrap();
-            reply.data(&buffer)
+            let mut buffer = Vec::with_capacity(size as usize);
+            let read = file.seek(SeekFrom::Start(offset as _)).and_then(|_| {
+                Read::by_ref(file)
+                    .take(size.into())
+                    .read_to_end(&mut buffer)
+            });
+            match read {
+                Ok(_) => reply.data(&buffer),
+                Err(e) => reply.error(errno(e)),
+            }
         } else {
-            reply.error(EIO)
+            reply.error(EBADF)
         }
     }
 
     fn write(
         &mut self,
-        _req: &Request,
+        req: &Request,
         _ino: u64,
         fh: u64,
         offset: i64,
@@ -165,13 +1194,17 @@ impl Filesystem for DumbFS {
         reply: ReplyWrite,
     ) {
         info!("write into fh={}", fh);
-        let file = self.opened_files.get_mut(&fh);
-        if let Some(file) = file {
-            file.seek(SeekFrom::Start(offset as _)).unwrap();
-            file.write_all(data).unwrap();
-            reply.written(data.len() as _)
-        } else {
-            reply.error(EIO)
+        let end = match write_end(offset, data.len()) {
+            Ok(end) => end,
+            Err(e) => return reply.error(e),
+        };
+        let written = self.prepare_write(req.uid(), fh, end).and_then(|file| {
+            file.write_data(offset as _, data).map_err(errno)?;
+            self.finish_write(fh, end)
+        });
+        match written {
+            Ok(()) => reply.written(data.len() as _),
+            Err(e) => reply.error(e),
         }
     }
 
@@ -185,79 +1218,61 @@ impl Filesystem for DumbFS {
         _flush: bool,
         reply: ReplyEmpty,
     ) {
-        if !self.opened_files.contains_key(&fh) {
-            return reply.error(EIO);
+        match self.close_handle(fh) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
         }
-        self.opened_files.remove(&fh);
-        reply.ok()
     }
 
     fn fsync(&mut self, _req: &Request, _ino: u64, fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        let read_only = self.read_only();
         let file = self.opened_files.get_mut(&fh);
         if let Some(file) = file {
-            file.flush().unwrap();
-            reply.ok()
+            // a read-only image has nothing to make durable
+            let flushed = if read_only { Ok(()) } else { file.flush() };
+            match flushed {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(errno(e)),
+            }
         } else {
-            reply.error(EIO)
+            reply.error(EBADF)
         }
     }
 
     fn create(
         &mut self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
         _mode: u32,
         flags: u32,
         reply: ReplyCreate,
     ) {
-        let parent = self.find_file(parent);
-        if let Some(mut parent) = parent {
-            if parent.meta.file_attr.kind != FileTypeDump::Directory {
-                reply.error(EIO);
-            } else {
-                let at_address = self.meta.next_free_address;
-                let new_created = FileBuilder::new(&self.disk, at_address)
-                    .ino(self.meta.acquire_next_ino())
-                    .kind(FileTypeDump::RegularFile.into())
-                    .filename(name.to_str().unwrap())
-                    .build();
-                new_created.sync(&self.disk);
-                if let Some(mut last_child) = parent.children().last() {
-                    last_child.meta.next_sibling = at_address;
-                    last_child.sync(&self.disk)
-                } else {
-                    parent.meta.first_child = at_address;
-                    parent.sync(&self.disk);
-                }
-                self.meta.next_free_address = align(new_created.address_after_dump(), 512);
-                self.meta.sync(&self.disk);
-                let fh = self.next_file_handler;
-                self.next_file_handler += 1;
-                reply.created(
-                    &TTL,
-                    &new_created.meta.file_attr.clone().into(),
-                    1,
-                    fh,
-                    flags,
-                );
-                self.opened_files.insert(fh, new_created);
+        match self.make_node(
+            req.uid(),
+            req.gid(),
+            parent,
+            name,
+            FileTypeDump::RegularFile,
+        ) {
+            Ok(new_created) => {
+                let attr = new_created.meta.file_attr.clone().into();
+                let generation = new_created.meta.generation;
+                let fh = self.open_handle(new_created);
+                reply.created(&TTL, &attr, generation, fh, flags);
             }
-        } else {
-            reply.error(ENOENT);
+            Err(e) => reply.error(e),
         }
     }
 
     fn opendir(&mut self, _req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
         info!("opendir: {}", ino);
-        let file = self.find_file(ino);
-        if let Some(file) = file {
-            let fh = self.next_file_handler;
-            self.next_file_handler += 1;
-            self.opened_files.insert(fh, file);
-            reply.opened(fh, flags);
-        } else {
-            reply.error(ENOENT);
+        match self.get_file(ino) {
+            Ok(file) => {
+                let fh = self.open_handle(file);
+                reply.opened(fh, flags);
+            }
+            Err(e) => reply.error(e),
         }
     }
 
@@ -272,6 +1287,10 @@ impl Filesystem for DumbFS {
         let dir = self.opened_files.get(&fh);
         if let Some(dir) = dir {
             for (i, entry) in dir.children().enumerate().skip(offset as _) {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => return reply.error(errno(e)),
+                };
                 if reply.add(
                     entry.meta.file_attr.ino,
                     (i + 1) as i64,
@@ -283,48 +1302,476 @@ impl Filesystem for DumbFS {
             }
             reply.ok()
         } else {
-            reply.error(EIO)
+            reply.error(EBADF)
         }
     }
 
     fn releasedir(&mut self, _req: &Request, _ino: u64, fh: u64, _flags: u32, reply: ReplyEmpty) {
-        if !self.opened_files.contains_key(&fh) {
-            return reply.error(EIO);
+        match self.close_handle(fh) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
         }
+    }
 
-        self.opened_files.remove(&fh);
-        reply.ok();
+    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        debug!("unlink {:?} in ino={}", name, parent);
+        match self.remove(parent, name, false) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
     }
 
-    fn mkdir(&mut self, _req: &Request, parent: u64, name: &OsStr, _mode: u32, reply: ReplyEntry) {
-        let parent = self.find_file(parent);
-        if let Some(mut parent) = parent {
-            if parent.meta.file_attr.kind != FileTypeDump::Directory {
-                reply.error(EIO);
-            } else {
-                let at_address = self.meta.next_free_address;
-                let new_created = FileBuilder::new(&self.disk, at_address)
-                    .ino(self.meta.acquire_next_ino())
-                    .kind(FileTypeDump::Directory.into())
-                    .filename(name.to_str().unwrap())
-                    .build();
-                new_created.sync(&self.disk);
-                if let Some(mut last_child) = parent.children().last() {
-                    last_child.meta.next_sibling = at_address;
-                    last_child.sync(&self.disk)
-                } else {
-                    parent.meta.first_child = at_address;
-                    parent.sync(&self.disk);
+    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        debug!("rmdir {:?} in ino={}", name, parent);
+        match self.remove(parent, name, true) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn statfs(&mut self, _req: &Request, ino: u64, reply: ReplyStatfs) {
+        let disk_len = match self.grow_if_requested().and_then(|_| self.disk.len()) {
+            Ok(len) => len,
+            Err(e) => return reply.error(errno(e)),
+        };
+        let used_blocks = match self.used_bytes() {
+            Ok(used) => used / 512,
+            Err(e) => return reply.error(errno(e)),
+        };
+        let mut blocks = if self.meta.block_count == 0 {
+            max(disk_len / 512, used_blocks)
+        } else {
+            self.meta.block_count
+        };
+        let mut free_blocks = blocks.saturating_sub(used_blocks);
+        let mut available_blocks = free_blocks.saturating_sub(self.meta.reserved_blocks());
+        let mut files = u64::from(u32::MAX);
+        let mut free_files = files - self.used_inos();
+        let file = match self.find_file(ino) {
+            Ok(file) => file,
+            Err(e) => return reply.error(errno(e)),
+        };
+        if let Some(file) = file {
+            if let Some(capacity) = self.quota.project_capacity(file.meta.project_id) {
+                let (project_blocks, project_free_blocks, project_files, project_free_files) =
+                    capacity;
+                if project_blocks != 0 {
+                    blocks = project_blocks;
+                    free_blocks = project_free_blocks;
+                    available_blocks = project_free_blocks;
+                }
+                if project_files != 0 {
+                    files = project_files;
+                    free_files = project_free_files;
                 }
-                self.meta.next_free_address = align(new_created.address_after_dump(), 512);
-                self.meta.sync(&self.disk);
-                let fh = self.next_file_handler;
-                self.next_file_handler += 1;
-                reply.entry(&TTL, &new_created.meta.file_attr.clone().into(), 1);
-                self.opened_files.insert(fh, new_created);
             }
-        } else {
-            reply.error(ENOENT);
         }
+        reply.statfs(
+            blocks,
+            free_blocks,
+            available_blocks,
+            files,
+            free_files,
+            self.meta.block_size() as u32,
+            self.meta.name_max(),
+            512,
+        );
     }
+
+    fn mkdir(&mut self, req: &Request, parent: u64, name: &OsStr, _mode: u32, reply: ReplyEntry) {
+        match self.make_node(req.uid(), req.gid(), parent, name, FileTypeDump::Directory) {
+            Ok(new_created) => reply.entry(
+                &TTL,
+                &new_created.meta.file_attr.clone().into(),
+                new_created.meta.generation,
+            ),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        reply_xattr(reply, size, self.get_xattr(ino, name))
+    }
+
+    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        reply_xattr(reply, size, self.list_xattr(ino))
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        match self.set_xattr(req.uid(), ino, name, value, flags) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn removexattr(&mut self, req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.remove_xattr(req.uid(), ino, name) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn symlink(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
+    ) {
+        match self.make_symlink(req.uid(), req.gid(), parent, name, link) {
+            Ok(new_created) => reply.entry(
+                &TTL,
+                &new_created.meta.file_attr.clone().into(),
+                new_created.meta.generation,
+            ),
+            Err(e) => reply.error(e),
+        }
+    }
+}
+
+#[test]
+fn test_check_name() {
+    assert_eq!(check_name(OsStr::new("file.txt"), 255), Ok(()));
+    assert_eq!(check_name(OsStr::from_bytes(b"\xff\xfe"), 255), Ok(()));
+    assert_eq!(check_name(OsStr::new("a/b"), 255), Err(EINVAL));
+    assert_eq!(check_name(OsStr::from_bytes(b"a\0b"), 255), Err(EINVAL));
+    assert_eq!(check_name(OsStr::new(""), 255), Err(EINVAL));
+    assert_eq!(check_name(OsStr::new("abcd"), 3), Err(ENAMETOOLONG));
+}
+
+/// Formats `disk` as a filesystem of `len` bytes and opens it.
+#[cfg(test)]
+fn formatted_fs(disk: Disk, len: u64) -> io::Result<DumbFS> {
+    use crate::fs::format::FormatOptions;
+    disk.set_len(len)?;
+    let mut dumbfs = DumbFS::with_disk(disk, MountOptions::default());
+    dumbfs.format(&FormatOptions::default())?;
+    dumbfs.open_filesystem()?;
+    Ok(dumbfs)
+}
+
+#[cfg(test)]
+fn formatted_memory_fs(len: u64) -> io::Result<DumbFS> {
+    formatted_fs(Disk::memory(), len)
+}
+
+#[test]
+fn test_io_errors() -> io::Result<()> {
+    use crate::disk::device::Memory;
+    use crate::disk::fault::{Fault, FaultRule, FaultSchedule, Faulty, Op};
+    let schedule = FaultSchedule::new(1);
+    let disk = Disk::with_device(Faulty::new(Memory::default(), schedule.clone()));
+    let mut dumbfs = formatted_fs(disk, 1 << 20)?;
+
+    schedule.inject(FaultRule::new(Op::Read, Fault::Error).times(1));
+    assert_eq!(dumbfs.get_file(1).err(), Some(EIO));
+    let mut root = File::load(&dumbfs.disk, 512)?;
+    schedule.inject(FaultRule::new(Op::Write, Fault::Error));
+    assert_eq!(
+        dumbfs.set_attr(0, 1, Some(0o700), None, None, None).err(),
+        Some(EIO)
+    );
+    assert_eq!(dumbfs.prepare_write(0, 7, 1).err(), Some(EBADF));
+    schedule.clear();
+
+    root.meta.first_child = 1000;
+    root.sync(&dumbfs.disk)?;
+    assert_eq!(dumbfs.get_file(2).err(), Some(EUCLEAN));
+    assert_eq!(write_end(-1, 1), Err(EINVAL));
+    assert_eq!(write_end(i64::MAX, 1), Err(EFBIG));
+    assert_eq!(write_end(4096, 512), Ok(4608));
+    Ok(())
+}
+
+#[test]
+fn test_read_only() -> io::Result<()> {
+    use crate::fs::format::FormatOptions;
+    use tempfile::tempdir;
+    let tempdir = tempdir()?;
+    let file_path = tempdir.path().join("temp.img");
+    std::fs::File::create(&file_path)?;
+    let mut options = MountOptions::default();
+    options.disk.read_only = true;
+    let mut dumbfs = DumbFS::with_options(&file_path, options.clone())?;
+    assert!(dumbfs.open_filesystem().is_err());
+
+    let mut dumbfs = DumbFS::new(&file_path)?;
+    dumbfs.format(&FormatOptions::default())?;
+    dumbfs.open_filesystem()?;
+    assert!(dumbfs.set_attr(0, 1, Some(0o700), None, None, None).is_ok());
+    dumbfs.close_filesystem()?;
+    drop(dumbfs);
+    let image = std::fs::read(&file_path)?;
+
+    let mut dumbfs = DumbFS::with_options(&file_path, options)?;
+    dumbfs.open_filesystem()?;
+    let root = dumbfs.get_file(1).unwrap();
+    assert_eq!(root.meta.file_attr.perm, 0o700);
+    let atime = Some(SystemTime::now());
+    assert_eq!(
+        dumbfs.set_attr(0, 1, None, None, atime, None).err(),
+        Some(EROFS)
+    );
+    assert_eq!(dumbfs.remove(1, OsStr::new("missing"), false), Err(EROFS));
+    let fh = dumbfs.open_handle(root);
+    assert_eq!(dumbfs.prepare_write(0, fh, 1).err(), Some(EROFS));
+    dumbfs.close_filesystem()?;
+    drop(dumbfs);
+    assert!(std::fs::read(&file_path)? == image);
+    Ok(())
+}
+
+#[test]
+fn test_enospc() -> io::Result<()> {
+    use crate::fs::quota::{QuotaKind, QuotaLimits};
+    let mut dumbfs = formatted_memory_fs(64 * 512)?;
+    dumbfs.set_reserved_percent(25)?;
+    assert_eq!(dumbfs.meta.block_count, 64);
+    let regular = FileTypeDump::RegularFile;
+    let file = dumbfs
+        .make_node(1000, 100, 1, OsStr::new("file"), regular.clone())
+        .unwrap();
+    let fh = dumbfs.open_handle(file);
+
+    // users get the space below the reserved blocks, root gets all of it
+    let room = 48 * 512 - dumbfs.meta.next_free_address;
+    assert_eq!(
+        dumbfs.prepare_write(1000, fh, room + 512).err(),
+        Some(ENOSPC)
+    );
+    assert!(dumbfs.prepare_write(1000, fh, room).is_ok());
+    let name = OsStr::new("more");
+    assert_eq!(
+        dumbfs.make_node(1000, 100, 1, name, regular.clone()).err(),
+        Some(ENOSPC)
+    );
+    assert!(dumbfs.make_node(0, 0, 1, name, regular).is_ok());
+    let e = dumbfs.allocate(16 * 512).unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(ENOSPC));
+
+    // the space of a removed file is handed out again
+    let used = dumbfs.used_bytes()?;
+    dumbfs.close_handle(fh).unwrap();
+    dumbfs.remove(1, OsStr::new("file"), false).unwrap();
+    // the block of the node goes to the free inode list created on the way
+    assert_eq!(dumbfs.used_bytes()?, used - room);
+    let address = dumbfs.allocate(16 * 512)?;
+    assert!(address + 16 * 512 <= 48 * 512);
+
+    // a node the quota refuses leaves neither its inode nor its block behind
+    let limits = QuotaLimits {
+        inode_hard: 1,
+        ..QuotaLimits::default()
+    };
+    dumbfs.quota.set_limits(QuotaKind::User, 1000, limits);
+    let regular = FileTypeDump::RegularFile;
+    let name = OsStr::new("limited");
+    assert!(dumbfs
+        .make_node(1000, 100, 1, name, regular.clone())
+        .is_ok());
+    let used = (dumbfs.used_bytes()?, dumbfs.used_inos());
+    let over = OsStr::new("over");
+    assert_eq!(
+        dumbfs.make_node(1000, 100, 1, over, regular).err(),
+        Some(libc::EDQUOT)
+    );
+    assert_eq!((dumbfs.used_bytes()?, dumbfs.used_inos()), used);
+    Ok(())
+}
+
+#[test]
+fn test_quota_table() -> io::Result<()> {
+    use crate::fs::quota::{QuotaKind, QuotaLimits};
+    let disk = Disk::memory();
+    let mut dumbfs = formatted_fs(disk.clone(), 1 << 20)?;
+    let limits = QuotaLimits {
+        block_hard: 8,
+        ..QuotaLimits::default()
+    };
+    dumbfs.quota.set_limits(QuotaKind::User, 1000, limits);
+    dumbfs.sync_quota()?;
+
+    // a table that outgrows its extent gives the old one back
+    let old = (dumbfs.meta.quota_address, dumbfs.meta.quota_capacity);
+    assert_ne!(old.0, 0);
+    for id in 0..old.1 as u32 / 8 {
+        dumbfs.quota.account(&[(QuotaKind::User, 2000 + id)], 0, 0);
+    }
+    dumbfs.sync_quota()?;
+    assert_ne!(dumbfs.meta.quota_address, old.0);
+    assert!(dumbfs.free_extents.take_at(old.0, old.1));
+    dumbfs.close_filesystem()?;
+    let address = dumbfs.meta.quota_address;
+    assert_ne!(address, 0);
+
+    // a table that cannot be read fails the mount instead of dropping every limit
+    let mut raw = disk.clone();
+    raw.seek(SeekFrom::Start(address))?;
+    raw.write_all(&[0xff; 512])?;
+    let mut dumbfs = DumbFS::with_disk(disk.clone(), MountOptions::default());
+    let e = dumbfs.open_filesystem().unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidData);
+    assert!(DumbFsMeta::load(&disk, 0)?.valid());
+    Ok(())
+}
+
+#[test]
+fn test_change_owner() -> io::Result<()> {
+    use crate::fs::quota::{QuotaKind, QuotaLimits};
+    let mut dumbfs = formatted_memory_fs(1 << 20)?;
+    let limits = QuotaLimits {
+        inode_hard: 1,
+        ..QuotaLimits::default()
+    };
+    dumbfs.quota.set_limits(QuotaKind::User, 1000, limits);
+    let regular = FileTypeDump::RegularFile;
+    dumbfs
+        .make_node(1000, 100, 1, OsStr::new("limited"), regular.clone())
+        .unwrap();
+
+    // a new owner is charged for the node and the old one relieved of it
+    let owned = dumbfs
+        .make_node(0, 0, 1, OsStr::new("owned"), regular)
+        .unwrap();
+    let ino = owned.meta.file_attr.ino;
+    let root_inodes = dumbfs.quota.entry(QuotaKind::User, 0).unwrap().inodes;
+    assert_eq!(
+        dumbfs.change_owner(1000, 100, ino, Some(1000), None),
+        Err(EPERM)
+    );
+    assert_eq!(
+        dumbfs.change_owner(0, 0, ino, Some(1000), None),
+        Err(libc::EDQUOT)
+    );
+    dumbfs
+        .change_owner(0, 0, ino, Some(1001), Some(300))
+        .unwrap();
+    assert_eq!(dumbfs.quota.entry(QuotaKind::User, 1001).unwrap().inodes, 1);
+    let root_entry = dumbfs.quota.entry(QuotaKind::User, 0).unwrap();
+    assert_eq!(root_entry.inodes, root_inodes - 1);
+    assert_eq!(
+        dumbfs.change_owner(1001, 300, ino, None, Some(400)),
+        Err(EPERM)
+    );
+    dumbfs
+        .change_owner(1001, 400, ino, None, Some(400))
+        .unwrap();
+    let attr = dumbfs.get_file(ino).unwrap().meta.file_attr;
+    assert_eq!((attr.uid, attr.gid), (1001, 400));
+    Ok(())
+}
+
+#[test]
+fn test_symlink_and_xattrs() -> io::Result<()> {
+    let mut dumbfs = formatted_memory_fs(1 << 20)?;
+    let link = dumbfs
+        .make_symlink(1000, 100, 1, OsStr::new("link"), Path::new("../target"))
+        .unwrap();
+    let ino = link.meta.file_attr.ino;
+    assert_eq!(link.meta.file_attr.size, 9);
+    assert_eq!(dumbfs.read_link(ino), Ok(b"../target".to_vec()));
+
+    let name = OsStr::new("user.origin");
+    assert_eq!(
+        dumbfs.set_xattr(0, ino, name, b"host", XATTR_REPLACE as u32),
+        Err(ENODATA)
+    );
+    dumbfs.set_xattr(0, ino, name, b"host", 0).unwrap();
+    assert_eq!(
+        dumbfs.set_xattr(0, ino, name, b"x", XATTR_CREATE as u32),
+        Err(EEXIST)
+    );
+    assert_eq!(dumbfs.get_xattr(ino, name), Ok(b"host".to_vec()));
+    let blocks = dumbfs.get_file(ino).unwrap().meta.file_attr.blocks;
+    let used = dumbfs.used_bytes()?;
+    dumbfs.remove_xattr(0, ino, name).unwrap();
+    assert_eq!(dumbfs.remove_xattr(0, ino, name), Err(ENODATA));
+    assert_eq!(dumbfs.list_xattr(ino), Ok(vec![]));
+    let file = dumbfs.get_file(ino).unwrap();
+    assert_eq!(file.meta.xattr_capacity, 0);
+    assert_eq!(file.meta.file_attr.blocks, blocks - 1);
+    assert_eq!(dumbfs.used_bytes()?, used - 512);
+    Ok(())
+}
+
+#[test]
+fn test_devices() -> io::Result<()> {
+    use crate::disk::{add_device, devices_path};
+    use crate::fs::format::FormatOptions;
+    use tempfile::tempdir;
+    let tempdir = tempdir()?;
+    let image = tempdir.path().join("temp.img");
+    let second = tempdir.path().join("second.img");
+    std::fs::File::create(&image)?.set_len(64 * 512)?;
+    std::fs::File::create(&second)?.set_len(64 * 512)?;
+    let mut dumbfs = DumbFS::new(&image)?;
+    dumbfs.format(&FormatOptions::default())?;
+    dumbfs.open_filesystem()?;
+    assert!(add_device(&image, &image).is_err());
+    let nbd = Path::new("nbd://localhost/disk");
+    let e = add_device(nbd, &second).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    add_device(&image, &second)?;
+    assert!(add_device(&image, &second).is_err());
+    // the device is only picked up once a grow is requested, on the next allocation
+    dumbfs.grow_if_requested()?;
+    assert_eq!(dumbfs.meta.device_count(), 1);
+    resize::request_grow(libc::SIGUSR1);
+    let allocated = dumbfs.allocate(512)?;
+    dumbfs.free_extent(allocated, 512)?;
+    assert_eq!(dumbfs.meta.block_count, 128);
+    assert_eq!(dumbfs.meta.device_count(), 2);
+    assert_eq!(dumbfs.disk.len()?, 128 * 512);
+    let label: DeviceLabel = dumbfs.disk.load_at(address(1, 0))?;
+    assert_eq!(label, DeviceLabel::new(dumbfs.meta.uuid, 1));
+
+    // nodes go to the second device once the first one is full
+    let regular = FileTypeDump::RegularFile;
+    let mut devices = vec![];
+    let e = loop {
+        let name = format!("file{}", devices.len());
+        match dumbfs.make_node(0, 0, 1, OsStr::new(&name), regular.clone()) {
+            Ok(file) => devices.push(split(file.location()).0),
+            Err(e) => break e,
+        }
+    };
+    assert_eq!(e, ENOSPC);
+    assert!(devices.contains(&0) && devices.contains(&1));
+    let last = format!("/file{}", devices.len() - 1);
+    dumbfs.close_filesystem()?;
+    drop(dumbfs);
+
+    let mut dumbfs = DumbFS::new(&image)?;
+    dumbfs.open_filesystem()?;
+    let file = dumbfs.find_path(Path::new(&last))?.unwrap();
+    assert_eq!(split(file.location()).0, 1);
+    assert!(split(file.location()).1 >= DEVICE_LABEL_SIZE);
+    dumbfs.close_filesystem()?;
+    drop(dumbfs);
+
+    // a filesystem whose device was swapped for a blank one of the same size is refused
+    let content = std::fs::read(&second)?;
+    std::fs::write(&second, vec![0u8; content.len()])?;
+    let mut dumbfs = DumbFS::new(&image)?;
+    let e = dumbfs.open_filesystem().err().unwrap();
+    assert_eq!(e.kind(), ErrorKind::InvalidData);
+    drop(dumbfs);
+    std::fs::write(&second, content)?;
+
+    // a filesystem with a device missing is refused
+    std::fs::remove_file(devices_path(&image)?)?;
+    let mut dumbfs = DumbFS::new(&image)?;
+    let e = dumbfs.open_filesystem().err().unwrap();
+    assert_eq!(e.kind(), ErrorKind::NotFound);
+    Ok(())
 }
@@ -0,0 +1,101 @@
+use crate::disk::DiskOptions;
+use crate::util::parse_size;
+
+/// Worker threads serving reads and writes unless `threads=` says otherwise.
+pub const DEFAULT_THREADS: usize = 4;
+/// Bytes of the image kept in the block cache unless `cache=` says otherwise.
+pub const DEFAULT_CACHE_SIZE: u64 = 16 << 20;
+
+/// Options understood by dumbfs itself, everything else given with `-o` goes to FUSE.
+#[derive(Debug, Clone)]
+pub struct MountOptions {
+    /// Discard freed ranges in the backing store as soon as they are freed.
+    pub discard: bool,
+    /// Worker threads for reads and writes, `0` serves every request on the FUSE session thread.
+    pub threads: usize,
+    /// How the backing store is opened.
+    pub disk: DiskOptions,
+}
+
+impl Default for MountOptions {
+    fn default() -> Self {
+        MountOptions {
+            discard: false,
+            threads: DEFAULT_THREADS,
+            disk: DiskOptions {
+                cache_size: DEFAULT_CACHE_SIZE,
+                uring: false,
+                direct: false,
+                read_only: false,
+                mirrors: vec![],
+                stripes: vec![],
+                parity: 1,
+            },
+        }
+    }
+}
+
+impl MountOptions {
+    /// Splits a comma separated option list into dumbfs options and the remaining FUSE ones.
+    pub fn parse(options: &str) -> (Self, Vec<String>) {
+        let mut result = MountOptions::default();
+        let mut rest = vec![];
+        for option in options.split(',').filter(|it| !it.is_empty()) {
+            match option {
+                "discard" => result.discard = true,
+                "nodiscard" => result.discard = false,
+                "uring" => result.disk.uring = true,
+                "nouring" => result.disk.uring = false,
+                "direct" => result.disk.direct = true,
+                "nodirect" => result.disk.direct = false,
+                "ro" => result.disk.read_only = true,
+                "rw" => result.disk.read_only = false,
+                _ if option.starts_with("threads=") => match option[8..].parse() {
+                    Ok(threads) => result.threads = threads,
+                    Err(_) => rest.push(option.to_string()),
+                },
+                _ if option.starts_with("mirror=") => result.disk.mirrors.push(option[7..].into()),
+                _ if option.starts_with("stripe=") => result.disk.stripes.push(option[7..].into()),
+                "parity=1" => result.disk.parity = 1,
+                "parity=2" => result.disk.parity = 2,
+                _ if option.starts_with("cache=") => match parse_size(&option[6..]) {
+                    Some(size) => result.disk.cache_size = size,
+                    None => rest.push(option.to_string()),
+                },
+                _ => rest.push(option.to_string()),
+            }
+        }
+        (result, rest)
+    }
+}
+
+#[test]
+fn test_parse_options() {
+    use std::path::PathBuf;
+    let (options, rest) = MountOptions::parse("ro,discard,allow_other,uring,direct");
+    assert!(options.discard);
+    assert!(options.disk.uring);
+    assert!(options.disk.direct);
+    assert!(options.disk.read_only);
+    assert_eq!(options.threads, DEFAULT_THREADS);
+    assert_eq!(options.disk.cache_size, DEFAULT_CACHE_SIZE);
+    assert_eq!(rest, vec!["allow_other"]);
+    let (options, rest) = MountOptions::parse("threads=0,threads=many");
+    assert_eq!(options.threads, 0);
+    assert_eq!(rest, vec!["threads=many"]);
+    let (options, rest) = MountOptions::parse("cache=64M,cache=0,cache=lots");
+    assert_eq!(options.disk.cache_size, 0);
+    assert_eq!(rest, vec!["cache=lots"]);
+    let (options, _) = MountOptions::parse("mirror=/b.img,mirror=/c.img");
+    assert_eq!(
+        options.disk.mirrors,
+        vec![PathBuf::from("/b.img"), PathBuf::from("/c.img")]
+    );
+    let (options, rest) = MountOptions::parse("stripe=/b.img,stripe=/c.img,parity=2,parity=3");
+    assert_eq!(options.disk.stripes.len(), 2);
+    assert_eq!(options.disk.parity, 2);
+    assert_eq!(rest, vec!["parity=3"]);
+    let (options, rest) = MountOptions::parse("discard,nodiscard");
+    assert!(!options.discard);
+    assert!(rest.is_empty());
+}
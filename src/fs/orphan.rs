@@ -0,0 +1,164 @@
+use crate::disk::dump::DumpToFixedLocation;
+use crate::file::{File, FileIterator};
+use crate::fs::{errno, DumbFS};
+use libc::{c_int, EBADF};
+use std::io;
+
+impl DumbFS {
+    /// Nodes unlinked while still open. They keep their space until the last handle is closed,
+    /// or until the next mount if the daemon went away before that.
+    pub(super) fn orphans(&self) -> FileIterator {
+        File::chain(&self.disk, self.meta.orphan_head)
+    }
+    fn is_open(&self, ino: u64) -> bool {
+        self.opened_files
+            .values()
+            .any(|it| it.meta.file_attr.ino == ino)
+    }
+    /// Frees a node just detached from its directory, or puts it on the orphan list if it is open.
+    pub(super) fn drop_node(&mut self, mut file: File) -> io::Result<()> {
+        if !self.is_open(file.meta.file_attr.ino) {
+            return self.release_node(&file);
+        }
+        debug!(
+            "ino={} is still open, keep it as orphan",
+            file.meta.file_attr.ino
+        );
+        // the unlink lands first, the orphan list must not hold a node still in the tree
+        self.write_barrier()?;
+        file.meta.next_sibling = self.meta.orphan_head;
+        file.sync(&self.disk)?;
+        self.meta.orphan_head = file.location();
+        self.meta.sync(&self.disk)?;
+        self.refresh_opened(&file);
+        self.write_barrier()
+    }
+    /// Unlinks the orphan at `address` from the orphan list.
+    fn take_orphan(&mut self, address: u64) -> io::Result<Option<File>> {
+        let mut previous: Option<File> = None;
+        for orphan in self.orphans() {
+            let orphan = orphan?;
+            if orphan.location() == address {
+                if let Some(mut previous) = previous {
+                    previous.meta.next_sibling = orphan.meta.next_sibling;
+                    previous.sync(&self.disk)?;
+                    self.refresh_opened(&previous);
+                } else {
+                    self.meta.orphan_head = orphan.meta.next_sibling;
+                    self.meta.sync(&self.disk)?;
+                }
+                // its space is reused next, a crash must not find it on the list then
+                self.write_barrier()?;
+                return Ok(Some(orphan));
+            }
+            previous = Some(orphan);
+        }
+        Ok(None)
+    }
+    /// Forgets the handle `fh`, releasing its node if it was the last handle of an orphan.
+    /// Unknown handles are `EBADF`.
+    pub(super) fn close_handle(&mut self, fh: u64) -> Result<(), c_int> {
+        let file = self.opened_files.remove(&fh).ok_or(EBADF)?;
+        if !self.is_open(file.meta.file_attr.ino) {
+            if let Some(orphan) = self.take_orphan(file.location()).map_err(errno)? {
+                debug!(
+                    "last handle of orphan ino={} closed",
+                    file.meta.file_attr.ino
+                );
+                self.release_node(&orphan).map_err(errno)?;
+                self.write_barrier().map_err(errno)?;
+            }
+        }
+        Ok(())
+    }
+    /// Releases orphans left behind by a daemon that did not shut down cleanly.
+    /// Each is unlinked before its space is given back, so a crash in between leaks it at worst.
+    pub(super) fn reclaim_orphans(&mut self) -> io::Result<()> {
+        while let Some(orphan) = self.orphans().next() {
+            let orphan = orphan?;
+            info!("reclaim orphan ino={}", orphan.meta.file_attr.ino);
+            self.meta.orphan_head = orphan.meta.next_sibling;
+            self.meta.sync(&self.disk)?;
+            self.write_barrier()?;
+            self.release_node(&orphan)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_orphan() -> std::io::Result<()> {
+    use crate::file::dump_file_attr::FileTypeDump;
+    use crate::file::FileBuilder;
+    use crate::fs::format::FormatOptions;
+    use crate::fs::quota::QuotaKind;
+    use std::ffi::OsStr;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::path::Path;
+    use tempfile::tempdir;
+    let tempdir = tempdir()?;
+    let file_path = tempdir.path().join("temp.img");
+    let mut dumbfs = DumbFS::new(&file_path)?;
+    dumbfs.format(&FormatOptions::default())?;
+    dumbfs.open_filesystem()?;
+    let mut root = File::load(&dumbfs.disk, 512)?;
+    for (ino, name) in [(2, "kept"), (3, "crashed")].iter() {
+        let address = dumbfs.allocate(super::NODE_SIZE)?;
+        let data_address = dumbfs.allocate(512)?;
+        let mut file = FileBuilder::new(&dumbfs.disk, address)
+            .ino(*ino)
+            .kind(FileTypeDump::RegularFile)
+            .filename(name)
+            .data(data_address, 512)
+            .build();
+        file.write_all(b"hello world")?;
+        dumbfs.append_child(&mut root, address)?;
+        dumbfs.opened_files.insert(*ino, file);
+        root = File::load(&dumbfs.disk, 512)?;
+    }
+    dumbfs.meta.reserve_inos_below(4);
+    dumbfs.recompute_quota()?;
+    let used = |dumbfs: &DumbFS| dumbfs.quota.entry(QuotaKind::User, 0).unwrap().inodes;
+    assert_eq!(used(&dumbfs), 3);
+
+    for name in &["kept", "crashed"] {
+        let removed = dumbfs.detach(&mut root, OsStr::new(name))?.unwrap();
+        dumbfs.drop_node(removed)?;
+    }
+    assert!(dumbfs.find_path(Path::new("/kept"))?.is_none());
+    assert_eq!(dumbfs.orphans().count(), 2);
+    assert_eq!(used(&dumbfs), 3);
+    let kept = dumbfs.opened_files.get_mut(&2).unwrap();
+    let mut content = String::new();
+    kept.seek(SeekFrom::Start(0))?;
+    kept.read_to_string(&mut content)?;
+    assert_eq!(content, "hello world");
+    assert!(dumbfs.find_file(2)?.is_some());
+
+    assert_eq!(dumbfs.close_handle(2), Ok(()));
+    assert_eq!(dumbfs.close_handle(2), Err(EBADF));
+    assert_eq!(dumbfs.orphans().count(), 1);
+    assert_eq!(used(&dumbfs), 2);
+    assert_eq!(dumbfs.free_inodes.len(), 1);
+
+    // the daemon goes away without closing the remaining handle, or writing back its cache
+    drop(root);
+    std::mem::forget(dumbfs);
+    let mut dumbfs = DumbFS::new(&file_path)?;
+    dumbfs.open_filesystem()?;
+    assert_eq!(dumbfs.orphans().count(), 0);
+    assert_eq!(dumbfs.meta.orphan_head, 0);
+    assert_eq!(used(&dumbfs), 1);
+    assert_eq!(dumbfs.free_inodes.len(), 2);
+
+    // a crash right after mounting still finds the image in use
+    dumbfs.close_filesystem()?;
+    drop(dumbfs);
+    let mut dumbfs = DumbFS::new(&file_path)?;
+    dumbfs.open_filesystem()?;
+    std::mem::forget(dumbfs);
+    let mut dumbfs = DumbFS::new(&file_path)?;
+    dumbfs.load_superblock()?;
+    assert!(!dumbfs.meta.clean);
+    Ok(())
+}
@@ -0,0 +1,417 @@
+//! Fills a new filesystem with a copy of a host directory, like `mke2fs -d`.
+//!
+//! Entries are copied in name order and every extent is written in full, so the same tree with
+//! the same UUID, ID mappings and fixed timestamp always gives the same image.
+
+use crate::disk::dump::DumpToFixedLocation;
+use crate::disk::encode::{Encode, Encoder};
+use crate::file::dump_file_attr::FileTypeDump;
+use crate::file::xattr::Xattrs;
+use crate::file::{File, FileBuilder, NODE_SIZE};
+use crate::fs::{check_name, DumbFS};
+use crate::util::align;
+use libc::{c_void, ENODATA, ENOTSUP, ERANGE};
+use std::cmp::min;
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr};
+use std::fs;
+use std::fs::Metadata;
+use std::io;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::ptr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Content is copied in pieces of this size.
+const COPY_BUFFER_SIZE: usize = 1 << 20;
+
+/// Maps host user or group IDs to the ones stored in the image.
+#[derive(Debug, Clone, Default)]
+pub struct IdMap {
+    ids: HashMap<u32, u32>,
+    /// What every ID missing from `ids` becomes, kept as it is when unset.
+    other: Option<u32>,
+}
+
+impl IdMap {
+    /// Adds the comma-separated `host:id` pairs of `list`, `*:id` maps every other host ID.
+    pub fn add(&mut self, list: &str) -> Option<()> {
+        for pair in list.split(',') {
+            let at = pair.find(':')?;
+            let id = pair[at + 1..].parse().ok()?;
+            match &pair[..at] {
+                "*" => self.other = Some(id),
+                host => {
+                    self.ids.insert(host.parse().ok()?, id);
+                }
+            }
+        }
+        Some(())
+    }
+    pub fn map(&self, id: u32) -> u32 {
+        match self.ids.get(&id) {
+            Some(&mapped) => mapped,
+            None => self.other.unwrap_or(id),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PopulateOptions {
+    pub uids: IdMap,
+    pub gids: IdMap,
+    /// Every time of every node, the host's times are kept when unset.
+    pub timestamp: Option<SystemTime>,
+}
+
+/// Calls a `*xattr` function first for the size and then with a buffer that fits, `None` when
+/// there is nothing to read.
+fn xattr_call<F: Fn(*mut c_void, usize) -> isize>(call: F) -> io::Result<Option<Vec<u8>>> {
+    loop {
+        let size = call(ptr::null_mut(), 0);
+        if size < 0 {
+            let e = io::Error::last_os_error();
+            return match e.raw_os_error() {
+                Some(ENOTSUP) | Some(ENODATA) => Ok(None),
+                _ => Err(e),
+            };
+        }
+        let mut buffer = vec![0u8; size as usize];
+        let size = call(buffer.as_mut_ptr() as *mut c_void, buffer.len());
+        if size >= 0 {
+            buffer.truncate(size as usize);
+            return Ok(Some(buffer));
+        }
+        // grew in between
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(ERANGE) {
+            return Err(e);
+        }
+    }
+}
+
+/// The extended attributes of `path` itself, a symlink is not followed.
+fn host_xattrs(path: &Path) -> io::Result<Xattrs> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut xattrs = Xattrs::default();
+    let names = xattr_call(|buffer, size| unsafe {
+        libc::llistxattr(path.as_ptr(), buffer as *mut _, size)
+    })?;
+    for name in names.unwrap_or_default().split(|&it| it == 0) {
+        if name.is_empty() {
+            continue;
+        }
+        let c_name = CString::new(name)?;
+        let value = xattr_call(|buffer, size| unsafe {
+            libc::lgetxattr(path.as_ptr(), c_name.as_ptr(), buffer, size)
+        })?;
+        // removed since it was listed
+        if let Some(value) = value {
+            xattrs.set(OsStr::from_bytes(name), &value)?;
+        }
+    }
+    Ok(xattrs)
+}
+
+fn change_time(metadata: &Metadata) -> SystemTime {
+    let seconds = metadata.ctime();
+    let nanoseconds = Duration::from_nanos(metadata.ctime_nsec() as u64);
+    if seconds >= 0 {
+        UNIX_EPOCH + Duration::from_secs(seconds as u64) + nanoseconds
+    } else {
+        UNIX_EPOCH - Duration::from_secs(seconds.wrapping_neg() as u64) + nanoseconds
+    }
+}
+
+impl DumbFS {
+    /// Copies the tree under the directory `source` into the freshly formatted filesystem, the
+    /// root taking the attributes of `source`. Hard links become separate copies and the kinds
+    /// dumbfs cannot store, like devices and sockets, are left out.
+    pub fn populate(&mut self, source: &Path, options: &PopulateOptions) -> io::Result<()> {
+        let metadata = fs::metadata(source)?;
+        if !metadata.is_dir() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{:?} is not a directory", source),
+            ));
+        }
+        let mut root = File::load(&self.disk, 512)?;
+        if root.meta.first_child != 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "only an empty filesystem can be populated",
+            ));
+        }
+        self.copy_attributes(&mut root, source, &metadata, options)?;
+        let mut pending = vec![(source.to_path_buf(), 512)];
+        while let Some((directory, address)) = pending.pop() {
+            let mut names = fs::read_dir(&directory)?
+                .map(|it| it.map(|it| it.file_name()))
+                .collect::<io::Result<Vec<_>>>()?;
+            names.sort();
+            // reloaded, the directory may have been linked to its siblings since it was queued
+            let mut dir = File::load(&self.disk, address)?;
+            let mut previous: Option<File> = None;
+            for name in names {
+                let path = directory.join(&name);
+                let file = match self.copy_node(&path, &name, dir.meta.project_id, options)? {
+                    Some(file) => file,
+                    None => continue,
+                };
+                if let Some(mut previous) = previous {
+                    previous.meta.next_sibling = file.location();
+                    previous.sync(&self.disk)?;
+                } else {
+                    dir.meta.first_child = file.location();
+                    dir.sync(&self.disk)?;
+                }
+                if file.meta.file_attr.kind == FileTypeDump::Directory {
+                    pending.push((path, file.location()));
+                }
+                previous = Some(file);
+            }
+        }
+        self.recompute_quota()
+    }
+    /// Creates the node for the host file at `path`, `None` for a kind dumbfs cannot store.
+    fn copy_node(
+        &mut self,
+        path: &Path,
+        name: &OsStr,
+        project_id: u32,
+        options: &PopulateOptions,
+    ) -> io::Result<Option<File>> {
+        if let Err(errno) = check_name(name, self.meta.name_max()) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "cannot store the name {:?}: {}",
+                    name,
+                    io::Error::from_raw_os_error(errno)
+                ),
+            ));
+        }
+        let metadata = fs::symlink_metadata(path)?;
+        let file_type = metadata.file_type();
+        let (kind, size) = if file_type.is_dir() {
+            (FileTypeDump::Directory, 0)
+        } else if file_type.is_file() {
+            (FileTypeDump::RegularFile, metadata.len())
+        } else if file_type.is_symlink() {
+            (
+                FileTypeDump::Symlink,
+                fs::read_link(path)?.as_os_str().len() as u64,
+            )
+        } else {
+            warn!("leave out {:?}, dumbfs cannot store its kind", path);
+            return Ok(None);
+        };
+        let (ino, generation) = self.acquire_ino()?;
+        let address = self.allocate(NODE_SIZE)?;
+        let capacity = align(size, self.meta.block_size());
+        let data_address = if capacity == 0 {
+            0
+        } else {
+            self.allocate_data(capacity)?
+        };
+        let mut file = FileBuilder::new(&self.disk, address)
+            .ino(ino)
+            .generation(generation)
+            .kind(kind.clone())
+            .filename(name)
+            .project_id(project_id)
+            .data(data_address, capacity)
+            .size(size)
+            .build();
+        if kind == FileTypeDump::Symlink {
+            let mut target = fs::read_link(path)?.into_os_string().into_vec();
+            if target.len() as u64 != size {
+                return changed(path);
+            }
+            target.resize(capacity as usize, 0);
+            file.write_data(0, &target)?;
+        } else if kind == FileTypeDump::RegularFile {
+            copy_content(path, &file)?;
+        }
+        self.copy_attributes(&mut file, path, &metadata, options)?;
+        Ok(Some(file))
+    }
+    /// Gives `file` the mode, mapped owner, times and extended attributes of the host file at
+    /// `path` and writes it.
+    fn copy_attributes(
+        &mut self,
+        file: &mut File,
+        path: &Path,
+        metadata: &Metadata,
+        options: &PopulateOptions,
+    ) -> io::Result<()> {
+        let attr = &mut file.meta.file_attr;
+        attr.perm = (metadata.mode() & 0o7777) as u16;
+        attr.uid = options.uids.map(metadata.uid());
+        attr.gid = options.gids.map(metadata.gid());
+        if let Some(timestamp) = options.timestamp {
+            attr.atime = timestamp;
+            attr.mtime = timestamp;
+            attr.ctime = timestamp;
+            attr.crtime = timestamp;
+        } else {
+            attr.atime = metadata.accessed()?;
+            attr.mtime = metadata.modified()?;
+            attr.ctime = change_time(metadata);
+            attr.crtime = metadata.created().unwrap_or(attr.ctime);
+        }
+        let xattrs = host_xattrs(path)?;
+        if !xattrs.is_empty() {
+            let capacity = align(xattrs.encoded_size(), 512);
+            let address = self.allocate(capacity)?;
+            let mut encoder = Encoder::default();
+            xattrs.encode(&mut encoder);
+            encoder.pad_to(capacity as usize);
+            let mut disk = self.disk.clone();
+            disk.seek(SeekFrom::Start(address))?;
+            disk.write_all(&encoder.into_inner())?;
+            file.meta.xattr_address = address;
+            file.meta.xattr_capacity = capacity;
+        }
+        file.meta.file_attr.blocks = file.allocated_blocks();
+        file.sync(&self.disk)
+    }
+}
+
+fn changed<T>(path: &Path) -> io::Result<T> {
+    Err(io::Error::new(
+        ErrorKind::InvalidData,
+        format!("{:?} changed while it was copied", path),
+    ))
+}
+
+/// Copies the content of the host file at `path` into the data extent of `file`, zeroing the
+/// rest of the extent.
+fn copy_content(path: &Path, file: &File) -> io::Result<()> {
+    let size = file.meta.file_attr.size;
+    let mut host_file = fs::File::open(path)?.take(size);
+    let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+    let mut offset = 0;
+    loop {
+        let read = host_file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        file.write_data(offset, &buffer[..read])?;
+        offset += read as u64;
+    }
+    if offset != size {
+        return changed(path);
+    }
+    let mut position = size;
+    while position < file.meta.data_capacity {
+        let length = min(file.meta.data_capacity - position, COPY_BUFFER_SIZE as u64);
+        file.write_data(position, &vec![0u8; length as usize])?;
+        position += length;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_populate() -> io::Result<()> {
+    use crate::fs::format::FormatOptions;
+    use std::ffi::OsString;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    use tempfile::tempdir;
+
+    let tempdir = tempdir()?;
+    let source = tempdir.path().join("source");
+    fs::create_dir_all(source.join("dir/empty"))?;
+    fs::write(source.join("dir/file.txt"), b"hello world")?;
+    fs::write(source.join("big"), vec![7u8; 3 << 20])?;
+    fs::set_permissions(source.join("big"), fs::Permissions::from_mode(0o640))?;
+    symlink("dir/file.txt", source.join("link"))?;
+    let path = CString::new(source.join("dir/file.txt").as_os_str().as_bytes())?;
+    let name = CString::new("user.origin")?;
+    // some filesystems, tmpfs among them, take no user attributes
+    let has_xattr =
+        unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), b"host".as_ptr() as _, 4, 0) } == 0;
+
+    let mut uids = IdMap::default();
+    uids.add(&format!("{}:1000", fs::metadata(&source)?.uid()))
+        .unwrap();
+    let mut gids = IdMap::default();
+    gids.add("*:100").unwrap();
+    assert_eq!(gids.map(0), 100);
+    assert_eq!(uids.map(u32::MAX), u32::MAX);
+    assert!(uids.add("1000").is_none());
+    let timestamp = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    let options = PopulateOptions {
+        uids,
+        gids,
+        timestamp: Some(timestamp),
+    };
+    let format = FormatOptions {
+        size: Some(16 << 20),
+        uuid: Some([1; 16]),
+        ..FormatOptions::default()
+    };
+    let build = |image: &Path| -> io::Result<Vec<u8>> {
+        fs::File::create(image)?;
+        let mut dumbfs = DumbFS::new(image)?;
+        dumbfs.format(&format)?;
+        dumbfs.populate(&source, &options)?;
+        dumbfs.close_filesystem()?;
+        fs::read(image)
+    };
+    let image = tempdir.path().join("image");
+    let content = build(&image)?;
+    assert!(content == build(&tempdir.path().join("again"))?);
+
+    let mut dumbfs = DumbFS::new(&image)?;
+    dumbfs.open_filesystem()?;
+    let names = File::load(&dumbfs.disk, 512)?
+        .children()
+        .map(|it| it.map(|it| it.meta.filename))
+        .collect::<io::Result<Vec<_>>>()?;
+    assert_eq!(
+        names,
+        vec![OsString::from("big"), "dir".into(), "link".into()]
+    );
+    let root = dumbfs.get_file(1).unwrap();
+    assert_eq!(root.meta.file_attr.uid, 1000);
+    assert_eq!(root.meta.file_attr.gid, 100);
+    assert_eq!(root.meta.file_attr.mtime, timestamp);
+    let mut big = dumbfs.find_path(Path::new("/big"))?.unwrap();
+    assert_eq!(big.meta.file_attr.perm, 0o640);
+    let mut read = vec![];
+    big.read_to_end(&mut read)?;
+    assert!(read == vec![7u8; 3 << 20]);
+    let link = dumbfs.find_path(Path::new("/link"))?.unwrap();
+    assert_eq!(link.meta.file_attr.kind, FileTypeDump::Symlink);
+    assert_eq!(
+        dumbfs.read_link(link.meta.file_attr.ino),
+        Ok(b"dir/file.txt".to_vec())
+    );
+    let file = dumbfs.find_path(Path::new("/dir/file.txt"))?.unwrap();
+    let ino = file.meta.file_attr.ino;
+    assert_eq!(dumbfs.read_link(ino), Err(libc::EINVAL));
+    if has_xattr {
+        assert_eq!(dumbfs.list_xattr(ino), Ok(b"user.origin\0".to_vec()));
+        let origin = dumbfs.get_xattr(ino, OsStr::new("user.origin"));
+        assert_eq!(origin, Ok(b"host".to_vec()));
+        assert_eq!(file.meta.file_attr.blocks, 3);
+    }
+    assert_eq!(
+        dumbfs.get_xattr(ino, OsStr::new("user.missing")),
+        Err(ENODATA)
+    );
+    assert!(dumbfs.find_path(Path::new("/dir/empty"))?.is_some());
+    assert_eq!(dumbfs.used_inos(), 6);
+
+    // trimming keeps the attribute extents and only an empty filesystem is populated
+    assert!(dumbfs.populate(&source, &options).is_err());
+    dumbfs.trim()?;
+    let file = dumbfs.find_path(Path::new("/dir/file.txt"))?.unwrap();
+    if has_xattr {
+        assert!(file.xattrs()?.get(OsStr::new("user.origin")).is_some());
+    }
+    Ok(())
+}
@@ -0,0 +1,364 @@
+use crate::disk::dump::DumpToFixedLocation;
+use crate::disk::encode::{invalid_data, Decode, Decoder, Encode, Encoder};
+use crate::disk::Disk;
+use crate::file::FileMeta;
+use libc::{c_int, EDQUOT};
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Read;
+use std::time::{Duration, SystemTime};
+
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+pub enum QuotaKind {
+    User,
+    Group,
+    Project,
+}
+
+/// Limits are counted in 512-byte blocks and inodes, `0` means unlimited.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct QuotaLimits {
+    pub block_soft: u64,
+    pub block_hard: u64,
+    pub inode_soft: u64,
+    pub inode_hard: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct QuotaEntry {
+    pub limits: QuotaLimits,
+    pub blocks: u64,
+    pub inodes: u64,
+    pub block_grace_expires: Option<SystemTime>,
+    pub inode_grace_expires: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone)]
+pub struct QuotaTableDump {
+    pub block_grace: Duration,
+    pub inode_grace: Duration,
+    pub entries: BTreeMap<(QuotaKind, u32), QuotaEntry>,
+}
+
+impl Default for QuotaTableDump {
+    fn default() -> Self {
+        QuotaTableDump {
+            block_grace: DEFAULT_GRACE_PERIOD,
+            inode_grace: DEFAULT_GRACE_PERIOD,
+            entries: BTreeMap::new(),
+        }
+    }
+}
+
+fn encode_expiry(encoder: &mut Encoder, expires: Option<SystemTime>) {
+    encoder.bool(expires.is_some());
+    encoder.time(expires.unwrap_or(SystemTime::UNIX_EPOCH));
+}
+
+fn decode_expiry<R: Read>(decoder: &mut Decoder<R>) -> io::Result<Option<SystemTime>> {
+    let present = decoder.bool()?;
+    let expires = decoder.time()?;
+    Ok(if present { Some(expires) } else { None })
+}
+
+impl Encode for QuotaTableDump {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.u64(self.block_grace.as_secs());
+        encoder.u64(self.inode_grace.as_secs());
+        encoder.u32(self.entries.len() as u32);
+        for ((kind, id), entry) in &self.entries {
+            encoder.u8(match kind {
+                QuotaKind::User => 1,
+                QuotaKind::Group => 2,
+                QuotaKind::Project => 3,
+            });
+            encoder.u32(*id);
+            encoder.u64(entry.limits.block_soft);
+            encoder.u64(entry.limits.block_hard);
+            encoder.u64(entry.limits.inode_soft);
+            encoder.u64(entry.limits.inode_hard);
+            encoder.u64(entry.blocks);
+            encoder.u64(entry.inodes);
+            encode_expiry(encoder, entry.block_grace_expires);
+            encode_expiry(encoder, entry.inode_grace_expires);
+        }
+    }
+}
+
+impl Decode for QuotaTableDump {
+    fn decode<R: Read>(decoder: &mut Decoder<R>) -> io::Result<Self> {
+        let block_grace = Duration::from_secs(decoder.u64()?);
+        let inode_grace = Duration::from_secs(decoder.u64()?);
+        let mut entries = BTreeMap::new();
+        for _ in 0..decoder.u32()? {
+            let kind = match decoder.u8()? {
+                1 => QuotaKind::User,
+                2 => QuotaKind::Group,
+                3 => QuotaKind::Project,
+                _ => return invalid_data("unknown quota kind"),
+            };
+            let id = decoder.u32()?;
+            let entry = QuotaEntry {
+                limits: QuotaLimits {
+                    block_soft: decoder.u64()?,
+                    block_hard: decoder.u64()?,
+                    inode_soft: decoder.u64()?,
+                    inode_hard: decoder.u64()?,
+                },
+                blocks: decoder.u64()?,
+                inodes: decoder.u64()?,
+                block_grace_expires: decode_expiry(decoder)?,
+                inode_grace_expires: decode_expiry(decoder)?,
+            };
+            entries.insert((kind, id), entry);
+        }
+        Ok(QuotaTableDump {
+            block_grace,
+            inode_grace,
+            entries,
+        })
+    }
+}
+
+pub struct QuotaTable {
+    address: u64,
+    pub table: QuotaTableDump,
+}
+
+pub fn owners(meta: &FileMeta) -> Vec<(QuotaKind, u32)> {
+    let mut result = vec![
+        (QuotaKind::User, meta.file_attr.uid),
+        (QuotaKind::Group, meta.file_attr.gid),
+    ];
+    if meta.project_id != 0 {
+        result.push((QuotaKind::Project, meta.project_id));
+    }
+    result
+}
+
+fn apply_delta(usage: u64, delta: i64) -> u64 {
+    if delta < 0 {
+        usage.saturating_sub(delta.wrapping_neg() as u64)
+    } else {
+        usage.saturating_add(delta as u64)
+    }
+}
+
+fn over_limit(usage: u64, soft: u64, hard: u64, grace_expires: Option<SystemTime>) -> bool {
+    if hard != 0 && usage > hard {
+        return true;
+    }
+    match grace_expires {
+        Some(expires) => soft != 0 && usage > soft && SystemTime::now() >= expires,
+        None => false,
+    }
+}
+
+fn update_grace(usage: u64, soft: u64, grace: Duration, grace_expires: &mut Option<SystemTime>) {
+    if soft != 0 && usage > soft {
+        if grace_expires.is_none() {
+            *grace_expires = Some(SystemTime::now() + grace);
+        }
+    } else {
+        *grace_expires = None;
+    }
+}
+
+impl QuotaTable {
+    pub fn new(address: u64) -> Self {
+        QuotaTable {
+            address,
+            table: QuotaTableDump::default(),
+        }
+    }
+    pub fn move_to(&mut self, address: u64) {
+        self.address = address;
+    }
+    pub fn entry(&self, kind: QuotaKind, id: u32) -> Option<&QuotaEntry> {
+        self.table.entries.get(&(kind, id))
+    }
+    /// The limits `statfs` reports for a project: hard limits where set, soft limits otherwise.
+    pub fn project_capacity(&self, project_id: u32) -> Option<(u64, u64, u64, u64)> {
+        let entry = self.entry(QuotaKind::Project, project_id)?;
+        let pick = |soft: u64, hard: u64| if hard != 0 { hard } else { soft };
+        let blocks = pick(entry.limits.block_soft, entry.limits.block_hard);
+        let inodes = pick(entry.limits.inode_soft, entry.limits.inode_hard);
+        if blocks == 0 && inodes == 0 {
+            None
+        } else {
+            Some((
+                blocks,
+                blocks.saturating_sub(entry.blocks),
+                inodes,
+                inodes.saturating_sub(entry.inodes),
+            ))
+        }
+    }
+    pub fn set_limits(&mut self, kind: QuotaKind, id: u32, limits: QuotaLimits) {
+        let grace = (self.table.block_grace, self.table.inode_grace);
+        let entry = self.table.entries.entry((kind, id)).or_default();
+        entry.limits = limits;
+        update_grace(
+            entry.blocks,
+            limits.block_soft,
+            grace.0,
+            &mut entry.block_grace_expires,
+        );
+        update_grace(
+            entry.inodes,
+            limits.inode_soft,
+            grace.1,
+            &mut entry.inode_grace_expires,
+        );
+    }
+    /// Checks whether `owners` may grow by `blocks` and `inodes`, and records the new usage if so.
+    /// Releasing space is always allowed.
+    pub fn charge(
+        &mut self,
+        owners: &[(QuotaKind, u32)],
+        blocks: i64,
+        inodes: i64,
+    ) -> Result<(), c_int> {
+        for owner in owners {
+            if let Some(entry) = self.table.entries.get(owner) {
+                let limits = &entry.limits;
+                if blocks > 0
+                    && over_limit(
+                        apply_delta(entry.blocks, blocks),
+                        limits.block_soft,
+                        limits.block_hard,
+                        entry.block_grace_expires,
+                    )
+                {
+                    return Err(EDQUOT);
+                }
+                if inodes > 0
+                    && over_limit(
+                        apply_delta(entry.inodes, inodes),
+                        limits.inode_soft,
+                        limits.inode_hard,
+                        entry.inode_grace_expires,
+                    )
+                {
+                    return Err(EDQUOT);
+                }
+            }
+        }
+        self.account(owners, blocks, inodes);
+        Ok(())
+    }
+    /// Records usage without checking limits.
+    pub fn account(&mut self, owners: &[(QuotaKind, u32)], blocks: i64, inodes: i64) {
+        let grace = (self.table.block_grace, self.table.inode_grace);
+        for owner in owners {
+            let entry = self.table.entries.entry(*owner).or_default();
+            entry.blocks = apply_delta(entry.blocks, blocks);
+            entry.inodes = apply_delta(entry.inodes, inodes);
+            update_grace(
+                entry.blocks,
+                entry.limits.block_soft,
+                grace.0,
+                &mut entry.block_grace_expires,
+            );
+            update_grace(
+                entry.inodes,
+                entry.limits.inode_soft,
+                grace.1,
+                &mut entry.inode_grace_expires,
+            );
+        }
+    }
+    pub fn reset_usage(&mut self) {
+        for entry in self.table.entries.values_mut() {
+            entry.blocks = 0;
+            entry.inodes = 0;
+        }
+    }
+}
+
+impl DumpToFixedLocation<QuotaTableDump> for QuotaTable {
+    fn dump_part(&self) -> QuotaTableDump {
+        self.table.clone()
+    }
+
+    fn location(&self) -> u64 {
+        self.address
+    }
+
+    fn load(disk: &Disk, address: u64) -> io::Result<Self> {
+        disk.load_at(address)
+            .map(|table| QuotaTable { address, table })
+    }
+}
+
+#[test]
+fn test_quota() -> io::Result<()> {
+    let disk = Disk::memory();
+    let mut quota = QuotaTable::new(1024);
+    let alice = [(QuotaKind::User, 1000), (QuotaKind::Group, 100)];
+    quota.set_limits(
+        QuotaKind::User,
+        1000,
+        QuotaLimits {
+            block_soft: 4,
+            block_hard: 8,
+            inode_soft: 0,
+            inode_hard: 2,
+        },
+    );
+    assert_eq!(quota.charge(&alice, 1, 1), Ok(()));
+    assert_eq!(quota.charge(&alice, 1, 1), Ok(()));
+    assert_eq!(quota.charge(&alice, 1, 1), Err(EDQUOT));
+    assert_eq!(quota.charge(&alice, 6, 0), Ok(()));
+    assert!(quota
+        .entry(QuotaKind::User, 1000)
+        .unwrap()
+        .block_grace_expires
+        .is_some());
+    assert_eq!(quota.charge(&alice, 1, 0), Err(EDQUOT));
+    assert_eq!(quota.charge(&alice, -5, -1), Ok(()));
+    assert!(quota
+        .entry(QuotaKind::User, 1000)
+        .unwrap()
+        .block_grace_expires
+        .is_none());
+    quota.table.block_grace = Duration::from_secs(0);
+    assert_eq!(quota.charge(&alice, 2, 0), Ok(()));
+    assert_eq!(quota.charge(&alice, 1, 0), Err(EDQUOT));
+    assert_eq!(quota.entry(QuotaKind::Group, 100).unwrap().blocks, 5);
+    quota.sync(&disk)?;
+    let expires = quota
+        .entry(QuotaKind::User, 1000)
+        .unwrap()
+        .block_grace_expires;
+    let mut quota = QuotaTable::load(&disk, 1024).unwrap();
+    assert_eq!(quota.table.block_grace, Duration::from_secs(0));
+    assert_eq!(
+        quota
+            .entry(QuotaKind::User, 1000)
+            .unwrap()
+            .block_grace_expires,
+        expires
+    );
+    assert_eq!(quota.entry(QuotaKind::User, 1000).unwrap().blocks, 5);
+    assert_eq!(quota.entry(QuotaKind::User, 1000).unwrap().inodes, 1);
+    quota.set_limits(
+        QuotaKind::Project,
+        7,
+        QuotaLimits {
+            block_soft: 16,
+            block_hard: 0,
+            inode_soft: 0,
+            inode_hard: 0,
+        },
+    );
+    let builds = [(QuotaKind::User, 0), (QuotaKind::Project, 7)];
+    assert_eq!(quota.charge(&builds, 10, 1), Ok(()));
+    assert_eq!(quota.project_capacity(7), Some((16, 6, 0, 0)));
+    assert_eq!(quota.project_capacity(8), None);
+    quota.reset_usage();
+    assert_eq!(quota.entry(QuotaKind::Group, 100).unwrap().blocks, 0);
+    Ok(())
+}
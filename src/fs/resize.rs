@@ -0,0 +1,210 @@
+use crate::disk::dump::DumpToFixedLocation;
+use crate::file::File;
+use crate::fs::DumbFS;
+use crate::util::align;
+use std::collections::HashMap;
+use std::io;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Bumped by every `SIGUSR1`, see `grow_on_signal`.
+static GROW_REQUESTS: AtomicU64 = AtomicU64::new(0);
+
+pub fn grow_requests() -> u64 {
+    GROW_REQUESTS.load(Ordering::SeqCst)
+}
+
+pub extern "C" fn request_grow(_signal: libc::c_int) {
+    GROW_REQUESTS.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Makes `SIGUSR1` grow a mounted filesystem onto an enlarged backing store and the devices added
+/// since, on its next allocation or `statfs`.
+pub fn grow_on_signal() {
+    let handler: extern "C" fn(libc::c_int) = request_grow;
+    unsafe { libc::signal(libc::SIGUSR1, handler as libc::sighandler_t) };
+}
+
+impl DumbFS {
+    /// Grows the filesystem if that was requested since the last time, see `grow_on_signal`.
+    pub fn grow_if_requested(&mut self) -> io::Result<()> {
+        let requests = grow_requests();
+        if requests == self.grow_requests {
+            return Ok(());
+        }
+        self.grow_requests = requests;
+        if !self.sized() {
+            info!("the filesystem grows with its image, there is nothing to grow");
+            return Ok(());
+        }
+        self.grow()
+    }
+
+    /// Resizes an unmounted filesystem to `size` bytes.
+    /// Shrinking packs every node towards the start of the image first so the tail can be cut off.
+    pub fn resize(&mut self, size: u64) -> io::Result<()> {
+        if self.meta.device_count() > 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "a filesystem spanning several devices grows by adding one",
+            ));
+        }
+        let size = size / 512 * 512;
+        if size < self.meta.next_free_address {
+            self.compact()?;
+        }
+        if size < self.meta.next_free_address {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "cannot shrink to {} bytes, {} bytes are in use",
+                    size, self.meta.next_free_address
+                ),
+            ));
+        }
+        info!("resize filesystem to {} blocks", size / 512);
+        self.disk.set_len(size)?;
+        self.meta.block_count = size / 512;
+        self.meta.sync(&self.disk)?;
+        Ok(())
+    }
+
+    /// Moves every extent down to close the gaps between them, keeping their order.
+    fn compact(&mut self) -> io::Result<()> {
+        let extents = self.used_extents()?;
+        let mut relocated = HashMap::new();
+        let mut next_free_address = 512;
+        let block_size = self.meta.block_size();
+        for &(address, length) in &extents {
+            // data extents stay on a block boundary
+            if address % block_size == 0 && length % block_size == 0 {
+                next_free_address = align(next_free_address, block_size);
+            }
+            relocated.insert(address, next_free_address);
+            next_free_address += length;
+        }
+        let relocate = |address: u64| match address {
+            0 => Ok(0),
+            _ => relocated.get(&address).cloned().ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("a link points to {}, where no extent starts", address),
+                )
+            }),
+        };
+        // a corrupt image is refused before anything moves
+        relocate(self.meta.quota_address)?;
+        relocate(self.meta.free_inodes_address)?;
+        let mut pending = vec![512, self.meta.orphan_head];
+        while let Some(address) = pending.pop() {
+            if address == 0 {
+                continue;
+            }
+            let meta = File::load(&self.disk, address)?.meta;
+            for &link in &[meta.data_address, meta.xattr_address] {
+                relocate(link)?;
+            }
+            for &link in &[meta.first_child, meta.next_sibling] {
+                relocate(link)?;
+                pending.push(link);
+            }
+        }
+        let mut disk = self.disk.clone();
+        for &(address, length) in &extents {
+            let target = relocated[&address];
+            if address != target {
+                debug!("move extent {}+{} to {}", address, length, target);
+                let mut buffer = vec![];
+                disk.seek(SeekFrom::Start(address))?;
+                (&mut disk).take(length).read_to_end(&mut buffer)?;
+                disk.seek(SeekFrom::Start(target))?;
+                disk.write_all(&buffer)?;
+            }
+        }
+        // the root never moves, everything else is reached through relocated links
+        self.meta.orphan_head = relocate(self.meta.orphan_head)?;
+        let mut pending = vec![512];
+        if self.meta.orphan_head != 0 {
+            pending.push(self.meta.orphan_head);
+        }
+        while let Some(address) = pending.pop() {
+            let mut file = File::load(&self.disk, address)?;
+            file.meta.first_child = relocate(file.meta.first_child)?;
+            file.meta.next_sibling = relocate(file.meta.next_sibling)?;
+            file.meta.data_address = relocate(file.meta.data_address)?;
+            file.meta.xattr_address = relocate(file.meta.xattr_address)?;
+            file.sync(&self.disk)?;
+            pending.extend(
+                [file.meta.first_child, file.meta.next_sibling]
+                    .iter()
+                    .filter(|it| **it != 0),
+            );
+        }
+        if self.meta.quota_address != 0 {
+            self.meta.quota_address = relocate(self.meta.quota_address)?;
+            self.quota.move_to(self.meta.quota_address);
+        }
+        if self.meta.free_inodes_address != 0 {
+            self.meta.free_inodes_address = relocate(self.meta.free_inodes_address)?;
+            self.free_inodes.move_to(self.meta.free_inodes_address);
+        }
+        self.meta.next_free_address = next_free_address;
+        self.meta.sync(&self.disk)?;
+        self.find_free_extents()?;
+        disk.flush()
+    }
+}
+
+#[test]
+fn test_resize() -> io::Result<()> {
+    use crate::file::dump_file_attr::FileTypeDump;
+    use crate::file::FileBuilder;
+    use crate::fs::format::FormatOptions;
+    use std::path::Path;
+    use tempfile::tempdir;
+    let tempdir = tempdir()?;
+    let file_path = tempdir.path().join("temp.img");
+    let mut dumbfs = DumbFS::new(&file_path)?;
+    dumbfs.format(&FormatOptions::default())?;
+    dumbfs.open_filesystem()?;
+    let mut root = File::load(&dumbfs.disk, 512).unwrap();
+    root.meta.first_child = 65536;
+    root.sync(&dumbfs.disk)?;
+    let mut child = FileBuilder::new(&dumbfs.disk, 65536)
+        .ino(2)
+        .kind(FileTypeDump::RegularFile)
+        .filename("child")
+        .data(131072, 512)
+        .build();
+    child.write_all(b"hello world")?;
+    dumbfs.meta.next_free_address = 131072 + 512;
+    dumbfs.close_filesystem()?;
+
+    let mut dumbfs = DumbFS::new(&file_path)?;
+    dumbfs.open_filesystem()?;
+    // a link to where no extent starts is refused before anything moves
+    let mut child = File::load(&dumbfs.disk, 65536)?;
+    child.meta.xattr_address = 4096;
+    child.sync(&dumbfs.disk)?;
+    let e = dumbfs.compact().unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidData);
+    assert_eq!(File::load(&dumbfs.disk, 65536)?.meta.data_address, 131072);
+    child.meta.xattr_address = 0;
+    child.sync(&dumbfs.disk)?;
+    assert!(dumbfs.resize(1024).is_err());
+    dumbfs.resize(8192)?;
+    assert_eq!(dumbfs.disk.len()?, 8192);
+    assert_eq!(dumbfs.meta.block_count, 16);
+    let mut child = dumbfs.find_path(Path::new("/child"))?.unwrap();
+    let mut buffer = [0u8; 11];
+    child.read_exact(&mut buffer)?;
+    assert_eq!(&buffer, b"hello world");
+    dumbfs.close_filesystem()?;
+
+    let mut dumbfs = DumbFS::new(&file_path)?;
+    dumbfs.open_filesystem()?;
+    dumbfs.resize(1 << 20)?;
+    assert_eq!(dumbfs.disk.len()?, 1 << 20);
+    assert_eq!(dumbfs.meta.next_free_address, 2560);
+    Ok(())
+}
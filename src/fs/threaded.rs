@@ -0,0 +1,425 @@
+//! Serves FUSE requests with a pool of worker threads.
+//!
+//! The tree and every other piece of metadata stay behind one mutex, which is only held for short
+//! metadata updates. Reads, writes, fsyncs, attribute changes and releases run on a worker thread
+//! and move data outside of it, so a slow one only holds up later requests for the same inode.
+
+use crate::fs::{errno, write_end, DumbFS, TTL};
+use fuse::{
+    Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
+    ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request,
+};
+use libc::EBADF;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::OsStr;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::SystemTime;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// How a job uses its inode: shared ones run alongside each other, an exclusive one alone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    Shared,
+    Exclusive,
+}
+
+/// The jobs of one inode that are running or waiting for it.
+#[derive(Default)]
+struct InodeQueue {
+    shared: usize,
+    exclusive: bool,
+    waiting: VecDeque<(Access, Job)>,
+}
+
+impl InodeQueue {
+    fn may_start(&self, access: Access) -> bool {
+        match access {
+            Access::Shared => !self.exclusive,
+            Access::Exclusive => !self.exclusive && self.shared == 0,
+        }
+    }
+    fn idle(&self) -> bool {
+        self.shared == 0 && !self.exclusive && self.waiting.is_empty()
+    }
+}
+
+struct Queues {
+    sender: Mutex<Option<Sender<Job>>>,
+    /// Inodes with jobs running or waiting.
+    inodes: Mutex<HashMap<u64, InodeQueue>>,
+    idle: Condvar,
+}
+
+/// Takes `access` to the inode of `queue` and hands `job` to a worker, which gives the access
+/// back and starts the jobs waiting for it once done.
+fn start(queues: &Arc<Queues>, queue: &mut InodeQueue, ino: u64, access: Access, job: Job) {
+    match access {
+        Access::Shared => queue.shared += 1,
+        Access::Exclusive => queue.exclusive = true,
+    }
+    let done = queues.clone();
+    let job: Job = Box::new(move || {
+        job();
+        finished(&done, ino, access);
+    });
+    let sender = queues.sender.lock().unwrap();
+    sender.as_ref().unwrap().send(job).unwrap();
+}
+
+fn finished(queues: &Arc<Queues>, ino: u64, access: Access) {
+    let mut inodes = queues.inodes.lock().unwrap();
+    let queue = inodes.get_mut(&ino).unwrap();
+    match access {
+        Access::Shared => queue.shared -= 1,
+        Access::Exclusive => queue.exclusive = false,
+    }
+    while let Some(next) = queue.waiting.front().map(|it| it.0) {
+        if !queue.may_start(next) {
+            break;
+        }
+        let (_, job) = queue.waiting.pop_front().unwrap();
+        start(queues, queue, ino, next, job);
+    }
+    if queue.idle() {
+        inodes.remove(&ino);
+        if inodes.is_empty() {
+            queues.idle.notify_all();
+        }
+    }
+}
+
+/// Runs the jobs of each inode in the order they came in. A job that has to wait for its inode
+/// stays queued instead of taking up a worker, so a busy inode does not hold up the others.
+struct Workers {
+    queues: Arc<Queues>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Workers {
+    fn new(count: usize) -> Self {
+        let (sender, receiver) = channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let threads = (0..count)
+            .map(|i| {
+                let receiver = receiver.clone();
+                thread::Builder::new()
+                    .name(format!("dumbfs-worker-{}", i))
+                    .spawn(move || loop {
+                        let job = receiver.lock().unwrap().recv();
+                        match job {
+                            Ok(job) => job(),
+                            Err(_) => break,
+                        }
+                    })
+                    .unwrap()
+            })
+            .collect();
+        Workers {
+            queues: Arc::new(Queues {
+                sender: Mutex::new(Some(sender)),
+                inodes: Mutex::new(HashMap::new()),
+                idle: Condvar::new(),
+            }),
+            threads,
+        }
+    }
+    fn execute<F: FnOnce() + Send + 'static>(&self, ino: u64, access: Access, job: F) {
+        let mut inodes = self.queues.inodes.lock().unwrap();
+        let queue = inodes.entry(ino).or_default();
+        if queue.waiting.is_empty() && queue.may_start(access) {
+            start(&self.queues, queue, ino, access, Box::new(job));
+        } else {
+            queue.waiting.push_back((access, Box::new(job)));
+        }
+    }
+    /// Waits for every queued job to complete and stops the threads.
+    fn finish(&mut self) {
+        let mut inodes = self.queues.inodes.lock().unwrap();
+        while !inodes.is_empty() {
+            inodes = self.queues.idle.wait(inodes).unwrap();
+        }
+        drop(inodes);
+        self.queues.sender.lock().unwrap().take();
+        for thread in self.threads.drain(..) {
+            thread.join().unwrap();
+        }
+    }
+}
+
+pub struct ThreadedDumbFS {
+    dumbfs: Arc<Mutex<DumbFS>>,
+    workers: Workers,
+}
+
+impl ThreadedDumbFS {
+    pub fn new(dumbfs: DumbFS, threads: usize) -> Self {
+        ThreadedDumbFS {
+            dumbfs: Arc::new(Mutex::new(dumbfs)),
+            workers: Workers::new(threads),
+        }
+    }
+    fn dumbfs(&self) -> MutexGuard<'_, DumbFS> {
+        self.dumbfs.lock().unwrap()
+    }
+}
+
+impl Filesystem for ThreadedDumbFS {
+    fn init(&mut self, req: &Request<'_>) -> Result<(), i32> {
+        self.dumbfs().init(req)
+    }
+
+    fn destroy(&mut self, req: &Request<'_>) {
+        self.workers.finish();
+        self.dumbfs().destroy(req)
+    }
+
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        self.dumbfs().lookup(req, parent, name, reply)
+    }
+
+    fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
+        self.dumbfs().getattr(req, ino, reply)
+    }
+
+    fn readlink(&mut self, req: &Request, ino: u64, reply: ReplyData) {
+        self.dumbfs().readlink(req, ino, reply)
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        mode: Option<u32>,
+        owner: Option<u32>,
+        group: Option<u32>,
+        size: Option<u64>,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let (uid, gid) = (req.uid(), req.gid());
+        let dumbfs = self.dumbfs.clone();
+        // truncating may move or release the data extent under running reads and writes
+        self.workers.execute(ino, Access::Exclusive, move || {
+            let mut dumbfs = dumbfs.lock().unwrap();
+            let set = dumbfs
+                .change_owner(uid, gid, ino, owner, group)
+                .and_then(|_| dumbfs.set_attr(uid, ino, mode, size, atime, mtime));
+            match set {
+                Ok(file) => reply.attr(&TTL, &file.meta.file_attr.into()),
+                Err(e) => reply.error(e),
+            }
+        })
+    }
+
+    fn open(&mut self, req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        self.dumbfs().open(req, ino, flags, reply)
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        reply: ReplyData,
+    ) {
+        let dumbfs = self.dumbfs.clone();
+        self.workers.execute(ino, Access::Shared, move || {
+            let file = dumbfs.lock().unwrap().opened_files.get(&fh).cloned();
+            if let Some(mut file) = file {
+                let mut buffer = Vec::with_capacity(size as usize);
+                let read = file
+                    .seek(SeekFrom::Start(offset as _))
+                    .and_then(|_| file.take(size.into()).read_to_end(&mut buffer));
+                match read {
+                    Ok(_) => reply.data(&buffer),
+                    Err(e) => reply.error(errno(e)),
+                }
+            } else {
+                reply.error(EBADF)
+            }
+        })
+    }
+
+    fn write(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _flags: u32,
+        reply: ReplyWrite,
+    ) {
+        let end = match write_end(offset, data.len()) {
+            Ok(end) => end,
+            Err(e) => return reply.error(e),
+        };
+        let uid = req.uid();
+        let dumbfs = self.dumbfs.clone();
+        let data = data.to_vec();
+        self.workers.execute(ino, Access::Exclusive, move || {
+            let prepared = dumbfs.lock().unwrap().prepare_write(uid, fh, end);
+            let written = prepared.and_then(|file| {
+                file.write_data(offset as _, &data).map_err(errno)?;
+                dumbfs.lock().unwrap().finish_write(fh, end)
+            });
+            match written {
+                Ok(()) => reply.written(data.len() as _),
+                Err(e) => reply.error(e),
+            }
+        })
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        let dumbfs = self.dumbfs.clone();
+        // the last release of an orphan frees its data extent
+        self.workers.execute(ino, Access::Exclusive, move || {
+            match dumbfs.lock().unwrap().close_handle(fh) {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(e),
+            }
+        })
+    }
+
+    fn fsync(&mut self, _req: &Request, ino: u64, fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        let dumbfs = self.dumbfs.clone();
+        self.workers.execute(ino, Access::Shared, move || {
+            let disk = {
+                let dumbfs = dumbfs.lock().unwrap();
+                if dumbfs.opened_files.contains_key(&fh) {
+                    Some(dumbfs.disk.clone())
+                } else {
+                    None
+                }
+            };
+            match disk.map(|mut it| it.flush()) {
+                Some(Ok(())) => reply.ok(),
+                Some(Err(e)) => reply.error(errno(e)),
+                None => reply.error(EBADF),
+            }
+        })
+    }
+
+    fn create(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        flags: u32,
+        reply: ReplyCreate,
+    ) {
+        self.dumbfs().create(req, parent, name, mode, flags, reply)
+    }
+
+    fn opendir(&mut self, req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        self.dumbfs().opendir(req, ino, flags, reply)
+    }
+
+    fn readdir(&mut self, req: &Request, ino: u64, fh: u64, offset: i64, reply: ReplyDirectory) {
+        self.dumbfs().readdir(req, ino, fh, offset, reply)
+    }
+
+    fn releasedir(&mut self, req: &Request, ino: u64, fh: u64, flags: u32, reply: ReplyEmpty) {
+        self.dumbfs().releasedir(req, ino, fh, flags, reply)
+    }
+
+    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.dumbfs().unlink(req, parent, name, reply)
+    }
+
+    fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.dumbfs().rmdir(req, parent, name, reply)
+    }
+
+    fn statfs(&mut self, req: &Request, ino: u64, reply: ReplyStatfs) {
+        self.dumbfs().statfs(req, ino, reply)
+    }
+
+    fn mkdir(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
+        self.dumbfs().mkdir(req, parent, name, mode, reply)
+    }
+
+    fn getxattr(&mut self, req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        self.dumbfs().getxattr(req, ino, name, size, reply)
+    }
+
+    fn listxattr(&mut self, req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        self.dumbfs().listxattr(req, ino, size, reply)
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32,
+        position: u32,
+        reply: ReplyEmpty,
+    ) {
+        self.dumbfs()
+            .setxattr(req, ino, name, value, flags, position, reply)
+    }
+
+    fn removexattr(&mut self, req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.dumbfs().removexattr(req, ino, name, reply)
+    }
+
+    fn symlink(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
+    ) {
+        self.dumbfs().symlink(req, parent, name, link, reply)
+    }
+}
+
+#[test]
+fn test_threaded() {
+    let mut workers = Workers::new(2);
+    let counter = Arc::new(Mutex::new(0));
+    let (release, held) = channel::<()>();
+    workers.execute(7, Access::Exclusive, move || held.recv().unwrap());
+    for _ in 0..2 {
+        let counter = counter.clone();
+        workers.execute(7, Access::Shared, move || {
+            *counter.lock().unwrap() += 1;
+        });
+    }
+    // the jobs waiting for inode 7 leave the second worker to a request for another inode
+    let (sender, receiver) = channel();
+    workers.execute(8, Access::Shared, move || sender.send(()).unwrap());
+    receiver.recv().unwrap();
+    assert_eq!(*counter.lock().unwrap(), 0);
+    release.send(()).unwrap();
+    workers.finish();
+    assert_eq!(*counter.lock().unwrap(), 2);
+    assert!(workers.queues.inodes.lock().unwrap().is_empty());
+}
@@ -0,0 +1,275 @@
+use crate::disk::dump::DumpToFixedLocation;
+use crate::disk::Disk;
+use crate::file::dump_file_attr::{FileAttrDump, FileTypeDump};
+use crate::file::legacy;
+use crate::file::{File, FileBuilder, NODE_SIZE};
+use crate::fs::format::FormatOptions;
+use crate::fs::meta::LEGACY_MAGIC;
+use crate::fs::{check_name, DumbFS};
+use crate::util::align;
+use std::ffi::OsStr;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+fn differs<T>(what: &str, path: &Path) -> io::Result<T> {
+    Err(io::Error::new(
+        ErrorKind::InvalidData,
+        format!("upgraded image differs in the {} of {:?}", what, path),
+    ))
+}
+
+/// Converts the legacy image at `source` into the current format.
+/// The result goes to `target` if one is given. Otherwise it is written next to `source` and
+/// renamed over it, but only once every name, attribute and byte of content has been compared.
+pub fn upgrade(source: &Path, target: Option<&Path>) -> io::Result<()> {
+    let old_disk = Disk::new(source)?;
+    let old_meta = legacy::DumbFsMeta::load(&old_disk)?;
+    if old_meta.magic != LEGACY_MAGIC {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "the image does not use the legacy on-disk layout",
+        ));
+    }
+    if target.is_none() && !std::fs::metadata(source)?.is_file() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "only an image in a regular file can be upgraded in place, give a target",
+        ));
+    }
+    let staging = match target {
+        Some(target) => target.to_path_buf(),
+        None => {
+            let mut staging = source.as_os_str().to_os_string();
+            staging.push(".upgrade");
+            PathBuf::from(staging)
+        }
+    };
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&staging)?;
+    let converted =
+        convert(&old_disk, &old_meta, &staging).and_then(|_| verify(&old_disk, &staging));
+    if let Err(e) = converted {
+        std::fs::remove_file(&staging)?;
+        return Err(e);
+    }
+    OpenOptions::new().write(true).open(&staging)?.sync_all()?;
+    if target.is_none() {
+        info!("replace {:?} with the upgraded image", source);
+        std::fs::rename(&staging, source)?;
+    }
+    Ok(())
+}
+
+fn convert(old_disk: &Disk, old_meta: &legacy::DumbFsMeta, path: &Path) -> io::Result<()> {
+    let mut dumbfs = DumbFS::new(path)?;
+    dumbfs.format(&FormatOptions::default())?;
+    dumbfs.open_filesystem()?;
+    dumbfs.meta.reserve_inos_below(old_meta.next_ino);
+    let old_root = legacy::File::load(old_disk, 512)?;
+    let mut root = File::load(&dumbfs.disk, 512)?;
+    root.meta.file_attr = old_root.meta.file_attr.clone().into();
+    root.meta.file_attr.blocks = root.allocated_blocks();
+    root.sync(&dumbfs.disk)?;
+    let mut pending = vec![(old_root, 512)];
+    while let Some((old_dir, address)) = pending.pop() {
+        // reloaded, the directory may have been linked to its siblings since it was queued
+        let mut dir = File::load(&dumbfs.disk, address)?;
+        let mut previous: Option<File> = None;
+        for old_file in old_dir.children() {
+            let mut old_file = old_file?;
+            let file = dumbfs.import(&mut old_file)?;
+            if let Some(mut previous) = previous {
+                previous.meta.next_sibling = file.location();
+                previous.sync(&dumbfs.disk)?;
+            } else {
+                dir.meta.first_child = file.location();
+                dir.sync(&dumbfs.disk)?;
+            }
+            if file.meta.file_attr.kind == FileTypeDump::Directory {
+                pending.push((old_file, file.location()));
+            }
+            previous = Some(file);
+        }
+    }
+    dumbfs.recompute_quota()?;
+    dumbfs.close_filesystem()
+}
+
+fn verify(old_disk: &Disk, path: &Path) -> io::Result<()> {
+    let mut dumbfs = DumbFS::new(path)?;
+    dumbfs.open_filesystem()?;
+    let mut pending = vec![(
+        legacy::File::load(old_disk, 512)?,
+        File::load(&dumbfs.disk, 512)?,
+        PathBuf::from("/"),
+    )];
+    while let Some((mut old_file, mut file, path)) = pending.pop() {
+        if file.meta.filename != old_file.meta.filename.as_str() {
+            return differs("name", &path);
+        }
+        let mut expected: FileAttrDump = old_file.meta.file_attr.clone().into();
+        expected.blocks = file.meta.file_attr.blocks;
+        if file.meta.file_attr != expected {
+            return differs("attributes", &path);
+        }
+        let mut old_content = vec![];
+        old_file.read_to_end(&mut old_content)?;
+        let mut content = vec![];
+        file.read_to_end(&mut content)?;
+        if content != old_content {
+            return differs("content", &path);
+        }
+        let old_children = old_file.children().collect::<io::Result<Vec<_>>>()?;
+        let children = file.children().collect::<io::Result<Vec<_>>>()?;
+        if children.len() != old_children.len() {
+            return differs("entries", &path);
+        }
+        for (old_child, child) in old_children.into_iter().zip(children) {
+            let path = path.join(&old_child.meta.filename);
+            pending.push((old_child, child, path));
+        }
+    }
+    dumbfs.close_filesystem()
+}
+
+impl DumbFS {
+    /// Copies a legacy node with its content into a newly allocated node and data extent.
+    fn import(&mut self, old_file: &mut legacy::File) -> io::Result<File> {
+        if let Err(errno) = check_name(OsStr::new(&old_file.meta.filename), self.meta.name_max()) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "cannot store the name {:?}: {}",
+                    old_file.meta.filename,
+                    io::Error::from_raw_os_error(errno)
+                ),
+            ));
+        }
+        let mut content = vec![];
+        old_file.read_to_end(&mut content)?;
+        if content.len() as u64 != old_file.meta.file_attr.size {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("content of {:?} is truncated", old_file.meta.filename),
+            ));
+        }
+        let address = self.allocate(NODE_SIZE)?;
+        let capacity = align(content.len() as u64, 512);
+        let data_address = if capacity == 0 {
+            0
+        } else {
+            self.allocate_data(capacity)?
+        };
+        let mut file = FileBuilder::new(&self.disk, address)
+            .filename(&old_file.meta.filename)
+            .data(data_address, capacity)
+            .build();
+        file.write_all(&content)?;
+        file.meta.file_attr = old_file.meta.file_attr.clone().into();
+        file.meta.file_attr.blocks = file.allocated_blocks();
+        file.sync(&self.disk)?;
+        Ok(file)
+    }
+}
+
+#[test]
+fn test_upgrade() -> io::Result<()> {
+    use bincode::serialize_into;
+    use std::io::{Seek, SeekFrom};
+    use std::time::{Duration, UNIX_EPOCH};
+    use tempfile::tempdir;
+    let tempdir = tempdir()?;
+    let file_path = tempdir.path().join("legacy.img");
+    let node = |ino, kind, filename: &str, first_child, next_sibling, size| legacy::FileMeta {
+        first_child,
+        next_sibling,
+        file_attr: legacy::FileAttrDump {
+            ino,
+            size,
+            blocks: 1,
+            atime: UNIX_EPOCH + Duration::new(1_500_000_000, 1),
+            mtime: UNIX_EPOCH + Duration::new(1_500_000_001, 2),
+            ctime: UNIX_EPOCH + Duration::new(1_500_000_002, 3),
+            crtime: UNIX_EPOCH + Duration::new(1_500_000_003, 4),
+            kind,
+            perm: 0o750,
+            nlink: 1,
+            uid: 1000,
+            gid: 100,
+            rdev: 0,
+            flags: 0,
+        },
+        filename: filename.to_string(),
+    };
+    {
+        let mut image = std::fs::File::create(&file_path)?;
+        let meta = legacy::DumbFsMeta {
+            magic: LEGACY_MAGIC,
+            next_ino: 5,
+            next_free_address: 2560,
+        };
+        serialize_into(&mut image, &meta).unwrap();
+        let dir = legacy::FileTypeDump::Directory;
+        let empty = node(1, dir.clone(), "", 0, 0, 0);
+        assert_eq!(
+            bincode::serialized_size(&empty).unwrap(),
+            legacy::MIN_NODE_SIZE
+        );
+        let file = legacy::FileTypeDump::RegularFile;
+        let nodes = vec![
+            (512, node(1, dir.clone(), "", 1024, 0, 0), ""),
+            (
+                1024,
+                node(2, file.clone(), "hello.txt", 0, 1536, 11),
+                "hello world",
+            ),
+            (1536, node(3, dir, "dir", 2048, 0, 0), ""),
+            (2048, node(4, file, "nested", 0, 0, 3), "abc"),
+        ];
+        for (address, meta, content) in nodes {
+            image.seek(SeekFrom::Start(address))?;
+            serialize_into(&mut image, &meta).unwrap();
+            image.write_all(content.as_bytes())?;
+        }
+    }
+    // a sibling chain that loops is refused instead of converted forever
+    let looped_path = tempdir.path().join("looped.img");
+    std::fs::copy(&file_path, &looped_path)?;
+    {
+        let mut image = OpenOptions::new().write(true).open(&looped_path)?;
+        image.seek(SeekFrom::Start(2048))?;
+        let file = legacy::FileTypeDump::RegularFile;
+        serialize_into(&mut image, &node(4, file, "nested", 0, 2048, 3)).unwrap();
+    }
+    let e = upgrade(&looped_path, Some(&tempdir.path().join("looped.new"))).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidData);
+
+    let upgraded_path = tempdir.path().join("upgraded.img");
+    upgrade(&file_path, Some(&upgraded_path))?;
+    assert!(upgrade(&upgraded_path, None).is_err());
+    upgrade(&file_path, None)?;
+    assert!(!tempdir.path().join("legacy.img.upgrade").exists());
+
+    let mut dumbfs = DumbFS::new(&file_path)?;
+    dumbfs.open_filesystem()?;
+    let mut hello = dumbfs.find_path(Path::new("/hello.txt"))?.unwrap();
+    let mut content = String::new();
+    hello.read_to_string(&mut content)?;
+    assert_eq!(content, "hello world");
+    assert_eq!(hello.meta.file_attr.ino, 2);
+    assert_eq!(hello.meta.file_attr.perm, 0o750);
+    assert_eq!(
+        hello.meta.file_attr.mtime,
+        UNIX_EPOCH + Duration::new(1_500_000_001, 2)
+    );
+    let mut nested = dumbfs.find_path(Path::new("/dir/nested"))?.unwrap();
+    let mut content = String::new();
+    nested.read_to_string(&mut content)?;
+    assert_eq!(content, "abc");
+    assert_eq!(dumbfs.meta.acquire_next_ino(), 5);
+    Ok(())
+}
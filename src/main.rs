@@ -1,24 +1,413 @@
 #[macro_use]
 extern crate log;
 
+use crate::disk::nbd::NbdTarget;
+use crate::disk::DiskOptions;
+use crate::fs::format;
+use crate::fs::format::FormatOptions;
+use crate::fs::options::MountOptions;
+use crate::fs::populate::PopulateOptions;
+use crate::fs::quota::{QuotaKind, QuotaLimits};
+use crate::fs::threaded::ThreadedDumbFS;
 use crate::fs::DumbFS;
+use crate::util::parse_size;
+use std::convert::TryFrom;
 use std::env;
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::process::exit;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 mod disk;
 mod file;
 mod fs;
 mod util;
 
+const USAGE: &str = "usage:
+    dumbfs <disk> <mountpoint> [-o ro,discard,threads=<n>,cache=<size>,uring,direct,mirror=<disk>,stripe=<disk>,parity=1|2,<fuse options>]
+    dumbfs quota <disk>
+    dumbfs quota <disk> user|group|project <id> <block-soft> <block-hard> <inode-soft> <inode-hard>
+    dumbfs quota <disk> grace <block-seconds> <inode-seconds>
+    dumbfs quotacheck <disk>
+    dumbfs project <disk> <path> <project-id>
+    dumbfs resize <disk> <size>
+    dumbfs fstrim <disk>
+    dumbfs reserve <disk> <percent>
+    dumbfs resync <replaced-disk> <disk>...
+    dumbfs rebuild <member> <parity> <disk> <stripe-disk>...
+    dumbfs add-device <disk> <device>
+    dumbfs upgrade <legacy-disk> [<new-disk>]
+    dumbfs mkfs [-f] [-s <size>] [-b <block-size>] [-L <label>] [-U <uuid>] [-N <reserved-inodes>]
+        [-O [^]quota,...] [-E root_owner=<uid>:<gid>,root_mode=<mode>]
+        [-d <directory> [-u <host-uid>:<uid>,...] [-g <host-gid>:<gid>,...] [-T <seconds>]] <disk>
+    mkfs-dumbfs ... and mkfs.dumbfs ..., a link to dumbfs or mkfs-dumbfs, are dumbfs mkfs ...
+-d copies a directory into the new filesystem, a host ID of * maps all others,
+    -T (or SOURCE_DATE_EPOCH) and -U make the image reproducible
+a disk is an image, a block device, a directory of chunks, nbd://<host>[:<port>]/<export> or nbd+unix:///<export>?socket=<path>
+SIGUSR1 makes a mounted dumbfs grow onto an enlarged disk and the devices added since";
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    exit(1)
+}
+
+fn parse<T: std::str::FromStr>(arg: Option<&OsString>) -> T {
+    arg.and_then(|it| it.to_str())
+        .and_then(|it| it.parse().ok())
+        .unwrap_or_else(|| usage())
+}
+
+/// The value of `result`, or exits with its error.
+fn check<T>(result: io::Result<T>) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1)
+    })
+}
+
+fn open_filesystem(disk: Option<&OsString>) -> DumbFS {
+    let disk = disk.unwrap_or_else(|| usage());
+    let opened = DumbFS::new(disk).and_then(|mut dumbfs| {
+        dumbfs.open_filesystem()?;
+        Ok(dumbfs)
+    });
+    opened.unwrap_or_else(|e| {
+        eprintln!("cannot open filesystem: {}", e);
+        exit(1)
+    })
+}
+
+/// Exits unless the filesystem keeps a quota table.
+fn check_quota_enabled(dumbfs: &DumbFS) {
+    if !dumbfs.quota_enabled() {
+        eprintln!("quota is disabled on this filesystem");
+        exit(1)
+    }
+}
+
+fn quota(args: &[OsString]) {
+    let mut dumbfs = open_filesystem(args.get(0));
+    check_quota_enabled(&dumbfs);
+    match args.get(1).and_then(|it| it.to_str()) {
+        None => {
+            println!("kind\tid\tblocks\tsoft\thard\tinodes\tsoft\thard");
+            for ((kind, id), entry) in &dumbfs.quota.table.entries {
+                println!(
+                    "{:?}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    kind,
+                    id,
+                    entry.blocks,
+                    entry.limits.block_soft,
+                    entry.limits.block_hard,
+                    entry.inodes,
+                    entry.limits.inode_soft,
+                    entry.limits.inode_hard
+                );
+            }
+        }
+        Some("grace") => {
+            dumbfs.quota.table.block_grace = Duration::from_secs(parse(args.get(2)));
+            dumbfs.quota.table.inode_grace = Duration::from_secs(parse(args.get(3)));
+        }
+        Some(kind) => {
+            let kind = match kind {
+                "user" => QuotaKind::User,
+                "group" => QuotaKind::Group,
+                "project" => QuotaKind::Project,
+                _ => usage(),
+            };
+            let limits = QuotaLimits {
+                block_soft: parse(args.get(3)),
+                block_hard: parse(args.get(4)),
+                inode_soft: parse(args.get(5)),
+                inode_hard: parse(args.get(6)),
+            };
+            dumbfs.quota.set_limits(kind, parse(args.get(2)), limits);
+        }
+    }
+    check(dumbfs.close_filesystem());
+}
+
+fn quotacheck(args: &[OsString]) {
+    let mut dumbfs = open_filesystem(args.get(0));
+    check_quota_enabled(&dumbfs);
+    check(dumbfs.recompute_quota());
+    check(dumbfs.close_filesystem());
+}
+
+fn project(args: &[OsString]) {
+    let mut dumbfs = open_filesystem(args.get(0));
+    let path = Path::new(args.get(1).unwrap_or_else(|| usage()));
+    if let Some(root) = check(dumbfs.find_path(path)) {
+        check(dumbfs.set_project(root, parse(args.get(2))));
+    } else {
+        eprintln!("{:?} not found", path);
+    }
+    check(dumbfs.close_filesystem());
+}
+
+fn resize(args: &[OsString]) {
+    let size = args
+        .get(1)
+        .and_then(|it| it.to_str())
+        .and_then(parse_size)
+        .unwrap_or_else(|| usage());
+    let mut dumbfs = open_filesystem(args.get(0));
+    let resized = dumbfs.resize(size);
+    check(dumbfs.close_filesystem());
+    check(resized);
+}
+
+fn fstrim(args: &[OsString]) {
+    let mut dumbfs = open_filesystem(args.get(0));
+    println!("trimmed {} bytes", check(dumbfs.trim()));
+    check(dumbfs.close_filesystem());
+}
+
+fn reserve(args: &[OsString]) {
+    let percent = parse(args.get(1));
+    if percent > 50 {
+        usage()
+    }
+    let mut dumbfs = open_filesystem(args.get(0));
+    check(dumbfs.set_reserved_percent(percent));
+    check(dumbfs.close_filesystem());
+}
+
+fn resync(args: &[OsString]) {
+    if args.len() < 2 {
+        usage()
+    }
+    // the replaced member goes last so that the others are read first
+    let paths = args[1..]
+        .iter()
+        .chain(&args[..1])
+        .map(Path::new)
+        .collect::<Vec<_>>();
+    let mirror = check(disk::open_mirror(&paths, &DiskOptions::default()));
+    check(mirror.resync(paths.len() - 1));
+    if mirror.in_sync().contains(&false) {
+        eprintln!("a member failed during the resync and needs another one");
+        exit(1)
+    }
+}
+
+fn rebuild(args: &[OsString]) {
+    let member = parse(args.get(0));
+    let options = DiskOptions {
+        parity: parse(args.get(1)),
+        ..DiskOptions::default()
+    };
+    if args.len() < 4 {
+        usage()
+    }
+    let paths = args[2..].iter().map(Path::new).collect::<Vec<_>>();
+    let striped = check(disk::open_parity(&paths, &options));
+    check(striped.rebuild(member));
+    if striped.healthy().contains(&false) {
+        eprintln!("a member failed during the rebuild and needs another one");
+        exit(1)
+    }
+}
+
+fn add_device(args: &[OsString]) {
+    let disk = Path::new(args.get(0).unwrap_or_else(|| usage()));
+    let device = Path::new(args.get(1).unwrap_or_else(|| usage()));
+    // read-only, the filesystem may be mounted
+    let mut options = MountOptions::default();
+    options.disk.read_only = true;
+    let mut dumbfs = check(DumbFS::with_options(disk, options));
+    check(dumbfs.open_filesystem());
+    if !dumbfs.sized() {
+        eprintln!(
+            "{:?} grows with use, give it a size with `dumbfs resize` first",
+            disk
+        );
+        exit(1)
+    }
+    check(disk::add_device(disk, device));
+    println!("send SIGUSR1 to dumbfs to start using it on a mounted filesystem");
+}
+
+fn upgrade(args: &[OsString]) {
+    let source = Path::new(args.get(0).unwrap_or_else(|| usage()));
+    let target = args.get(1).map(Path::new);
+    if let Err(e) = fs::upgrade::upgrade(source, target) {
+        eprintln!("cannot upgrade {:?}: {}", source, e);
+        exit(1)
+    }
+}
+
+/// Options `-E` takes, the owner and mode of the root directory.
+fn parse_extended(list: &str, options: &mut FormatOptions) -> Option<()> {
+    for option in list.split(',') {
+        let at = option.find('=')?;
+        let value = &option[at + 1..];
+        match &option[..at] {
+            "root_owner" => {
+                let at = value.find(':')?;
+                options.root_uid = value[..at].parse().ok()?;
+                options.root_gid = value[at + 1..].parse().ok()?;
+            }
+            "root_mode" => options.root_mode = u16::from_str_radix(value, 8).ok()?,
+            _ => return None,
+        }
+    }
+    Some(())
+}
+
+/// Seconds since the epoch.
+fn parse_timestamp(text: &str) -> Option<SystemTime> {
+    text.parse()
+        .ok()
+        .map(|it| UNIX_EPOCH + Duration::from_secs(it))
+}
+
+fn mkfs(args: &[OsString]) {
+    let mut options = FormatOptions::default();
+    let mut populate = PopulateOptions::default();
+    let mut source = None;
+    let mut disk = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let flag = match arg.to_str() {
+            Some(flag) if flag.len() == 2 && flag.starts_with('-') => flag,
+            _ if disk.is_none() => {
+                disk = Some(arg);
+                continue;
+            }
+            _ => usage(),
+        };
+        if flag == "-f" {
+            options.force = true;
+            continue;
+        } else if flag == "-d" {
+            source = Some(args.next().unwrap_or_else(|| usage()));
+            continue;
+        }
+        let value = args
+            .next()
+            .and_then(|it| it.to_str())
+            .unwrap_or_else(|| usage());
+        let parsed = match flag {
+            "-s" => parse_size(value).map(|it| options.size = Some(it)),
+            "-b" => parse_size(value)
+                .and_then(|it| u32::try_from(it).ok())
+                .filter(|it| it.is_power_of_two() && *it >= 512)
+                .map(|it| options.block_size = it),
+            "-L" => {
+                options.label = value.as_bytes().to_vec();
+                Some(())
+            }
+            "-U" => format::parse_uuid(value).map(|it| options.uuid = Some(it)),
+            "-N" => value.parse().ok().map(|it| options.reserved_inodes = it),
+            "-O" => format::parse_features(value, options.features).map(|it| options.features = it),
+            "-E" => parse_extended(value, &mut options),
+            "-u" => populate.uids.add(value),
+            "-g" => populate.gids.add(value),
+            "-T" => parse_timestamp(value).map(|it| populate.timestamp = Some(it)),
+            _ => None,
+        };
+        if parsed.is_none() {
+            usage()
+        }
+    }
+    let disk = disk.unwrap_or_else(|| usage());
+    if populate.timestamp.is_none() {
+        if let Ok(value) = env::var("SOURCE_DATE_EPOCH") {
+            populate.timestamp = Some(parse_timestamp(&value).unwrap_or_else(|| usage()));
+        }
+    }
+    // a new image file, block devices and chunk directories have to exist
+    let nbd = disk.to_str().and_then(NbdTarget::parse).is_some();
+    if !nbd && !Path::new(disk).exists() {
+        check(File::create(disk));
+    }
+    let mut dumbfs = check(DumbFS::new(disk));
+    if let Err(e) = dumbfs.format(&options) {
+        eprintln!("cannot format {:?}: {}", disk, e);
+        exit(1)
+    }
+    if let Some(source) = source {
+        if let Err(e) = dumbfs.populate(Path::new(source), &populate) {
+            eprintln!("cannot copy {:?}: {}", source, e);
+            exit(1)
+        }
+    }
+    check(dumbfs.close_filesystem());
+    match dumbfs.block_count() {
+        0 => println!("{:?}: grows with use", disk),
+        blocks => println!("{:?}: {} blocks of 512 bytes", disk, blocks),
+    }
+    println!("UUID {}", format::format_uuid(&dumbfs.uuid()));
+}
+
 fn main() {
     env_logger::init();
-    let disk = env::args_os().nth(1).unwrap();
-    let mountpoint = env::args_os().nth(2).unwrap();
-    info!("mount: {:?} on {:?}", disk, mountpoint);
-    let options = ["-o", "rw,default_permissions", "-o", "fsname=dumbfs"]
+    let args: Vec<OsString> = env::args_os().skip(1).collect();
+    let program = env::args_os().next().unwrap_or_default();
+    if Path::new(&program).file_name() == Some(OsStr::new("mkfs.dumbfs")) {
+        return mkfs(&args);
+    }
+    match args.get(0).and_then(|it| it.to_str()) {
+        Some("mkfs") => return mkfs(&args[1..]),
+        Some("quota") => return quota(&args[1..]),
+        Some("quotacheck") => return quotacheck(&args[1..]),
+        Some("project") => return project(&args[1..]),
+        Some("resize") => return resize(&args[1..]),
+        Some("fstrim") => return fstrim(&args[1..]),
+        Some("reserve") => return reserve(&args[1..]),
+        Some("resync") => return resync(&args[1..]),
+        Some("rebuild") => return rebuild(&args[1..]),
+        Some("add-device") => return add_device(&args[1..]),
+        Some("upgrade") => return upgrade(&args[1..]),
+        _ => {}
+    }
+    let (mount_options, fuse_options) = match args.len() {
+        2 => MountOptions::parse(""),
+        4 if args[2] == "-o" => MountOptions::parse(&args[3].to_string_lossy()),
+        _ => usage(),
+    };
+    let disk = &args[0];
+    let mountpoint = &args[1];
+    info!(
+        "mount: {:?} on {:?} with {:?}",
+        disk, mountpoint, mount_options
+    );
+    let mode = if mount_options.disk.read_only {
+        "ro"
+    } else {
+        "rw"
+    };
+    let mut options = vec![
+        format!("{},default_permissions", mode),
+        "fsname=dumbfs".to_string(),
+    ];
+    options.extend(fuse_options);
+    let options = options
         .iter()
-        .map(|o| o.as_ref())
+        .flat_map(|o| vec![OsStr::new("-o"), o.as_ref()])
         .collect::<Vec<&OsStr>>();
-    let dumbfs = DumbFS::new(disk);
-    fuse::mount(dumbfs, mountpoint, &options).unwrap();
+    let threads = mount_options.threads;
+    let mut dumbfs = DumbFS::with_options(disk, mount_options).unwrap_or_else(|e| {
+        eprintln!("cannot open {:?}: {}", disk, e);
+        exit(1)
+    });
+    // refuse here, the kernel only learns that the mount failed
+    if let Err(e) = dumbfs.load_superblock() {
+        eprintln!("cannot mount {:?}: {}", disk, e);
+        exit(1)
+    }
+    fs::resize::grow_on_signal();
+    if threads == 0 {
+        check(fuse::mount(dumbfs, mountpoint, &options));
+    } else {
+        check(fuse::mount(
+            ThreadedDumbFS::new(dumbfs, threads),
+            mountpoint,
+            &options,
+        ));
+    }
 }
@@ -1,4 +1,7 @@
 use num::Integer;
+use std::fs::File;
+use std::io;
+use std::io::Read;
 
 pub fn align<T: Integer + Copy>(num: T, to: T) -> T {
     if to == T::zero() || num % to == T::zero() {
@@ -8,6 +11,57 @@ pub fn align<T: Integer + Copy>(num: T, to: T) -> T {
     }
 }
 
+/// Parses a byte count with an optional `K`, `M`, `G` or `T` binary suffix.
+pub fn parse_size(size: &str) -> Option<u64> {
+    let (number, shift) = match size.chars().last()?.to_ascii_uppercase() {
+        'K' => (&size[..size.len() - 1], 10),
+        'M' => (&size[..size.len() - 1], 20),
+        'G' => (&size[..size.len() - 1], 30),
+        'T' => (&size[..size.len() - 1], 40),
+        _ => (size, 0),
+    };
+    number.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+/// Lookup table of the reflected CRC-32 polynomial used by zlib and Ethernet.
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// CRC-32 of `data`, as computed by zlib.
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        CRC32_TABLE[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// A version 4 UUID.
+pub fn random_uuid() -> io::Result<[u8; 16]> {
+    let mut uuid = [0u8; 16];
+    File::open("/dev/urandom")?.read_exact(&mut uuid)?;
+    uuid[6] = uuid[6] & 0x0f | 0x40;
+    uuid[8] = uuid[8] & 0x3f | 0x80;
+    Ok(uuid)
+}
+
 #[test]
 fn test_align() {
     assert_eq!(align(0, 512), 0);
@@ -18,3 +72,19 @@ fn test_align() {
     assert_eq!(align(128, 512), 512);
     assert_eq!(align(513, 512), 1024);
 }
+
+#[test]
+fn test_parse_size() {
+    assert_eq!(parse_size("512"), Some(512));
+    assert_eq!(parse_size("4k"), Some(4096));
+    assert_eq!(parse_size("64M"), Some(64 << 20));
+    assert_eq!(parse_size("1G"), Some(1 << 30));
+    assert_eq!(parse_size("G"), None);
+    assert_eq!(parse_size(""), None);
+}
+
+#[test]
+fn test_crc32() {
+    assert_eq!(crc32(b""), 0);
+    assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+}